@@ -5,6 +5,7 @@ use criterion::{black_box, criterion_group, criterion_main, Criterion};
 use primitive_types::U256;
 
 use kindelia::bits::ProtoSerialize;
+use kindelia::config::RollbackConfig;
 use kindelia::hvm;
 use kindelia::net;
 use kindelia::node;
@@ -25,7 +26,7 @@ pub fn temp_dir() -> PathBuf {
 pub fn init_runtime(path: PathBuf) -> hvm::Runtime {
   let genesis_stmts =
     hvm::parse_code(kindelia::constants::GENESIS_CODE).expect("Genesis code parses.");
-  hvm::init_runtime(path, &genesis_stmts)
+  hvm::init_runtime(path, &genesis_stmts, &RollbackConfig::default())
 }
 
 fn khvm_benches(c: &mut Criterion) {
@@ -11,7 +11,8 @@ use crate::net::ProtoComm;
 use crate::node;
 
 use super::{
-  BlockInfo, CtrInfo, FuncInfo, Hash, HexStatement, Name, RegInfo, Stats,
+  BlockInfo, CtrInfo, FitInfo, FuncInfo, Hash, HexStatement, Name, RegInfo,
+  StateProof, Stats, U120,
 };
 
 pub struct ApiClient {
@@ -102,6 +103,14 @@ impl ApiClient {
     self.get::<Option<BlockInfo>>(&format!("/blocks/{}", id)).await
   }
 
+  pub async fn get_run_result(
+    &self,
+    block_idx: u64,
+    stmt_idx: u64,
+  ) -> ApiResult<U120> {
+    self.get::<U120>(&format!("/blocks/{}/runs/{}", block_idx, stmt_idx)).await
+  }
+
   pub async fn get_functions(&self) -> ApiResult<Vec<Name>> {
     self.get::<Vec<Name>>("/functions").await
   }
@@ -114,6 +123,13 @@ impl ApiClient {
     self.get::<Term>(&format!("/functions/{}/state", name)).await
   }
 
+  pub async fn get_function_state_proof(
+    &self,
+    name: Name,
+  ) -> ApiResult<StateProof> {
+    self.get::<StateProof>(&format!("/functions/{}/state?proof=1", name)).await
+  }
+
   pub async fn get_constructor(&self, name: Name) -> ApiResult<CtrInfo> {
     self.get::<CtrInfo>(&format!("/constructor/{}", name)).await
   }
@@ -125,6 +141,10 @@ impl ApiClient {
     self.req(Method::POST, "/run", Some(code)).await
   }
 
+  pub async fn fit_code(&self, code: Vec<HexStatement>) -> ApiResult<FitInfo> {
+    self.req(Method::POST, "/fit", Some(code)).await
+  }
+
   // I'm not sure what the return type should be.
   pub async fn publish_code(
     &self,
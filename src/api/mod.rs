@@ -17,12 +17,13 @@ use serde_with::{serde_as, DisplayFromStr};
 use tokio::sync::oneshot;
 
 use crate::bits::ProtoSerialize;
+use crate::crypto;
 use crate::hvm;
 use crate::net::ProtoComm;
 use crate::node;
 use crate::util;
 
-pub use crate::common::Name;
+pub use crate::common::{Name, U120};
 
 // Util
 // ====
@@ -268,6 +269,49 @@ pub struct BlockInfo {
   pub hash: Hash,
   pub height: u64,
   pub results: Option<Vec<hvm::StatementResult>>,
+  #[serde(default)]
+  pub scheduled: Option<Vec<hvm::StatementResult>>,
+  /// Root of the runtime state after this block, see `Runtime::get_state_root`.
+  #[serde(default)]
+  pub state_root: Option<Hash>,
+}
+
+/// The state of a function, with a proof of its inclusion in the state root of
+/// a block. See `verify_state_proof`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StateProof {
+  /// The block whose state root the proof is against.
+  pub block: Hash,
+  /// The state root of that block, according to the node that built the proof.
+  pub state_root: Hash,
+  pub name: Name,
+  /// The state, or `None` if the function has no state.
+  pub state: Option<hvm::Term>,
+  /// Hash of the arity, owner and function of `name`.
+  pub definition: Hash,
+  /// Position of the leaf of `name` on the state root.
+  pub index: u64,
+  /// Number of leaves on the state root.
+  pub leaves: u64,
+  /// Siblings of the path from the leaf up to the state root.
+  pub path: Vec<Hash>,
+}
+
+/// Checks that `proof` proves the state of its function under `state_root`.
+/// That root must come from a source trusted to have computed the block, not
+/// from the proof itself.
+pub fn verify_state_proof(proof: &StateProof, state_root: &Hash) -> bool {
+  fn to_crypto(hash: &Hash) -> crypto::Hash {
+    crypto::Hash::from(&hash.value)
+  }
+  let state = hvm::hash_state(proof.state.as_ref());
+  let leaf = hvm::state_leaf(proof.name, &to_crypto(&proof.definition), &state);
+  let path: Vec<crypto::Hash> = proof.path.iter().map(to_crypto).collect();
+  let (index, leaves) = match (proof.index.try_into(), proof.leaves.try_into()) {
+    (Ok(index), Ok(leaves)) => (index, leaves),
+    _ => return false,
+  };
+  crypto::merkle_verify(&to_crypto(state_root), &leaf, index, leaves, &path)
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -284,6 +328,22 @@ pub struct CtrInfo {
 pub struct RegInfo {
   pub ownr: Name,
   pub stmt: Vec<Name>,
+  /// Tick of the block that last transferred the namespace, if any.
+  #[serde(default)]
+  pub xfer: Option<u64>,
+}
+
+/// Result of dry-running a sequence of statements against the block limits.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FitInfo {
+  pub fits: bool,
+  pub used_bytes: usize,
+  pub max_bytes: usize,
+  pub used_mana: u64,
+  pub max_mana: u64,
+  pub used_bits: i64,
+  pub max_bits: u64,
+  pub results: Vec<hvm::StatementResult>,
 }
 
 // Node Internal API
@@ -310,6 +370,11 @@ pub enum NodeRequest<C: ProtoComm> {
     range: (i64, i64),
     tx: ReqAnsSend<Vec<BlockInfo>>,
   },
+  GetRunResult {
+    block_idx: u64,
+    stmt_idx: u64,
+    tx: ReqAnsSend<Option<U120>>,
+  },
   GetFunctions {
     tx: ReqAnsSend<HashSet<u128>>,
   },
@@ -321,6 +386,10 @@ pub enum NodeRequest<C: ProtoComm> {
     name: Name,
     tx: ReqAnsSend<Option<hvm::Term>>,
   },
+  GetStateProof {
+    name: Name,
+    tx: ReqAnsSend<Option<StateProof>>,
+  },
   GetPeers {
     all: bool,
     tx: ReqAnsSend<Vec<node::Peer<C::Address>>>,
@@ -336,6 +405,7 @@ pub enum NodeRequest<C: ProtoComm> {
   /// DEPRECATED
   RunCode {
     code: String,
+    trace: bool,
     tx: ReqAnsSend<Vec<hvm::StatementResult>>,
   },
   /// DEPRECATED
@@ -351,6 +421,10 @@ pub enum NodeRequest<C: ProtoComm> {
     code: Vec<hvm::Statement>,
     tx: ReqAnsSend<PublishResults>,
   },
+  Fit {
+    code: Vec<hvm::Statement>,
+    tx: ReqAnsSend<FitInfo>,
+  },
 }
 
 impl<C: ProtoComm> NodeRequest<C> {
@@ -370,6 +444,13 @@ impl<C: ProtoComm> NodeRequest<C> {
     let (tx, rx) = oneshot::channel();
     (NodeRequest::GetBlocks { range, tx }, rx)
   }
+  pub fn get_run_result(
+    block_idx: u64,
+    stmt_idx: u64,
+  ) -> (Self, ReqAnsRecv<Option<U120>>) {
+    let (tx, rx) = oneshot::channel();
+    (NodeRequest::GetRunResult { block_idx, stmt_idx, tx }, rx)
+  }
   pub fn get_functions() -> (Self, ReqAnsRecv<HashSet<u128>>) {
     let (tx, rx) = oneshot::channel();
     (NodeRequest::GetFunctions { tx }, rx)
@@ -382,6 +463,10 @@ impl<C: ProtoComm> NodeRequest<C> {
     let (tx, rx) = oneshot::channel();
     (NodeRequest::GetState { name, tx }, rx)
   }
+  pub fn get_state_proof(name: Name) -> (Self, ReqAnsRecv<Option<StateProof>>) {
+    let (tx, rx) = oneshot::channel();
+    (NodeRequest::GetStateProof { name, tx }, rx)
+  }
   pub fn get_peers(
     all: bool,
   ) -> (Self, ReqAnsRecv<Vec<node::Peer<C::Address>>>) {
@@ -398,9 +483,10 @@ impl<C: ProtoComm> NodeRequest<C> {
   }
   pub fn test_code(
     code: String,
+    trace: bool,
   ) -> (Self, ReqAnsRecv<Vec<hvm::StatementResult>>) {
     let (tx, rx) = oneshot::channel();
-    (NodeRequest::RunCode { code, tx }, rx)
+    (NodeRequest::RunCode { code, trace, tx }, rx)
   }
   pub fn post_code(
     code: String,
@@ -420,4 +506,8 @@ impl<C: ProtoComm> NodeRequest<C> {
     let (tx, rx) = oneshot::channel();
     (NodeRequest::Publish { code, tx }, rx)
   }
+  pub fn fit(code: Vec<hvm::Statement>) -> (Self, ReqAnsRecv<FitInfo>) {
+    let (tx, rx) = oneshot::channel();
+    (NodeRequest::Fit { code, tx }, rx)
+  }
 }
@@ -56,6 +56,22 @@ fn err_json(message: &str) -> warp::reply::Json {
   warp::reply::json(&json_body)
 }
 
+// Deserializes a query flag given either as `1`/`0` or as `true`/`false`
+fn query_flag<'de, D>(deserializer: D) -> Result<Option<bool>, D::Error>
+where
+  D: serde::Deserializer<'de>,
+{
+  let flag: Option<String> = Option::deserialize(deserializer)?;
+  match flag.as_deref() {
+    None => Ok(None),
+    Some("1") | Some("true") => Ok(Some(true)),
+    Some("0") | Some("false") => Ok(Some(false)),
+    Some(flag) => {
+      Err(serde::de::Error::custom(format!("invalid flag '{}'", flag)))
+    }
+  }
+}
+
 fn json_body<T>() -> impl Filter<Extract = (T,), Error = warp::Rejection> + Clone
 where
   T: DeserializeOwned + Send,
@@ -242,8 +258,30 @@ async fn api_serve<'a, C: ProtoComm + 'static>(
 
   let get_block_go = get_block().and(path!()).map(ok_json);
 
+  let query_tx = node_query_sender.clone();
+  let get_block_run = path!("blocks" / u64 / "runs" / u64).and_then(
+    move |block_idx: u64, stmt_idx: u64| {
+      let query_tx = query_tx.clone();
+      async move {
+        let req = NodeRequest::get_run_result(block_idx, stmt_idx);
+        let result = ask(query_tx, req).await;
+        match result {
+          None => {
+            let message = format!(
+              "Run result for statement {} of block {} not found",
+              stmt_idx, block_idx
+            );
+            Err(Rejection::from(NotFound::from(message)))
+          }
+          Some(result) => Ok(ok_json(result)),
+        }
+      }
+    },
+  );
+
   let blocks_router = get_blocks //
     .or(get_block_go)
+    .or(get_block_run)
     .or(get_block_hash);
 
   // == Functions ==
@@ -290,6 +328,8 @@ async fn api_serve<'a, C: ProtoComm + 'static>(
   #[derive(Deserialize)]
   struct GetStateQuery {
     protocol: Option<bool>, // TODO: base64 ?
+    #[serde(default, deserialize_with = "query_flag")]
+    proof: Option<bool>,
   }
 
   let query_tx = node_query_sender.clone();
@@ -299,6 +339,19 @@ async fn api_serve<'a, C: ProtoComm + 'static>(
     .and_then(move |name: Name, query: GetStateQuery| {
       let query_tx = query_tx.clone();
       async move {
+        if let Some(true) = query.proof {
+          let proof = ask(query_tx, NodeRequest::get_state_proof(name)).await;
+          return match proof {
+            Some(proof) => Ok(ok_json(proof)),
+            None => {
+              let message = format!(
+                "State for function '{}' is too big or is missing.",
+                name
+              );
+              Err(Rejection::from(TermTooBig::from(message)))
+            }
+          };
+        }
         let state = ask(query_tx, NodeRequest::get_state(name)).await;
         if let Some(state) = state {
           if let Some(true) = query.protocol {
@@ -356,17 +409,25 @@ async fn api_serve<'a, C: ProtoComm + 'static>(
 
   let interact_code_base = path!("code" / ..);
 
+  #[derive(Deserialize)]
+  struct RunCodeQuery {
+    #[serde(default, deserialize_with = "query_flag")]
+    trace: Option<bool>,
+  }
+
   let query_tx = node_query_sender.clone();
   let interact_code_run = post()
     .and(interact_code_base)
     .and(path!("run"))
+    .and(query::<RunCodeQuery>())
     .and(body::bytes())
-    .and_then(move |code: warp::hyper::body::Bytes| {
+    .and_then(move |query: RunCodeQuery, code: warp::hyper::body::Bytes| {
       let query_tx = query_tx.clone();
       async move {
         let code = String::from_utf8(code.to_vec());
         if let Ok(code) = code {
-          let res = ask(query_tx, NodeRequest::test_code(code)).await;
+          let trace = query.trace.unwrap_or(false);
+          let res = ask(query_tx, NodeRequest::test_code(code, trace)).await;
           Ok(ok_json(res))
         } else {
           Err(reject::custom(InvalidParameter::from(
@@ -435,10 +496,24 @@ async fn api_serve<'a, C: ProtoComm + 'static>(
     },
   );
 
+  let query_tx = node_query_sender.clone();
+  let interact_fit = post().and(path!("fit")).and(json_body()).then(
+    move |code: Vec<HexStatement>| {
+      let query_tx = query_tx.clone();
+      async move {
+        let code: Vec<hvm::Statement> =
+          code.into_iter().map(|x| x.into()).collect();
+        let info = ask(query_tx, NodeRequest::fit(code)).await;
+        ok_json(info)
+      }
+    },
+  );
+
   let interact_router = interact_code_run
     .or(interact_code_publish)
     .or(interact_run)
-    .or(interact_publish);
+    .or(interact_publish)
+    .or(interact_fit);
 
   // == Reg ==
 
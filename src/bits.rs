@@ -289,6 +289,36 @@ impl ProtoSerialize for Name {
   }
 }
 
+impl ProtoSerialize for U120 {
+  fn proto_serialize(&self, bits: &mut BitVec, _names: &mut Names) {
+    serialize_number(**self, bits);
+  }
+
+  fn proto_deserialize(
+    bits: &BitVec,
+    index: &mut usize,
+    _names: &mut Names,
+  ) -> Option<Self> {
+    let numb = deserialize_number(bits, index)?.low_u128();
+    numb.try_into().ok()
+  }
+}
+
+impl ProtoSerialize for u64 {
+  fn proto_serialize(&self, bits: &mut BitVec, _names: &mut Names) {
+    serialize_number(*self as u128, bits);
+  }
+
+  fn proto_deserialize(
+    bits: &BitVec,
+    index: &mut usize,
+    _names: &mut Names,
+  ) -> Option<Self> {
+    let numb = deserialize_number(bits, index)?.low_u128();
+    numb.try_into().ok()
+  }
+}
+
 // TODO: avoid recursion here; important for checksum functionality
 impl ProtoSerialize for Term {
   fn proto_serialize(&self, bits: &mut BitVec, names: &mut Names) {
@@ -461,29 +491,43 @@ impl<T: ProtoSerialize> ProtoSerialize for Option<T> {
 impl ProtoSerialize for Statement {
   fn proto_serialize(&self, bits: &mut BitVec, names: &mut Names) {
     match self {
-      Statement::Fun { name, args, func, init, sign } => {
+      Statement::Fun { name, args, func, init, until, sign } => {
         serialize_fixlen(4, 0, bits);
         name.proto_serialize(bits, names);
         serialize_list(args, bits, names);
         func.proto_serialize(bits, names);
         init.proto_serialize(bits, names);
+        until.proto_serialize(bits, names);
         sign.proto_serialize(bits, names);
       }
-      Statement::Ctr { name, args, sign } => {
+      Statement::Ctr { name, args, until, sign } => {
         serialize_fixlen(4, 1, bits);
         name.proto_serialize(bits, names);
         serialize_list(args, bits, names);
+        until.proto_serialize(bits, names);
         sign.proto_serialize(bits, names);
       }
-      Statement::Run { expr, sign } => {
+      Statement::Run { expr, mana, nonce, until, sign } => {
         serialize_fixlen(4, 2, bits);
         expr.proto_serialize(bits, names);
+        mana.proto_serialize(bits, names);
+        nonce.proto_serialize(bits, names);
+        until.proto_serialize(bits, names);
         sign.proto_serialize(bits, names);
       }
-      Statement::Reg { name, ownr, sign } => {
+      Statement::Reg { name, ownr, until, sign } => {
         serialize_fixlen(4, 3, bits);
         name.proto_serialize(bits, names);
         serialize_fixlen_big(128, &U256::from(**ownr), bits);
+        until.proto_serialize(bits, names);
+        sign.proto_serialize(bits, names);
+      }
+      Statement::Own { name, ownr, nonce, until, sign } => {
+        serialize_fixlen(4, 4, bits);
+        name.proto_serialize(bits, names);
+        serialize_fixlen_big(128, &U256::from(**ownr), bits);
+        nonce.proto_serialize(bits, names);
+        until.proto_serialize(bits, names);
         sign.proto_serialize(bits, names);
       }
     }
@@ -501,26 +545,41 @@ impl ProtoSerialize for Statement {
         let args = deserialize_list(bits, index, names)?;
         let func = Func::proto_deserialize(bits, index, names)?;
         let init = Option::<Term>::proto_deserialize(bits, index, names)?;
+        let until = Option::<u64>::proto_deserialize(bits, index, names)?;
         let sign = Option::<Signature>::proto_deserialize(bits, index, names)?;
-        Some(Statement::Fun { name, args, func, init, sign })
+        Some(Statement::Fun { name, args, func, init, until, sign })
       }
       1 => {
         let name = Name::proto_deserialize(bits, index, names)?;
         let args = deserialize_list(bits, index, names)?;
+        let until = Option::<u64>::proto_deserialize(bits, index, names)?;
         let sign = Option::proto_deserialize(bits, index, names)?;
-        Some(Statement::Ctr { name, args, sign })
+        Some(Statement::Ctr { name, args, until, sign })
       }
       2 => {
         let expr = Term::proto_deserialize(bits, index, names)?;
+        let mana = Option::<u64>::proto_deserialize(bits, index, names)?;
+        let nonce = Option::<U120>::proto_deserialize(bits, index, names)?;
+        let until = Option::<u64>::proto_deserialize(bits, index, names)?;
         let sign = Option::proto_deserialize(bits, index, names)?;
-        Some(Statement::Run { expr, sign })
+        Some(Statement::Run { expr, mana, nonce, until, sign })
       }
       3 => {
         let name = Name::proto_deserialize(bits, index, names)?;
         let ownr = deserialize_fixlen_big(128, bits, index)?.low_u128();
         let ownr: U120 = ownr.try_into().ok()?;
+        let until = Option::<u64>::proto_deserialize(bits, index, names)?;
+        let sign = Option::proto_deserialize(bits, index, names)?;
+        Some(Statement::Reg { name, ownr, until, sign })
+      }
+      4 => {
+        let name = Name::proto_deserialize(bits, index, names)?;
+        let ownr = deserialize_fixlen_big(128, bits, index)?.low_u128();
+        let ownr: U120 = ownr.try_into().ok()?;
+        let nonce = Option::<U120>::proto_deserialize(bits, index, names)?;
+        let until = Option::<u64>::proto_deserialize(bits, index, names)?;
         let sign = Option::proto_deserialize(bits, index, names)?;
-        Some(Statement::Reg { name, ownr, sign })
+        Some(Statement::Own { name, ownr, nonce, until, sign })
       }
       _ => None,
     }
@@ -13,6 +13,7 @@ use kindelia::api::{client as api_client, Hash, HexStatement};
 use kindelia::bits::ProtoSerialize;
 use kindelia::common::Name;
 use kindelia::crypto;
+use kindelia::format;
 use kindelia::hvm::{self, view_statement, Statement};
 use kindelia::net;
 use kindelia::node;
@@ -29,6 +30,8 @@ type NC = UdpSocket;
 
 kindelia test file.kdl
 
+kindelia fmt [--check] file.kdl
+
 kindelia serialize code.kdl > code.hex.txt
 
 kindelia deserialize code.hex.txt
@@ -123,6 +126,12 @@ pub enum CliCommand {
     /// Whether to consider size and mana in the execution.
     #[clap(long)]
     sudo: bool,
+    /// Whether to print each rewrite rule fired during evaluation.
+    #[clap(long)]
+    trace: bool,
+    /// Whether to print the cost of each function rule, most expensive first.
+    #[clap(long)]
+    profile: bool,
   },
   /// Serialize a code file.
   Serialize {
@@ -139,6 +148,14 @@ pub enum CliCommand {
     /// Hex string of the serialized statement.
     stmt: String,
   },
+  /// Format a Kindelia code file (.kdl) in place.
+  Fmt {
+    /// The path to the file to format. Formatted stdin goes to stdout.
+    file: FileInput,
+    /// Only check if the file is formatted, failing if it isn't.
+    #[clap(long)]
+    check: bool,
+  },
   /// Sign a code file.
   Sign {
     /// The path to the file to sign.
@@ -159,6 +176,15 @@ pub enum CliCommand {
     #[clap(long, short = 'e')]
     encoded: bool,
   },
+  /// Check if a Kindelia (.kdl) file fits in a block, dry-running it on the
+  /// current remote KVM state.
+  Fit {
+    /// Input file.
+    file: FileInput,
+    /// In case the input code is serialized.
+    #[clap(long, short = 'e')]
+    encoded: bool,
+  },
   /// Post a Kindelia code file.
   Publish {
     /// The path to the file to post.
@@ -223,6 +249,20 @@ pub enum NodeCommand {
     #[clap(long, short)]
     json: bool,
   },
+  /// Exports the runtime state at a block to a file. The node must not be
+  /// running.
+  ExportState {
+    /// The hash of the block on the node's best chain.
+    hash: String,
+    /// The file to write the state to.
+    file: PathBuf,
+  },
+  /// Imports the runtime state from a file exported by another node. The
+  /// node must not be running, and will sync from the state's block.
+  ImportState {
+    /// The file to read the state from.
+    file: PathBuf,
+  },
 }
 
 #[derive(Subcommand)]
@@ -265,6 +305,13 @@ pub enum GetKind {
   BlockHash {
     index: u64,
   },
+  /// Get the result of a run statement.
+  Run {
+    /// The index of the block the statement is in.
+    block_idx: u64,
+    /// The index of the statement in the block.
+    stmt_idx: u64,
+  },
   /// Get node stats.
   Stats {
     /// The stat of the node to get.
@@ -502,14 +549,14 @@ pub fn run_cli() -> Result<(), String> {
   .resolve(parsed.api, None)?;
 
   match parsed.command {
-    CliCommand::Test { file, sudo } => {
-      let code: String = file.read_to_string()?;
-      test_code(&code, sudo);
+    CliCommand::Test { file, sudo, trace, profile } => {
+      let stmts = load_code(file, false)?;
+      test_code(&stmts, sudo, trace, profile);
       Ok(())
     }
     CliCommand::Serialize { file } => {
-      let code: String = file.read_to_string()?;
-      serialize_code(&code);
+      let stmts = load_code(file, false)?;
+      serialize_code(&stmts);
       Ok(())
     }
     CliCommand::Deserialize { file } => {
@@ -517,6 +564,30 @@ pub fn run_cli() -> Result<(), String> {
       deserialize_code(&code)
     }
     CliCommand::Unserialize { stmt } => deserialize_code(&stmt),
+    CliCommand::Fmt { file, check } => {
+      let code = file.read_to_string()?;
+      let formatted = format::format_code(&code)
+        .map_err(|errs| hvm::show_parse_errors(&code, &errs))?;
+      if check {
+        if formatted != code {
+          return Err(format!("'{}' is not formatted.", file));
+        }
+        return Ok(());
+      }
+      match file {
+        FileInput::Stdin => {
+          print!("{}", formatted);
+          Ok(())
+        }
+        FileInput::Path { path } => {
+          if formatted != code {
+            std::fs::write(&path, formatted)
+              .map_err(|e| format!("Cannot write to '{:?}' file: {}", path, e))?;
+          }
+          Ok(())
+        }
+      }
+    }
     CliCommand::Sign { file, secret_file, encoded, encoded_output } => {
       let skey: String = arg_from_file_or_stdin(secret_file.into())?;
       let skey = skey.trim();
@@ -526,42 +597,57 @@ pub fn run_cli() -> Result<(), String> {
       let skey: [u8; 32] = skey
         .try_into()
         .map_err(|_| "Secret key should have exactly 64 bytes".to_string())?;
-      let code = load_code(file, encoded)?;
-      let statement = match &code[..] {
+      // imported statements are output unsigned, before the signed one
+      let code = load_code_imports(file, encoded)?;
+      let (imported, code) = code.statements.split_at(code.imported);
+      let statement = match code {
         [stmt] => sign_code(stmt, &skey),
         _ => Err("Input file should contain exactly one statement".to_string()),
       }?;
-      if encoded_output {
-        println!("{}", hex::encode(statement.proto_serialized().to_bytes()));
-      } else {
-        println!("{}", view_statement(&statement));
-      };
+      for statement in imported.iter().chain([&statement]) {
+        if encoded_output {
+          println!("{}", hex::encode(statement.proto_serialized().to_bytes()));
+        } else {
+          println!("{}", view_statement(statement));
+        };
+      }
       Ok(())
     }
     CliCommand::RunRemote { file, encoded } => {
       // TODO: client timeout
-      let code = file.read_to_string()?;
       let f = |client: api_client::ApiClient, stmts| async move {
         client.run_code(stmts).await
       };
-      let stmts = if encoded {
-        statements_from_hex_seq(&code)?
-      } else {
-        hvm::parse_code(&code)?
-      };
+      let stmts = load_code(file, encoded)?;
       let results = run_on_remote(&api_url, stmts, f)?;
       for result in results {
         println!("{}", result);
       }
       Ok(())
     }
-    CliCommand::Publish { file, encoded } => {
-      let code = file.read_to_string()?;
-      let stmts = if encoded {
-        statements_from_hex_seq(&code)?
-      } else {
-        hvm::parse_code(&code)?
+    CliCommand::Fit { file, encoded } => {
+      let f = |client: api_client::ApiClient, stmts| async move {
+        client.fit_code(stmts).await
       };
+      let stmts = load_code(file, encoded)?;
+      let info = run_on_remote(&api_url, stmts, f)?;
+      for result in &info.results {
+        if let Err(err) = result {
+          println!("[error] {}", err.err);
+        }
+      }
+      println!("[body] {} / {} bytes", info.used_bytes, info.max_bytes);
+      println!("[mana] {} / {}", info.used_mana, info.max_mana);
+      println!("[size] {} / {} bits", info.used_bits, info.max_bits);
+      if info.fits {
+        println!("Fits in a block.");
+        Ok(())
+      } else {
+        Err("Does not fit in a block.".to_string())
+      }
+    }
+    CliCommand::Publish { file, encoded } => {
+      let stmts = load_code(file, encoded)?;
       publish_code(&api_url, stmts)
     }
     CliCommand::Post { stmt } => {
@@ -589,6 +675,14 @@ pub fn run_cli() -> Result<(), String> {
       }
       .resolve(data_dir, config)?;
 
+      let rollback_config = ConfigSettingsBuilder::default()
+        .prop("node.rollback")
+        .default_value(|| Ok(config::RollbackConfig::default()))
+        .build()
+        .unwrap()
+        .resolve_from_file_opt(config)?
+        .unwrap_or_default();
+
       match command {
         NodeCommand::Clean => {
           // warning
@@ -674,12 +768,24 @@ pub fn run_cli() -> Result<(), String> {
             }),
             api: Some(api_config),
             ws: None, // TODO: load from config file
+            rollback: rollback_config,
           };
 
           node::start(node_cfg, node_comm, initial_peers);
 
           Ok(())
         }
+        NodeCommand::ExportState { hash, file } => {
+          let hash = Hash::try_from(hash.as_str())?;
+          node::export_state(&data_path, hash.into(), &file, &rollback_config)?;
+          println!("Exported the state at block {} to '{}'.", hash, file.display());
+          Ok(())
+        }
+        NodeCommand::ImportState { file } => {
+          let hash = node::import_state(&data_path, &file, &rollback_config)?;
+          println!("Imported the state at block {}.", Hash::from(hash));
+          Ok(())
+        }
       }
     }
     CliCommand::Util { command } => match command {
@@ -725,6 +831,15 @@ pub async fn get_info(
       println!("{}", block_hash);
       Ok(())
     }
+    GetKind::Run { block_idx, stmt_idx } => {
+      let result = client.get_run_result(block_idx, stmt_idx).await?;
+      if json {
+        println!("{}", serde_json::to_string(&result).unwrap());
+      } else {
+        println!("{}", hvm::view_term(&hvm::Term::num(result)));
+      }
+      Ok(())
+    }
     GetKind::Block { hash } => {
       let hash = Hash::try_from(hash.as_str())?;
       let block = client.get_block(hash).await?;
@@ -759,6 +874,7 @@ pub async fn get_info(
             args: vec![Name::NONE],
             func,
             init: Some(hvm::Term::var(Name::NONE)), // to show that we are actually not returning the initial state
+            until: None,
             sign: None,
           };
           println!("{}", statement);
@@ -824,9 +940,7 @@ pub async fn get_info(
   }
 }
 
-pub fn serialize_code(code: &str) {
-  let statements =
-    hvm::read_statements(code).map_err(|err| err.erro).unwrap().1;
+pub fn serialize_code(statements: &[Statement]) {
   for statement in statements {
     println!("{}", hex::encode(statement.proto_serialized().to_bytes()));
   }
@@ -852,7 +966,8 @@ pub fn sign_code(
     Statement::Fun { sign, .. }
     | Statement::Ctr { sign, .. }
     | Statement::Run { sign, .. }
-    | Statement::Reg { sign, .. } => {
+    | Statement::Reg { sign, .. }
+    | Statement::Own { sign, .. } => {
       if sign.is_some() {
         return Err("Statement already has a signature.".to_string());
       }
@@ -882,8 +997,8 @@ pub fn publish_code(
   Ok(())
 }
 
-pub fn test_code(code: &str, sudo: bool) {
-  hvm::test_statements_from_code(code, sudo);
+pub fn test_code(stmts: &Vec<Statement>, sudo: bool, trace: bool, profile: bool) {
+  hvm::test_statements(stmts, sudo, trace, profile);
 }
 
 fn init_socket() -> Option<UdpSocket> {
@@ -963,15 +1078,24 @@ fn init_config_file(path: &Path) -> Result<(), String> {
 // ----
 
 fn load_code(file: FileInput, encoded: bool) -> Result<Vec<Statement>, String> {
-  let code = file.read_to_string()?;
-  handle_code(&code, encoded)
+  Ok(load_code_imports(file, encoded)?.statements)
 }
 
-fn handle_code(code: &str, encoded: bool) -> Result<Vec<Statement>, String> {
+/// Reads statements from a file, resolving its imports relative to it.
+fn load_code_imports(
+  file: FileInput,
+  encoded: bool,
+) -> Result<hvm::Loaded, String> {
+  let code = file.read_to_string()?;
   if encoded {
-    statements_from_hex_seq(code)
+    let statements = statements_from_hex_seq(&code)?;
+    Ok(hvm::Loaded { statements, imported: 0 })
   } else {
-    hvm::parse_code(code)
+    let path = match &file {
+      FileInput::Path { path } => Some(path.as_path()),
+      FileInput::Stdin => None,
+    };
+    hvm::load_code(&code, path)
   }
 }
 
@@ -1194,3 +1318,18 @@ impl ArgumentFrom<toml::Value> for config::ApiConfig {
     t.try_into().map_err(|_| "Could not convert value into array".to_string())
   }
 }
+
+impl ArgumentFrom<toml::Value> for config::RollbackConfig {
+  fn arg_from(t: toml::Value) -> Result<Self, String> {
+    let rollback: Self = t
+      .try_into()
+      .map_err(|_| "Could not convert value into rollback config".to_string())?;
+    if rollback.heaps < 2 {
+      return Err("`heaps` must be at least 2".to_string());
+    }
+    if rollback.spacing < 1 {
+      return Err("`spacing` must be at least 1".to_string());
+    }
+    Ok(rollback)
+  }
+}
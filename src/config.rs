@@ -22,6 +22,8 @@ pub struct NodeConfig {
   pub api: Option<ApiConfig>,
   #[builder(default)]
   pub ws: Option<WsConfig>,
+  #[builder(default)]
+  pub rollback: RollbackConfig,
 }
 
 // Mineration config
@@ -34,6 +36,25 @@ pub struct MineConfig {
   pub slow_mining: Option<u64>,
 }
 
+// Rollback config
+// ===============
+
+/// The past states kept for block reorgs: `heaps` snapshots, taken every
+/// `spacing` ticks. Reorgs deeper than `heaps * spacing` blocks can't be
+/// rolled back to.
+#[derive(Debug, Clone, Copy, Builder, Serialize, Deserialize)]
+#[builder(setter(strip_option))]
+pub struct RollbackConfig {
+  pub heaps: u64,
+  pub spacing: u64,
+}
+
+impl Default for RollbackConfig {
+  fn default() -> Self {
+    RollbackConfig { heaps: 16, spacing: 64 }
+  }
+}
+
 // User Interface config
 // =====================
 
@@ -30,6 +30,14 @@ impl From<&Hash> for U256 {
   }
 }
 
+impl From<&U256> for Hash {
+  fn from(value: &U256) -> Self {
+    let mut bytes = [0; 32];
+    value.to_little_endian(&mut bytes);
+    Hash(bytes)
+  }
+}
+
 // Keccak256
 // ---------
 
@@ -100,6 +108,174 @@ impl<T> Keccakable for Hashed<T> {
   }
 }
 
+// Merkle trees
+// ------------
+
+/// Hash of a Merkle tree leaf holding `data`. Leaves and inner nodes are
+/// hashed with different prefixes, so that neither can pass for the other.
+pub fn merkle_leaf(data: &[u8]) -> Hash {
+  let mut bytes = Vec::with_capacity(1 + data.len());
+  bytes.push(0);
+  bytes.extend_from_slice(data);
+  Hash::keccak256_from_bytes(&bytes)
+}
+
+/// Hash of a Merkle tree inner node with children `left` and `right`.
+pub fn merkle_node(left: &Hash, right: &Hash) -> Hash {
+  let mut bytes = Vec::with_capacity(65);
+  bytes.push(1);
+  bytes.extend_from_slice(&left.0);
+  bytes.extend_from_slice(&right.0);
+  Hash::keccak256_from_bytes(&bytes)
+}
+
+/// Root of the Merkle tree over `leaves`, pairing nodes level by level. A node
+/// left without a pair is carried up to the next level as it is. The root of
+/// no leaves is zero.
+pub fn merkle_root(leaves: &[Hash]) -> Hash {
+  if leaves.is_empty() {
+    return Hash([0; 32]);
+  }
+  let mut level = leaves.to_vec();
+  while level.len() > 1 {
+    level = merkle_level(&level);
+  }
+  level.pop().unwrap()
+}
+
+/// Siblings of the nodes on the path from the leaf at `index` up to the root of
+/// the Merkle tree over `leaves`. Nodes without a pair have no sibling.
+pub fn merkle_proof(leaves: &[Hash], mut index: usize) -> Vec<Hash> {
+  let mut proof = vec![];
+  let mut level = leaves.to_vec();
+  while level.len() > 1 {
+    if let Some(sibling) = level.get(index ^ 1) {
+      proof.push(sibling.clone());
+    }
+    level = merkle_level(&level);
+    index /= 2;
+  }
+  proof
+}
+
+/// Checks that `leaf` is the leaf at `index` of a Merkle tree over `count`
+/// leaves with the given `root`, using the `proof` built by `merkle_proof`.
+pub fn merkle_verify(
+  root: &Hash,
+  leaf: &Hash,
+  mut index: usize,
+  mut count: usize,
+  proof: &[Hash],
+) -> bool {
+  if index >= count {
+    return false;
+  }
+  let mut node = leaf.clone();
+  let mut proof = proof.iter();
+  while count > 1 {
+    if index ^ 1 < count {
+      let sibling = match proof.next() {
+        Some(sibling) => sibling,
+        None => return false,
+      };
+      node = if index % 2 == 1 {
+        merkle_node(sibling, &node)
+      } else {
+        merkle_node(&node, sibling)
+      };
+    }
+    index /= 2;
+    count = count.div_ceil(2);
+  }
+  proof.next().is_none() && node == *root
+}
+
+/// A Merkle tree that keeps all of its levels, built as by `merkle_root`, so
+/// that changing its leaves only rehashes the nodes above them.
+#[derive(Debug, Clone)]
+pub struct MerkleTree {
+  levels: Vec<Vec<Hash>>, // from the leaves up to the root
+}
+
+impl MerkleTree {
+  pub fn new(leaves: Vec<Hash>) -> Self {
+    let mut tree = MerkleTree { levels: vec![leaves] };
+    tree.rehash_from(0);
+    tree
+  }
+
+  pub fn leaves(&self) -> &[Hash] {
+    &self.levels[0]
+  }
+
+  pub fn root(&self) -> Hash {
+    match self.levels.last().unwrap().first() {
+      Some(root) => root.clone(),
+      None => Hash([0; 32]),
+    }
+  }
+
+  /// Same as `merkle_proof`, without rehashing the tree.
+  pub fn proof(&self, mut index: usize) -> Vec<Hash> {
+    let mut proof = vec![];
+    for level in &self.levels[.. self.levels.len() - 1] {
+      if let Some(sibling) = level.get(index ^ 1) {
+        proof.push(sibling.clone());
+      }
+      index /= 2;
+    }
+    proof
+  }
+
+  /// Replaces the leaf at `index`, rehashing its path up to the root.
+  pub fn set(&mut self, mut index: usize, leaf: Hash) {
+    self.levels[0][index] = leaf;
+    for depth in 0 .. self.levels.len() - 1 {
+      let start = index - index % 2;
+      let end = std::cmp::min(start + 2, self.levels[depth].len());
+      let node = merkle_level(&self.levels[depth][start .. end]).pop().unwrap();
+      index /= 2;
+      self.levels[depth + 1][index] = node;
+    }
+  }
+
+  /// Replaces the leaves from `index` on by `leaves`, rehashing the nodes on
+  /// their right side of the tree.
+  pub fn replace_from(&mut self, index: usize, leaves: Vec<Hash>) {
+    self.levels[0].truncate(index);
+    self.levels[0].extend(leaves);
+    self.rehash_from(index);
+  }
+
+  // Recomputes the nodes above the leaves from `index` on
+  fn rehash_from(&mut self, mut index: usize) {
+    let mut depth = 0;
+    while self.levels[depth].len() > 1 {
+      index /= 2;
+      let upper = merkle_level(&self.levels[depth][index * 2 ..]);
+      if self.levels.len() == depth + 1 {
+        self.levels.push(vec![]);
+      }
+      self.levels[depth + 1].truncate(index);
+      self.levels[depth + 1].extend(upper);
+      depth += 1;
+    }
+    self.levels.truncate(depth + 1);
+  }
+}
+
+// Hashes each pair of nodes of a Merkle tree level into the level above it
+fn merkle_level(level: &[Hash]) -> Vec<Hash> {
+  level
+    .chunks(2)
+    .map(|pair| match pair {
+      [left, right] => merkle_node(left, right),
+      [node] => node.clone(),
+      _ => unreachable!(),
+    })
+    .collect()
+}
+
 // Address
 // =======
 
@@ -11,6 +11,7 @@ use warp::{Filter, Rejection, Reply};
 
 use crate::api::Hash;
 use crate::config::{UiConfig, WsConfig};
+use crate::hvm::{self, StatementInfo, StatementResult};
 use crate::net::ProtoAddr;
 use crate::node::{HashedBlock, Peer};
 
@@ -90,6 +91,16 @@ pub enum AddBlockEvent {
     parent: Hash,
   },
   TooLate,
+  Logs {
+    logs: Vec<StatementLogs>,
+  },
+}
+
+/// Terms logged by a `run` statement through the `LOG` IO primitive.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct StatementLogs {
+  stmt: usize,
+  logs: Vec<hvm::Term>,
 }
 
 #[derive(Debug, Clone, serde::Serialize)]
@@ -373,6 +384,18 @@ impl std::fmt::Display for NodeEventType {
             blocks.iter().map(|b| format!("{}", b)).collect();
           format!("[computed] block {} | computed_blocks: {}", block, blocks)
         }
+        AddBlockEvent::Logs { logs } => {
+          let logs = logs
+            .iter()
+            .flat_map(|l| {
+              l.logs
+                .iter()
+                .map(move |t| format!("#{}: {}", l.stmt, hvm::view_term(t)))
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+          format!("[logs] block {} | logs: {}", block, logs)
+        }
       },
       NodeEventType::Mining { event } => match event {
         MiningEvent::Success { block, target } => {
@@ -456,6 +479,30 @@ impl NodeEventType {
       event: Box::new(AddBlockEvent::Computed { blocks }),
     }
   }
+  pub fn logs(
+    block: &HashedBlock,
+    height: Option<u128>,
+    results: &[StatementResult],
+  ) -> Option<Self> {
+    let logs: Vec<_> = results
+      .iter()
+      .enumerate()
+      .filter_map(|(stmt, res)| match res {
+        Ok(StatementInfo::Run { logs, .. }) if !logs.is_empty() => {
+          Some(StatementLogs { stmt, logs: logs.clone() })
+        }
+        _ => None,
+      })
+      .collect();
+    if logs.is_empty() {
+      return None;
+    }
+    let hash = U256::from(block.get_hash());
+    Some(NodeEventType::AddBlock {
+      block: BlockInfo { hash: hash.into(), parent: block.prev.into(), height },
+      event: Box::new(AddBlockEvent::Logs { logs }),
+    })
+  }
   pub fn reorg(
     old: (&HashedBlock, u128),
     new: (&HashedBlock, u128),
@@ -0,0 +1,537 @@
+// Formatter
+// =========
+
+// Rewrites Kindelia code (.kdl) in a canonical layout, keeping its comments.
+// The statements of the output parse exactly into the ones of the input.
+//
+// Comments between statements and between the rules of a `fun` are moved
+// along with them. A rule or statement that has comments inside its terms is
+// kept as written, only re-indented, as terms don't record where comments are.
+
+use std::collections::HashMap;
+
+use crate::common::{Name, U120};
+use crate::hvm::{
+  self, read_char, read_import, read_name, read_numb, read_rule, read_statement, read_until,
+  term_to_string, view_expiry, view_mana, view_name, view_nonce, view_oper,
+  view_sign, view_string, ParseErr, SrcPos, Statement, Term,
+};
+
+const WIDTH: usize = 80;
+
+// Comments and blank lines between two items of the source
+struct Gap<'a> {
+  trailing: Option<&'a str>, // comment on the line of the previous item
+  lines: Vec<Option<&'a str>>, // comments on their own lines, `None` for blanks
+}
+
+// Reads whitespace and comments until the next item.
+fn read_gap(code: &str) -> (Gap<'_>, &str) {
+  let mut gap = Gap { trailing: None, lines: vec![] };
+  let mut code = code;
+  let mut first_line = true;
+  let mut blank_line = false;
+  loop {
+    code = code.trim_start_matches([' ', '\t', '\r']);
+    if code.starts_with("//") {
+      let end = code.find('\n').unwrap_or(code.len());
+      let comment = code[..end].trim_end();
+      if first_line {
+        gap.trailing = Some(comment);
+      } else {
+        gap.lines.push(Some(comment));
+      }
+      blank_line = false;
+      code = &code[end..];
+    } else if let Some(rest) = code.strip_prefix('\n') {
+      if blank_line && gap.lines.last() != Some(&None) {
+        gap.lines.push(None);
+      }
+      first_line = false;
+      blank_line = true;
+      code = rest;
+    } else {
+      return (gap, code);
+    }
+  }
+}
+
+// Output lines, already indented
+struct Lines {
+  lines: Vec<String>,
+}
+
+impl Lines {
+  fn push(&mut self, indent: usize, text: &str) {
+    for line in text.lines() {
+      self
+        .lines
+        .push(format!("{}{}", " ".repeat(indent), line).trim_end().to_string());
+    }
+  }
+
+  fn blank(&mut self) {
+    if self.lines.last().is_some_and(|line| !line.is_empty()) {
+      self.lines.push(String::new());
+    }
+  }
+
+  fn trim_blank(&mut self) {
+    while self.lines.last().is_some_and(|line| line.is_empty()) {
+      self.lines.pop();
+    }
+  }
+
+  // Puts the comments of a gap after the last line, at the given indentation.
+  fn gap(&mut self, indent: usize, gap: &Gap) {
+    if let Some(comment) = gap.trailing {
+      match self.lines.last_mut() {
+        Some(line) if !line.is_empty() => {
+          line.push(' ');
+          line.push_str(comment);
+        }
+        _ => self.push(indent, comment),
+      }
+    }
+    for line in &gap.lines {
+      match line {
+        Some(comment) => self.push(indent, comment),
+        None => self.blank(),
+      }
+    }
+  }
+}
+
+// Whether there is a comment in the code, ignoring `//` inside strings.
+fn has_comment(code: &str) -> bool {
+  let mut chars = code.chars().peekable();
+  let mut in_string = false;
+  while let Some(chr) = chars.next() {
+    match chr {
+      '\\' if in_string => {
+        chars.next();
+      }
+      '"' => in_string = !in_string,
+      '/' if !in_string && chars.peek() == Some(&'/') => return true,
+      _ => {}
+    }
+  }
+  false
+}
+
+// Spelling of each number literal of a statement, so that `'name'` and `#x`
+// numbers are not printed back in decimal.
+type Spellings = HashMap<u128, String>;
+
+fn read_spellings(code: &str) -> Spellings {
+  let mut spellings = HashMap::new();
+  for line in code.lines() {
+    let mut line = &line[..line.find("//").unwrap_or(line.len())];
+    while let Some(idx) = line.find(['#', '\'']) {
+      let lit = &line[idx..];
+      let read = if let Some(numb) = lit.strip_prefix('#') {
+        read_numb::<U120>(numb).ok()
+      } else {
+        read_name(&lit[1..])
+          .and_then(|(code, name)| {
+            read_char(code, '\'').map(|(code, ())| (code, name))
+          })
+          .ok()
+          .and_then(|(code, name)| {
+            U120::try_from(*name).ok().map(|numb| (code, numb))
+          })
+      };
+      match read {
+        Some((rest, numb)) => {
+          let spelling = &lit[..lit.len() - rest.len()];
+          spellings.entry(*numb).or_insert_with(|| spelling.to_string());
+          line = rest;
+        }
+        None => {
+          line = &lit[1..];
+        }
+      }
+    }
+  }
+  spellings
+}
+
+fn view_numb(numb: U120, spellings: &Spellings) -> String {
+  match spellings.get(&*numb) {
+    Some(spelling) => spelling.clone(),
+    None => format!("#{}", numb),
+  }
+}
+
+// Terms
+// -----
+
+// A binding that is laid out on its own line, followed by the rest of the term
+enum Bind<'a> {
+  Dup { nam0: Name, nam1: Name, expr: &'a Term },
+  Ask { name: Name, expr: &'a Term },
+  Let { name: Name, expr: &'a Term },
+}
+
+fn read_bind(term: &Term) -> Option<(Bind<'_>, &Term)> {
+  match term {
+    Term::Dup { nam0, nam1, expr, body } => {
+      Some((Bind::Dup { nam0: *nam0, nam1: *nam1, expr }, body))
+    }
+    Term::App { func, argm } => match (&**func, &**argm) {
+      (Term::Lam { name, body }, expr) => {
+        Some((Bind::Let { name: *name, expr }, body))
+      }
+      (
+        expr @ (Term::Fun { .. } | Term::App { .. }),
+        Term::Lam { name, body },
+      ) => Some((Bind::Ask { name: *name, expr }, body)),
+      _ => None,
+    },
+    _ => None,
+  }
+}
+
+fn view_flat(term: &Term, spellings: &Spellings) -> String {
+  let view_args = |args: &[Term]| {
+    args
+      .iter()
+      .map(|arg| format!(" {}", view_flat(arg, spellings)))
+      .collect::<String>()
+  };
+  match term {
+    Term::Var { name } => view_name(*name),
+    Term::Dup { nam0, nam1, expr, body } => {
+      let expr = view_flat(expr, spellings);
+      let body = view_flat(body, spellings);
+      format!(
+        "dup {} {} = {}; {}",
+        view_name(*nam0),
+        view_name(*nam1),
+        expr,
+        body
+      )
+    }
+    Term::Lam { name, body } => {
+      format!("@{} {}", view_name(*name), view_flat(body, spellings))
+    }
+    Term::App { func, argm } => {
+      let func = view_flat(func, spellings);
+      let argm = view_flat(argm, spellings);
+      format!("({}{} {})", app_prefix(&func), func, argm)
+    }
+    Term::Ctr { name, args } => match term_to_string(term) {
+      Some(text) => view_string(&text),
+      None => format!("{{{}{}}}", name, view_args(args)),
+    },
+    Term::Fun { name, args } => format!("({}{})", name, view_args(args)),
+    Term::Num { numb } => view_numb(*numb, spellings),
+    Term::Op2 { oper, val0, val1 } => {
+      let val0 = view_flat(val0, spellings);
+      let val1 = view_flat(val1, spellings);
+      format!("({} {} {})", view_oper(oper), val0, val1)
+    }
+  }
+}
+
+// `(Foo x)` is a function call, so applying an uppercase variable needs `!`
+fn app_prefix(func: &str) -> &'static str {
+  if func.starts_with(|chr: char| chr.is_ascii_uppercase()) {
+    "! "
+  } else {
+    ""
+  }
+}
+
+// Lays out a term starting at column `indent`. Bindings go on their own
+// lines, and terms that don't fit in `WIDTH` get one argument per line.
+fn view_term(term: &Term, indent: usize, spellings: &Spellings) -> String {
+  let tab = " ".repeat(indent);
+  if let Some((bind, body)) = read_bind(term) {
+    let bind = match bind {
+      Bind::Dup { nam0, nam1, expr } => {
+        let expr = view_term(expr, indent, spellings);
+        format!("dup {} {} = {};", view_name(nam0), view_name(nam1), expr)
+      }
+      Bind::Ask { name, expr } if name.is_none() => {
+        format!("ask {};", view_term(expr, indent, spellings))
+      }
+      Bind::Ask { name, expr } => {
+        format!(
+          "ask {} = {};",
+          view_name(name),
+          view_term(expr, indent, spellings)
+        )
+      }
+      Bind::Let { name, expr } => {
+        format!(
+          "let {} = {};",
+          view_name(name),
+          view_term(expr, indent, spellings)
+        )
+      }
+    };
+    return format!("{}\n{}{}", bind, tab, view_term(body, indent, spellings));
+  }
+  let flat = view_flat(term, spellings);
+  if indent + flat.len() <= WIDTH || term_to_string(term).is_some() {
+    return flat;
+  }
+  let (open, args, close): (String, Vec<&Term>, &str) = match term {
+    Term::Lam { name, body } => {
+      return format!(
+        "@{} {}",
+        view_name(*name),
+        view_term(body, indent, spellings)
+      );
+    }
+    Term::App { func, argm } => {
+      let prefix = app_prefix(&view_flat(func, spellings));
+      (format!("({}", prefix.trim_end()), vec![&**func, &**argm], ")")
+    }
+    Term::Ctr { name, args } => {
+      (format!("{{{}", name), args.iter().collect(), "}")
+    }
+    Term::Fun { name, args } => {
+      (format!("({}", name), args.iter().collect(), ")")
+    }
+    Term::Op2 { oper, val0, val1 } => {
+      (format!("({}", view_oper(oper)), vec![&**val0, &**val1], ")")
+    }
+    _ => return flat,
+  };
+  let mut text = open;
+  for arg in args {
+    text.push_str(&format!(
+      "\n{}  {}",
+      tab,
+      view_term(arg, indent + 2, spellings)
+    ));
+  }
+  text.push_str(&format!("\n{}{}", tab, close));
+  text
+}
+
+// Lays out a term that is alone in a block, as in `run { term }`.
+fn view_block(term: &Term, spellings: &Spellings) -> String {
+  format!("{{\n  {}\n}}", view_term(term, 2, spellings))
+}
+
+// Statements
+// ----------
+
+// Re-indents code kept as written. `column` is where its first line started.
+fn reindent(code: &str, column: usize, indent: usize) -> String {
+  let mut lines = code.lines();
+  let mut text =
+    format!("{}{}", " ".repeat(indent), lines.next().unwrap_or("").trim());
+  for line in lines {
+    let spaces = line.len() - line.trim_start().len();
+    let extra = spaces.saturating_sub(column);
+    text.push('\n');
+    if !line.trim().is_empty() {
+      text.push_str(&" ".repeat(indent + extra));
+      text.push_str(line.trim());
+    }
+  }
+  text
+}
+
+fn view_rule(rule: &hvm::Rule, spellings: &Spellings) -> String {
+  let lhs = view_flat(&rule.lhs, spellings);
+  let rhs = view_flat(&rule.rhs, spellings);
+  if read_bind(&rule.rhs).is_none() && 2 + lhs.len() + 3 + rhs.len() <= WIDTH {
+    format!("  {} = {}", lhs, rhs)
+  } else {
+    format!("  {} =\n    {}", lhs, view_term(&rule.rhs, 4, spellings))
+  }
+}
+
+// The rules of a `fun`, with the comments around them
+struct FunBody<'a> {
+  // comments before each rule, its offset and code
+  rules: Vec<(Gap<'a>, usize, &'a str)>,
+  // comments before the closing brace
+  end: Gap<'a>,
+}
+
+// Reads the rules of a `fun` statement. Fails if there are comments outside
+// of its rules and the gaps between them.
+fn read_fun_body(code: &str) -> Option<FunBody<'_>> {
+  let (rest, _) = read_char(code.get(3..)?, '(').ok()?;
+  let (rest, _) = read_name(rest).ok()?;
+  let (rest, _) = read_until(rest, ')', read_name).ok()?;
+  let (mut rest, _) = read_char(rest, '{').ok()?;
+  if has_comment(&code[..code.len() - rest.len()]) {
+    return None;
+  }
+  let mut rules = vec![];
+  loop {
+    let (gap, next) = read_gap(rest);
+    if let Some(tail) = next.strip_prefix('}') {
+      if has_comment(tail) {
+        return None;
+      }
+      return Some(FunBody { rules, end: gap });
+    }
+    let (after, _) = read_rule(next).ok()?;
+    rules.push((
+      gap,
+      code.len() - next.len(),
+      &next[..next.len() - after.len()],
+    ));
+    rest = after;
+  }
+}
+
+// Formats a statement whose code, `code`, starts at `pos` of the source.
+fn view_statement(
+  statement: &Statement,
+  code: &str,
+  pos: SrcPos,
+) -> Vec<String> {
+  let spellings = read_spellings(code);
+  let mut out = Lines { lines: vec![] };
+  match statement {
+    Statement::Fun { name, args, func, init, until, sign } => {
+      let body = match read_fun_body(code) {
+        Some(body) => body,
+        None => {
+          out.push(0, &reindent(code, pos.column - 1, 0));
+          return out.lines;
+        }
+      };
+      let args = args
+        .iter()
+        .map(|arg| format!(" {}", view_name(*arg)))
+        .collect::<String>();
+      out.push(0, &format!("fun ({}{}) {{", name, args));
+      for (rule, (gap, offset, rule_code)) in
+        func.rules.iter().zip(body.rules.iter())
+      {
+        out.gap(2, gap);
+        if has_comment(rule_code) {
+          let rule_pos = SrcPos::at(code, *offset);
+          let column = if rule_pos.line == 1 { pos.column - 1 } else { 0 }
+            + rule_pos.column
+            - 1;
+          out.push(0, &reindent(rule_code, column, 2));
+        } else {
+          out.push(0, &view_rule(rule, &spellings));
+        }
+      }
+      out.gap(2, &body.end);
+      out.trim_blank();
+      let init = match init {
+        Some(init) => format!(" with {}", view_block(init, &spellings)),
+        None => String::new(),
+      };
+      out.push(
+        0,
+        &format!("}}{}{}{}", init, view_expiry(until), view_sign(sign)),
+      );
+    }
+    _ if has_comment(code) => {
+      out.push(0, &reindent(code, pos.column - 1, 0));
+    }
+    Statement::Ctr { name, args, until, sign } => {
+      let args = args
+        .iter()
+        .map(|arg| format!(" {}", view_name(*arg)))
+        .collect::<String>();
+      out.push(
+        0,
+        &format!(
+          "ctr {{{}{}}}{}{}",
+          name,
+          args,
+          view_expiry(until),
+          view_sign(sign)
+        ),
+      );
+    }
+    Statement::Run { expr, mana, nonce, until, sign } => {
+      let clauses = format!(
+        "{}{}{}",
+        view_nonce(nonce),
+        view_expiry(until),
+        view_sign(sign)
+      );
+      out.push(
+        0,
+        &format!(
+          "run{} {}{}",
+          view_mana(mana),
+          view_block(expr, &spellings),
+          clauses
+        ),
+      );
+    }
+    Statement::Reg { name, ownr, until, sign }
+    | Statement::Own { name, ownr, until, sign, .. } => {
+      let (keyword, nonce) = match statement {
+        Statement::Own { nonce, .. } => ("own", view_nonce(nonce)),
+        _ => ("reg", String::new()),
+      };
+      let name =
+        if *name == Name::EMPTY { String::new() } else { format!("{} ", name) };
+      let ownr = match spellings.get(&**ownr) {
+        Some(spelling) => spelling.clone(),
+        None => format!("#x{:0>30x}", **ownr),
+      };
+      out.push(
+        0,
+        &format!(
+          "{} {}{{ {} }}{}{}{}",
+          keyword,
+          name,
+          ownr,
+          nonce,
+          view_expiry(until),
+          view_sign(sign)
+        ),
+      );
+    }
+  }
+  out.lines
+}
+
+/// Formats Kindelia code, keeping the imports at its top. Fails with all
+/// parse errors if it doesn't parse.
+pub fn format_code(code: &str) -> Result<String, Vec<ParseErr>> {
+  let mut out = Lines { lines: vec![] };
+  let mut rest = code;
+  loop {
+    let (gap, next) = read_gap(rest);
+    let start = code.len() - next.len();
+    match read_import(next).map_err(|err| vec![err.place(next, start)])? {
+      (after, Some(file)) => {
+        out.gap(0, &gap);
+        out.push(0, &format!("import {}", view_string(&file)));
+        rest = after;
+      }
+      (_, None) => break,
+    }
+  }
+  hvm::parse_file_statements(code, code.len() - rest.len())?;
+  loop {
+    let (gap, next) = read_gap(rest);
+    out.gap(0, &gap);
+    if next.is_empty() {
+      break;
+    }
+    let start = code.len() - next.len();
+    let (after, statement) = read_statement(next).map_err(|err| vec![err.place(next, start)])?;
+    let pos = SrcPos::at(code, start);
+    out.lines.extend(view_statement(
+      &statement,
+      &next[..next.len() - after.len()],
+      pos,
+    ));
+    rest = after;
+  }
+  out.trim_blank();
+  let mut text = out.lines.join("\n");
+  text.push('\n');
+  Ok(text)
+}
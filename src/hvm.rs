@@ -203,22 +203,21 @@
 //
 // Kindelia's heap is set to grow exactly 8 GB per year. In other words, 10 years after the genesis
 // block, the heap size will be of exactly 80 GB. But that doesn't mean a full node will be able
-// to operate with even that much ram, because Kindelia must also save snapshots. Right now, it
-// stores at most 10 snapshots, trying to keep them distributed with exponentially decreasing ages.
-// For example, if we're on block 1000, it might store a snapshot of blocks 998, 996, 992, 984,
-// 968, 872, 744 and 488, which is compatible with the fact that longer-term rollbacks are
-// increasingly unlikely. If there is a rollback to block 990, we just go back to the earliest
-// snapshot, 984, and reprocess blocks 985-1000, which is much faster than recomputing the entire
-// history.
+// to operate with even that much ram, because Kindelia must also save snapshots. It keeps a ring
+// of them, spaced evenly, whose size and spacing are set by the node's config. For example, with
+// a spacing of 16 blocks, if we're on block 1000, it might store a snapshot of blocks 992, 976,
+// 960, 944 and so on, until the oldest one, which holds the state of every block before it. If
+// there is a rollback to block 990, we just go back to the earliest snapshot, 976, and reprocess
+// blocks 977-1000, which is much faster than recomputing the entire history.
 //
 // In order to keep a good set of snapshots, we must be able to create and discard these heaps.
 // Obviously, if this operation required copying the entire heap buffer every block, it would
 // completely destroy the network's performance. As such, instead, heaps only actually store
-// data that changed. So, using the example above, if a list was allocated and persisted on block 980,
-// it will actually be stored on the snapshot 984, which is the earliest snapshot after 980. If the
+// data that changed. So, using the example above, if a list was allocated and persisted on block 970,
+// it will actually be stored on the snapshot 976, which is the earliest snapshot after 970. If the
 // runtime, now on block 1000, attempts to read the memory where the list is allocated, it will
-// actually receive a signal that it is stored on a past heap, and look for it on 996 and 992,
-// until it is found on block 984.
+// actually receive a signal that it is stored on a past heap, and look for it on 992, until it is
+// found on block 976.
 //
 // To achieve that, hashmaps are used to store defined functions and persistent state pointers. If
 // a key isn't present, Kindelia will look for it on past snapshots. As for the runtime's memory,
@@ -244,8 +243,7 @@
 use std::collections::{hash_map, HashMap, HashSet};
 use std::fmt::{self, Write};
 use std::hash::{BuildHasherDefault, Hash, Hasher};
-use std::path::PathBuf;
-use std::fs::File;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::Instant;
 
@@ -255,12 +253,13 @@ use serde_with::{serde_as, DisplayFromStr};
 use crate::bits::ProtoSerialize;
 use crate::constants;
 use crate::crypto;
-use crate::util::{self, U128_SIZE, mask};
+use crate::util::{self, U128_SIZE, U256, mask};
 use crate::util::{LocMap, NameMap, U128Map, U120Map};
 use crate::NoHashHasher::NoHashHasher;
 
 use crate::common::{Name, U120};
-use crate::persistence::DiskSer;
+use crate::config::RollbackConfig;
+use crate::persistence::{self, DiskSer};
 
 /// This is the HVM's term type. It is used to represent an expression. It is not used in rewrite
 /// rules. Instead, it is stored on HVM's heap using its memory model, which will be elaborated
@@ -396,10 +395,11 @@ pub struct Store {
 /// A global statement that alters the state of the blockchain
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub enum Statement {
-  Fun { name: Name, args: Vec<Name>, func: Func, init: Option<Term>, sign: Option<crypto::Signature> },
-  Ctr { name: Name, args: Vec<Name>, sign: Option<crypto::Signature> },
-  Run { expr: Term, sign: Option<crypto::Signature> },
-  Reg { name: Name, ownr: U120, sign: Option<crypto::Signature> },
+  Fun { name: Name, args: Vec<Name>, func: Func, init: Option<Term>, until: Option<u64>, sign: Option<crypto::Signature> },
+  Ctr { name: Name, args: Vec<Name>, until: Option<u64>, sign: Option<crypto::Signature> },
+  Run { expr: Term, mana: Option<u64>, nonce: Option<U120>, until: Option<u64>, sign: Option<crypto::Signature> },
+  Reg { name: Name, ownr: U120, until: Option<u64>, sign: Option<crypto::Signature> },
+  Own { name: Name, ownr: U120, nonce: Option<U120>, until: Option<u64>, sign: Option<crypto::Signature> },
 }
 
 /// RawCell
@@ -492,6 +492,52 @@ pub struct Hashs {
   pub stmt_hashes: U128Map<crypto::Hash>,
 }
 
+// A map of `(block_idx, stmt_idx) -> U120`
+// It links a `run` statement position to its numeric result.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Runs {
+  pub runs: U128Map<U120>,
+}
+
+// A map of `subject -> nonce`
+// It holds the nonce that the next signed `run` of each subject must have.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Nonces {
+  pub nonces: U120Map<U120>,
+}
+
+// A map of `tick -> calls`
+// It holds the calls scheduled by SCHE, ran before the statements of a block.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scheds {
+  pub scheds: U128Map<Vec<Sched>>,
+}
+
+// A map of `FuncID -> Hash`
+// It holds the leaf of each name on the state root, see `Runtime::get_state_root`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Leafs {
+  pub leafs: NameMap<crypto::Hash>,
+}
+
+// The Merkle tree of the state root, over the committed leaves sorted by name. It is kept
+// between commits, so that a commit only rehashes the paths of the leaves it changed.
+#[derive(Debug, Clone)]
+pub struct StateTree {
+  names: Vec<Name>,
+  tree: crypto::MerkleTree,
+}
+
+/// A call of `(callee {name args...})`, scheduled by `caller` to run at `tick`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sched {
+  pub tick: u64,
+  pub caller: U120,
+  pub callee: Name,
+  pub name: Name,
+  pub args: Vec<U120>,
+}
+
 // HVM's memory state (nodes, functions, metadata, statistics)
 #[derive(Debug, Clone, PartialEq)]
 pub struct Heap {
@@ -503,6 +549,11 @@ pub struct Heap {
   pub indx: Indxs, // function name to position in heap
   pub hash: Hashs,
   pub ownr: Ownrs, // namespace owners
+  pub xfer: Indxs, // namespace name to position of its last transfer
+  pub runs: Runs,  // run results
+  pub nonc: Nonces, // next run nonce of each subject
+  pub sche: Scheds, // scheduled calls
+  pub leaf: Leafs, // state root leaves
   pub tick: u64,  // tick counter
   pub time: u128,  // block timestamp
   pub meta: u128,  // block metadata
@@ -515,20 +566,17 @@ pub struct Heap {
   pub size: u64,  // total used memory (in 64-bit words)
   pub mcap: u64,  // memory capacity (in 64-bit words)
   pub next: u64,  // memory index that *may* be empty
-  // TODO: store run results (Num). (block_idx, stmt_idx) [as u128] -> U120
 }
 
-// A list of past heap states, for block-reorg rollback
-// FIXME: this should be replaced by a much simpler index array
+// The past heap states, for block-reorg rollback. It is a ring of heap indices, holding a
+// snapshot every `spacing` ticks, from the oldest to the newest. When it is full, the oldest
+// snapshot absorbs the next one, so that it still covers the whole past state.
 #[derive(Debug, Clone)]
-pub enum Rollback {
-  Cons {
-    keep: u64,
-    life: u64,
-    head: u64,
-    tail: Arc<Rollback>,
-  },
-  Nil,
+pub struct Rollback {
+  ring: Vec<u64>, // heap index of each slot
+  head: usize,    // slot of the oldest snapshot
+  size: usize,    // number of snapshots
+  spacing: u64,   // ticks between snapshots
 }
 
 // The current and past states
@@ -537,8 +585,14 @@ pub struct Runtime {
   draw: u64,            // drawing heap index
   curr: u64,            // current heap index
   nuls: Vec<u64>,       // reuse heap indices
-  back: Arc<Rollback>,  // past states
-  path: PathBuf,        // where to save runtime state
+  back: Rollback,       // past states
+  path: Option<PathBuf>, // where to save runtime state, if it is persisted
+  tip: U256,            // block whose state was last committed, when run by a node
+  logs: Vec<Term>,      // terms logged by the running statement
+  dirt: HashSet<Name>,  // names whose state root leaves changed since the last commit
+  root: StateTree,      // state root tree, as of the last commit
+  trace: Option<Vec<TraceStep>>, // rewrites fired by `reduce`, when tracing
+  profile: Option<Profiler>,      // cost of each rewrite rule, when profiling
 }
 
 #[derive(Debug, Clone)]
@@ -568,15 +622,22 @@ pub enum DefinitionError {
   VarIsNotUsed { name : Name, rule_index: usize },
   NestedMatch { rule_index: usize },
   UnsupportedMatch { rule_index: usize },
-  
+  AuxNameTooBig { name: Name },
 }
 
 #[derive(Debug, Clone)]
 pub enum EffectFailure {
   NoSuchState { state: U120 },
   InvalidCallArg {caller: U120, callee: U120, arg: RawCell},
+  CallArgTooDeep { caller: U120, callee: U120 },
+  CallArgTooBig { caller: U120, callee: U120 },
   InvalidIOCtr { name: Name },
   InvalidIONonCtr { ptr: RawCell },
+  CallDepthExceeded { caller: U120, callee: U120 },
+  InvalidSchedTick { tick: U120 },
+  SchedTickFull { tick: U120 },
+  TooManyLogs { subject: U120 },
+  LogsTooBig { subject: U120 },
 }
 
 //pub fn heaps_invariant(rt: &Runtime) -> (bool, Vec<u8>, Vec<u64>) {
@@ -619,8 +680,90 @@ pub enum StatementInfo {
     size_diff: i64,
     #[serde_as(as = "DisplayFromStr")]
     end_size: u64,
+    logs: Vec<Term>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    trace: Option<Vec<TraceStep>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    profile: Option<Vec<ProfileRow>>,
   },
   Reg { name: Name, ownr: U120 },
+  Own { name: Name, prev: U120, ownr: U120 },
+  Sched {
+    name: Name,
+    caller: U120,
+    done_term: Term,
+    #[serde_as(as = "DisplayFromStr")]
+    used_mana: u64,
+    #[serde_as(as = "DisplayFromStr")]
+    size_diff: i64,
+    logs: Vec<Term>,
+  },
+}
+
+/// A rewrite rule fired by `reduce`, or a costly IO effect of `run_io`. Names
+/// follow the `*Mana` functions below. `Truncated` ends a trace that reached
+/// `MAX_TRACE_STEPS`, standing for the steps left out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Rewrite {
+  AppLam,
+  AppSup,
+  Op2Num,
+  Op2Sup,
+  FunCtr,
+  FunSup,
+  DupLam,
+  DupNum,
+  DupCtr,
+  DupDup,
+  DupSup,
+  DupEra,
+  IoHash,
+  IoSign,
+  IoSche,
+  IoCall,
+  IoLog,
+  Truncated,
+}
+
+/// A single step of a reduction trace: the rule fired, the function or
+/// constructor involved (if any) and the mana it was charged.
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TraceStep {
+  pub rule: Rewrite,
+  pub name: Option<Name>,
+  #[serde_as(as = "DisplayFromStr")]
+  pub mana: u64,
+}
+
+/// Aggregated cost of the rewrites of a statement that fired the same rule on
+/// the same function. For `FunCtr`, `index` is the matched rule of the
+/// `CompFunc`.
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProfileRow {
+  pub rule: Rewrite,
+  pub name: Option<Name>,
+  pub index: Option<u64>,
+  #[serde_as(as = "DisplayFromStr")]
+  pub mana: u64,
+  #[serde_as(as = "DisplayFromStr")]
+  pub rwts: u64,
+  #[serde_as(as = "DisplayFromStr")]
+  pub dups: u64,
+  #[serde_as(as = "DisplayFromStr")]
+  pub cells: u64,
+}
+
+type ProfileKey = (Rewrite, Option<Name>, Option<u64>);
+
+// Accumulates `ProfileRow`s. Dups and cells are attributed to the last
+// rewrite fired before they were created.
+struct Profiler {
+  rows: HashMap<ProfileKey, ProfileRow>,
+  last: Option<ProfileKey>,
+  dups: u64,  // dups counter when the last rewrite fired
+  cells: u64, // cells allocated since the last rewrite fired
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -634,6 +777,17 @@ pub type ParseResult<'a, A> = Result<(&'a str, A), ParseErr>;
 pub struct ParseErr {
   pub code: String,
   pub erro: String,
+  #[serde(default)]
+  pub offset: usize, // byte offset in the source, once placed by `parse_statements`
+}
+
+// A position in a source file. `offset` is in bytes, `line` and `column`
+// start at 1 and `column` counts chars.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SrcPos {
+  pub offset: usize,
+  pub line: usize,
+  pub column: usize,
 }
 
 // Constants
@@ -643,15 +797,6 @@ const U128_PER_KB: u128 = (1024 / U128_SIZE) as u128;
 const U128_PER_MB: u128 = U128_PER_KB << 10;
 const U128_PER_GB: u128 = U128_PER_MB << 10;
 
-// With the constants below, we pre-alloc 6 heaps, which is enough for
-// 4 snapshots: 16 seconds old, 4 minutes old, 1 hour old and 1 day old, on
-// average.
-
-/// Number of heaps (2 are used for draw/curr, the rest for rollbacks)
-const MAX_HEAPS: u64 = 6;
-// Number of heaps for snapshots
-const MAX_ROLLBACK: u64 = MAX_HEAPS - 2;
-
 pub const MAX_TERM_DEPTH: u128 = 256; // maximum depth of a LHS or RHS term
 
 // Size of each RawCell field in bits
@@ -720,15 +865,23 @@ pub const U64_NONE: u64 = u64::MAX; //TODO: rewrite as FFF's?if think it is easi
 //   (DONE expr)           : (IO r)
 //   (TAKE           then) : (IO r)
 //   (SAVE expr      then) : (IO r)
+//   (PEEK name      then) : (IO r)
 //   (CALL name argm then) : (IO r)
+//   (SCHE tick name argm then) : (IO r)
 //   (SUBJ           then) : (IO r)
 //   (FROM           then) : (IO r)
 //   (TICK           then) : (IO r)
 //   (TIME           then) : (IO r)
+//   (LOG  expr      then) : (IO r)
+//   (GRUN blck stmt then) : (IO r)
+//   (HASH a b       then) : (IO r)
+//   (SIGN hash sign then) : (IO r) -- hash and sign are spread in 3 and 5 fields
 const IO_DONE : u128 = 0x39960f; // name_to_u128("DONE")
 const IO_TAKE : u128 = 0x78b54f; // name_to_u128("TAKE")
 const IO_SAVE : u128 = 0x74b80f; // name_to_u128("SAVE")
+const IO_PEEK : u128 = 0x68f3d5; // name_to_u128("PEEK")
 const IO_CALL : u128 = 0x34b596; // name_to_u128("CALL")
+const IO_SCHE : u128 = 0x74d48f; // name_to_u128("SCHE")
 const IO_SUBJ : u128 = 0x75f314; // name_to_u128("SUBJ")
 const IO_FROM : u128 = 0x41c657; // name_to_u128("FROM")
 const IO_LOAD : u128 = 0x5992ce; // name_to_u128("LOAD")
@@ -740,8 +893,23 @@ const IO_HAX1 : u128 = 0x48b882; // name_to_u128("HAX1")
 const IO_GIDX : u128 = 0x4533a2; // name_to_u128("GIDX")
 const IO_STH0 : u128 = 0x75e481; // name_to_u128("STH0")
 const IO_STH1 : u128 = 0x75e482; // name_to_u128("STH1")
+const IO_LOG  : u128 = 0x16651;  // name_to_u128("LOG")
+const IO_GRUN : u128 = 0x45c7d8; // name_to_u128("GRUN")
+const IO_HASH : u128 = 0x48b752; // name_to_u128("HASH")
+const IO_SIGN : u128 = 0x753458; // name_to_u128("SIGN")
 // TODO: STH0 & STH1 -> get hash of statement (by (block_idx, stmt_idx))
-// TODO: GRUN -> get run result
+
+// String literals are lists of UTF-8 chunks, packed 15 bytes per number,
+// with the first byte on the most significant one.
+// (String) : Type
+//   (StrCons chunk rest) : String
+//   (StrNil)             : String
+const STR_CONS : u128 = 0x1de36373cb7; // name_to_u128("StrCons")
+const STR_NIL  : u128 = 0x778d98b70;   // name_to_u128("StrNil")
+const STR_CHUNK_BYTES : usize = 15;
+
+// Pairs, as returned by HASH
+const TUP2 : u128 = 0x783; // name_to_u128("T2")
 
 // Maximum mana that can be spent in a block
 pub const BLOCK_MANA_LIMIT : u64 = 4_000_000;
@@ -749,6 +917,30 @@ pub const BLOCK_MANA_LIMIT : u64 = 4_000_000;
 // Maximum state growth per block, in bits
 pub const BLOCK_BITS_LIMIT : u64 = 2048; // 1024 bits per sec = about 8 GB per year
 
+// Maximum number of nested contract calls in a single IO run
+pub const MAX_CALL_DEPTH : usize = 256;
+
+// Maximum nesting of constructors in the argument of a contract call
+pub const MAX_CALL_ARG_DEPTH : usize = 64;
+
+// Maximum number of constructors and numbers in the argument of a contract call
+pub const MAX_CALL_ARG_SIZE : u64 = 1024;
+
+// Maximum mana that scheduled calls can spend in a block, out of its mana limit
+pub const BLOCK_SCHED_MANA : u64 = BLOCK_MANA_LIMIT / 4;
+
+// Maximum number of calls scheduled to run at the same tick
+pub const MAX_TICK_SCHEDS : usize = 256;
+
+// Maximum number of terms logged by a single statement
+pub const MAX_LOGS : usize = 64;
+
+// Maximum total size of the terms logged by a single statement, in cells
+pub const MAX_LOGS_SIZE : u64 = 4096;
+
+// Maximum number of steps recorded in the trace of a single statement
+pub const MAX_TRACE_STEPS : usize = 1 << 16;
+
 // Mana Table
 // ----------
 
@@ -821,6 +1013,41 @@ fn DupEraMana() -> u64 {
   return 2;
 }
 
+// Hashing is charged once per HASH effect, regardless of its inputs
+fn IoHashMana() -> u64 {
+  return 64;
+}
+
+// Recovering a signer is much slower than hashing, so it costs more
+fn IoSignMana() -> u64 {
+  return 2048;
+}
+
+// Scheduled calls are stored outside of the HVM memory, so they are charged up front
+fn IoScheMana() -> u64 {
+  return 256;
+}
+
+// Space taken by a scheduled call until it runs: its tick, caller, callee, name and arguments
+fn sched_size(call: &Sched) -> u64 {
+  return 4 + call.args.len() as u64;
+}
+
+// Call arguments are copied for the callee, so they are charged per cell
+fn IoCallMana(size: u64) -> u64 {
+  return 2 * size;
+}
+
+// Logged terms are read back and kept with the statement's result, so they are charged per cell
+fn IoLogMana(size: u64) -> u64 {
+  return 2 * size;
+}
+
+// Size of a logged term, in cells, counting its root
+fn log_size(term: &Term) -> u64 {
+  return 1 + count_allocs(term);
+}
+
 fn count_allocs(body: &Term) -> u64 {
   match body {
     Term::Var { name } => {
@@ -976,7 +1203,54 @@ impl ParseErr {
     C: Into<String>,
     E: Into<String>
   {
-    ParseErr { code: code.into(), erro: erro.into() }
+    ParseErr { code: code.into(), erro: erro.into(), offset: 0 }
+  }
+
+  /// Places an error raised while reading `code`, a piece of the source
+  /// starting at byte `start`. The error lies within that piece, where its
+  /// own `code`, the input left unread, begins.
+  pub fn place(mut self, code: &str, start: usize) -> Self {
+    self.offset = start + code.len().saturating_sub(self.code.len());
+    self
+  }
+
+  /// Finds where this error happened in `source`, the code it was placed in.
+  pub fn locate(&self, source: &str) -> SrcPos {
+    let mut offset = std::cmp::min(self.offset, source.len());
+    while !source.is_char_boundary(offset) {
+      offset -= 1;
+    }
+    SrcPos::at(source, offset)
+  }
+
+  /// Renders the error with the line of `source` where it happened and a
+  /// caret pointing at its column.
+  pub fn show(&self, source: &str) -> String {
+    let pos = self.locate(source);
+    let line = source.lines().nth(pos.line - 1).unwrap_or("");
+    let gutter = " ".repeat(pos.line.to_string().len());
+    // Tabs are kept, so that the caret lines up however they are shown
+    let caret = line.chars().take(pos.column - 1).map(|chr| if chr == '\t' { '\t' } else { ' ' }).collect::<String>();
+    format!(
+      "error: {}\n{} --> {}\n{} |\n{} | {}\n{} | {}^",
+      self.erro, gutter, pos, gutter, pos.line, line, gutter, caret
+    )
+  }
+}
+
+impl SrcPos {
+  pub fn at(source: &str, offset: usize) -> Self {
+    let before = &source[..offset];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let column = before[line_start..].chars().count() + 1;
+    SrcPos { offset, line, column }
+  }
+}
+
+impl fmt::Display for SrcPos {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}:{}", self.line, self.column)
   }
 }
 
@@ -1003,66 +1277,107 @@ pub fn get_namespace(name: Name) -> Option<Name> {
 // Removes the signature from a statement
 pub fn remove_sign(statement: &Statement) -> Statement {
   match statement {
-    Statement::Fun { name, args, func, init, sign } => {
+    Statement::Fun { name, args, func, init, until, sign } => {
       Statement::Fun {
         name: *name,
         args: args.clone(),
         func: func.clone(),
         init: init.clone(),
+        until: *until,
         sign: None,
       }
     }
-    Statement::Ctr { name, args, sign } => {
+    Statement::Ctr { name, args, until, sign } => {
       Statement::Ctr {
         name: *name,
         args: args.clone(),
+        until: *until,
         sign: None,
       }
     }
-    Statement::Run { expr, sign } => {
+    Statement::Run { expr, mana, nonce, until, sign } => {
       Statement::Run {
         expr: expr.clone(),
+        mana: *mana,
+        nonce: *nonce,
+        until: *until,
         sign: None,
       }
     }
-    Statement::Reg { name, ownr, sign } => {
+    Statement::Reg { name, ownr, until, sign } => {
       Statement::Reg {
         name: *name,
         ownr: *ownr,
+        until: *until,
+        sign: None,
+      }
+    }
+    Statement::Own { name, ownr, nonce, until, sign } => {
+      Statement::Own {
+        name: *name,
+        ownr: *ownr,
+        nonce: *nonce,
+        until: *until,
         sign: None,
       }
     }
   }
 }
 
+// Last tick on which a statement can run, if it expires
+pub fn get_until(statement: &Statement) -> Option<u64> {
+  match statement {
+    Statement::Fun { until, .. }
+    | Statement::Ctr { until, .. }
+    | Statement::Run { until, .. }
+    | Statement::Reg { until, .. }
+    | Statement::Own { until, .. } => *until,
+  }
+}
+
 pub fn set_sign(statement: &Statement, new_sign: crypto::Signature) -> Statement {
   match statement {
-    Statement::Fun { name, args, func, init, sign } => {
+    Statement::Fun { name, args, func, init, until, sign } => {
       Statement::Fun {
         name: *name,
         args: args.clone(),
         func: func.clone(),
         init: init.clone(),
+        until: *until,
         sign: Some(new_sign),
       }
     }
-    Statement::Ctr { name, args, sign } => {
+    Statement::Ctr { name, args, until, sign } => {
       Statement::Ctr {
         name: *name,
         args: args.clone(),
+        until: *until,
         sign: Some(new_sign),
       }
     }
-    Statement::Run { expr, sign } => {
+    Statement::Run { expr, mana, nonce, until, sign } => {
       Statement::Run {
         expr: expr.clone(),
+        mana: *mana,
+        nonce: *nonce,
+        until: *until,
         sign: Some(new_sign),
       }
     }
-    Statement::Reg { name, ownr, sign } => {
+    Statement::Reg { name, ownr, until, sign } => {
       Statement::Reg {
         name: *name,
         ownr: *ownr,
+        until: *until,
+        sign: Some(new_sign),
+      }
+    }
+    Statement::Own { name, ownr, nonce, until, sign } => {
+      Statement::Own {
+        name: *name,
+        ownr: *ownr,
+        nonce: *nonce,
+        until: *until,
         sign: Some(new_sign),
       }
     }
@@ -1078,8 +1393,70 @@ impl fmt::Display for StatementInfo {
       StatementInfo::Ctr { name, args } => write!(f, "[ctr] {}", name),
       StatementInfo::Fun { name, args } => write!(f, "[fun] {}", name),
       StatementInfo::Reg { name, .. } => write!(f, "[reg] {}", name),
+      StatementInfo::Own { name, ownr, .. } => write!(f, "[own] {} #x{:0>30x}", name, **ownr),
       StatementInfo::Run { done_term, used_mana, size_diff, .. } =>
-        write!(f, "[run] {} \x1b[2m[{} mana | {} size]\x1b[0m", view_term(&done_term), used_mana, size_diff)
+        write!(f, "[run] {} \x1b[2m[{} mana | {} size]\x1b[0m", view_term(&done_term), used_mana, size_diff),
+      StatementInfo::Sched { name, done_term, used_mana, size_diff, .. } =>
+        write!(f, "[sched] {} {} \x1b[2m[{} mana | {} size]\x1b[0m", name, view_term(&done_term), used_mana, size_diff),
+    }
+  }
+}
+
+// Profiler
+// ========
+
+impl Profiler {
+  fn new(dups: u64) -> Self {
+    Profiler { rows: HashMap::new(), last: None, dups, cells: 0 }
+  }
+
+  fn flush(&mut self, dups: u64) {
+    if let Some(key) = self.last {
+      if let Some(row) = self.rows.get_mut(&key) {
+        row.dups += dups - self.dups;
+        row.cells += self.cells;
+      }
+    }
+    self.dups = dups;
+    self.cells = 0;
+  }
+
+  fn rewrite(&mut self, key: ProfileKey, mana: u64, dups: u64) {
+    self.flush(dups);
+    let (rule, name, index) = key;
+    let row = self.rows.entry(key).or_insert(ProfileRow { rule, name, index, mana: 0, rwts: 0, dups: 0, cells: 0 });
+    row.mana += mana;
+    row.rwts += 1;
+    self.last = Some(key);
+  }
+
+  /// Returns the rows collected so far, most expensive first, and resets.
+  fn finish(&mut self, dups: u64) -> Vec<ProfileRow> {
+    self.flush(dups);
+    self.last = None;
+    let mut rows: Vec<ProfileRow> = self.rows.drain().map(|(_, row)| row).collect();
+    rows.sort_by(|a, b| b.mana.cmp(&a.mana).then(b.rwts.cmp(&a.rwts)));
+    rows
+  }
+}
+
+/// Renders a profile as a table, one line per row.
+pub fn view_profile(rows: &[ProfileRow]) -> Vec<String> {
+  let mut lines = vec![format!("{:<6} {:<12} {:>4} {:>10} {:>8} {:>8} {:>8}", "rule", "name", "idx", "mana", "rwts", "dups", "cells")];
+  for row in rows {
+    let name = row.name.map_or("-".to_string(), |name| name.to_string());
+    let index = row.index.map_or("-".to_string(), |index| index.to_string());
+    let rule = format!("{:?}", row.rule);
+    lines.push(format!("{:<6} {:<12} {:>4} {:>10} {:>8} {:>8} {:>8}", rule, name, index, row.mana, row.rwts, row.dups, row.cells));
+  }
+  lines
+}
+
+impl fmt::Display for TraceStep {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self.name {
+      Some(name) => write!(f, "{:?} {} \x1b[2m[{} mana]\x1b[0m", self.rule, name, self.mana),
+      None => write!(f, "{:?} \x1b[2m[{} mana]\x1b[0m", self.rule, self.mana),
     }
   }
 }
@@ -1095,6 +1472,36 @@ fn absorb_u64(a: u64, b: u64, overwrite: bool) -> u64 {
   if b == U64_NONE { a } else if overwrite || a == U64_NONE { b } else { a }
 }
 
+/// Splits a hash into two U120s, from its bytes 0 to 16 and 15 to 31, little
+/// endian. The bits past 120 of each part and the last byte are thrown away.
+pub fn hash_to_u120s(hash: &crypto::Hash) -> (u128, u128) {
+  let mut bytes: [u8; 16] = [0; 16];
+  bytes.copy_from_slice(&hash.0[0..16]);
+  let part0 = u128::from_le_bytes(bytes) & *U120::MAX;
+  bytes.copy_from_slice(&hash.0[15..31]);
+  let part1 = u128::from_le_bytes(bytes) & *U120::MAX;
+  (part0, part1)
+}
+
+/// Packs numbers into `len` bytes, 15 big-endian bytes per number, with the last
+/// number holding the remaining bytes. Returns `None` if a number doesn't fit.
+pub fn u120s_to_bytes(nums: &[U120], len: usize) -> Option<Vec<u8>> {
+  let mut bytes = Vec::with_capacity(len);
+  for num in nums {
+    let size = std::cmp::min(15, len - bytes.len());
+    if **num >> (size * 8) != 0 {
+      return None;
+    }
+    bytes.extend_from_slice(&(**num).to_be_bytes()[16 - size ..]);
+  }
+  Some(bytes)
+}
+
+/// Position of a statement in the chain, as used by the `indx`, `hash` and `runs` maps.
+pub fn stmt_position(block_idx: u128, stmt_idx: u128) -> u128 {
+  block_idx.wrapping_shl(60) | stmt_idx //TODO: refactor to use less bits
+}
+
 impl Heap {
   fn write(&mut self, idx: Loc, val: RawCell) {
     return self.memo.write(idx, val);
@@ -1132,12 +1539,48 @@ impl Heap {
   fn read_indx(&self, name: &Name) -> Option<u128> {
     return self.indx.read(name);
   }
+  fn write_xfer(&mut self, name: Name, pos: u128) {
+    return self.xfer.write(name, pos);
+  }
+  fn read_xfer(&self, name: &Name) -> Option<u128> {
+    return self.xfer.read(name);
+  }
   fn write_stmt_hash(&mut self, pos: u128, hash: crypto::Hash) {
     return self.hash.write(pos, hash);
   }
   fn read_stmt_hash(&self, pos: &u128) -> Option<&crypto::Hash> {
     return self.hash.read(pos);
   }
+  fn write_run(&mut self, pos: u128, result: U120) {
+    return self.runs.write(pos, result);
+  }
+  fn read_run(&self, pos: &u128) -> Option<U120> {
+    return self.runs.read(pos);
+  }
+  fn write_nonce(&mut self, subj: U120, nonce: U120) {
+    return self.nonc.write(subj, nonce);
+  }
+  fn read_nonce(&self, subj: &U120) -> Option<U120> {
+    return self.nonc.read(subj);
+  }
+  fn write_sched(&mut self, tick: u64, calls: Vec<Sched>) {
+    return self.sche.write(tick, calls);
+  }
+  fn read_sched(&self, tick: u64) -> Option<Vec<Sched>> {
+    return self.sche.read(tick);
+  }
+  fn read_sched_count(&self, tick: u64) -> Option<usize> {
+    return self.sche.count(tick);
+  }
+  fn push_sched(&mut self, call: Sched) {
+    return self.sche.push(call);
+  }
+  fn write_leaf(&mut self, name: Name, leaf: crypto::Hash) {
+    return self.leaf.write(name, leaf);
+  }
+  fn read_leaf(&self, name: &Name) -> Option<crypto::Hash> {
+    return self.leaf.read(name);
+  }
   fn set_tick(&mut self, tick: u64) {
     self.tick = tick;
   }
@@ -1216,6 +1659,14 @@ impl Heap {
     self.disk.absorb(&mut other.disk, overwrite);
     self.file.absorb(&mut other.file, overwrite);
     self.arit.absorb(&mut other.arit, overwrite);
+    self.indx.absorb(&mut other.indx, overwrite);
+    self.hash.absorb(&mut other.hash, overwrite);
+    self.ownr.absorb(&mut other.ownr, overwrite);
+    self.xfer.absorb(&mut other.xfer, overwrite);
+    self.runs.absorb(&mut other.runs, overwrite);
+    self.nonc.absorb(&mut other.nonc, overwrite);
+    self.sche.absorb(&mut other.sche, overwrite);
+    self.leaf.absorb(&mut other.leaf, overwrite);
     self.tick = absorb_u64(self.tick, other.tick, overwrite);
     self.time = absorb_u128(self.time, other.time, overwrite);
     self.meta = absorb_u128(self.meta, other.meta, overwrite);
@@ -1229,12 +1680,24 @@ impl Heap {
     self.mcap = absorb_u64(self.mcap, other.mcap, overwrite);
     self.next = absorb_u64(self.next, other.next, overwrite);
   }
+  // Copies the contents of a newer heap into this one
+  fn merge(&mut self, other: &Heap) {
+    self.absorb(&mut other.clone(), true);
+  }
   fn clear(&mut self) {
     self.uuid = fastrand::u128(..);
     self.memo.clear();
     self.disk.clear();
     self.file.clear();
     self.arit.clear();
+    self.indx.clear();
+    self.hash.clear();
+    self.ownr.clear();
+    self.xfer.clear();
+    self.runs.clear();
+    self.nonc.clear();
+    self.sche.clear();
+    self.leaf.clear();
     self.tick = U64_NONE;
     self.time = U128_NONE;
     self.meta = U128_NONE;
@@ -1248,90 +1711,105 @@ impl Heap {
     self.mcap = U64_NONE;
     self.next = U64_NONE;
   }
-  pub fn serialize(self: &Heap, path: &PathBuf, append: bool) -> std::io::Result<()> {
-    fn open_writer(heap: &Heap, path: &PathBuf, buffer_name: &str, append: bool) -> std::io::Result<File> {
-      let file_path = Heap::buffer_file_path(heap.uuid, buffer_name, path);
-      std::fs::OpenOptions::new()
-        .write(true)
-        .append(append)
-        .create(true)
-        .open(file_path)
-    }
-    self.memo.nodes.disk_serialize(&mut open_writer(self, path, "memo", append)?)?;
-    self.disk.links.disk_serialize(&mut open_writer(self, path, "disk", append)?)?;
-    self.file.funcs.disk_serialize(&mut open_writer(self, path, "file", append)?)?;
-    self.arit.arits.disk_serialize(&mut open_writer(self, path, "arit", append)?)?;
-    self.indx.indxs.disk_serialize(&mut open_writer(self, path, "indx", append)?)?;
-    self.hash.stmt_hashes.disk_serialize(&mut open_writer(self, path, "stmt_hashes", append)?)?;
-    self.ownr.ownrs.disk_serialize(&mut open_writer(self, path, "ownr", append)?)?;
-    let mut stat = open_writer(self, path, "stat", false)?;
-    self.tick.disk_serialize(&mut stat)?;
-    self.time.disk_serialize(&mut stat)?;
-    self.meta.disk_serialize(&mut stat)?;
-    self.hax0.disk_serialize(&mut stat)?;
-    self.hax1.disk_serialize(&mut stat)?;
-    self.funs.disk_serialize(&mut stat)?;
-    self.dups.disk_serialize(&mut stat)?;
-    self.rwts.disk_serialize(&mut stat)?;
-    self.mana.disk_serialize(&mut stat)?;
-    self.size.disk_serialize(&mut stat)?;
-    self.mcap.disk_serialize(&mut stat)?;
-    self.next.disk_serialize(&mut stat)?;
-    Ok(())
+  // Saves this heap to a single file, with each of its buffers prefixed by its length. The file
+  // is written atomically, so a heap whose file exists was fully saved.
+  pub fn serialize(self: &Heap, path: &PathBuf) -> std::io::Result<()> {
+    persistence::write_atomic(&Heap::file_path(self.uuid, path), &self.to_bytes()?)
   }
   pub fn deserialize(uuid: u128, path: &PathBuf) -> std::io::Result<Heap> {
-    fn open_reader(uuid: u128, path: &PathBuf, buffer_name: &str) -> std::io::Result<File> {
-      let file_path = Heap::buffer_file_path(uuid, buffer_name, path);
-      std::fs::OpenOptions::new()
-        .read(true)
-        .open(file_path)
-    }
-    fn read_hash_map_from_file<K: DiskSer + Eq + std::hash::Hash + crate::NoHashHasher::IsEnabled, V: DiskSer>
-      (uuid: u128, path: &PathBuf, buffer_name: &str) -> std::io::Result<HashMap<K, V, std::hash::BuildHasherDefault<NoHashHasher<K>>>> {
-      HashMap::disk_deserialize(&mut open_reader(uuid, path, buffer_name)?)?
-        .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::UnexpectedEof))
-    }
-    fn read_num<T: DiskSer>(file: &mut File) -> std::io::Result<T>{
-      T::disk_deserialize(file)?.ok_or_else(|| std::io::Error::from(std::io::ErrorKind::UnexpectedEof))
-    }
-    let memo = Nodes { nodes: read_hash_map_from_file(uuid, path, "memo")? };
-    let disk = Store { links: read_hash_map_from_file(uuid, path, "disk")? };
-    let file = Funcs { funcs: read_hash_map_from_file(uuid, path, "file")? };
-    let arit = Arits { arits: read_hash_map_from_file(uuid, path, "arit")? };
-    let indx = Indxs { indxs: read_hash_map_from_file(uuid, path, "indx")? };
-    let hash = Hashs { stmt_hashes: read_hash_map_from_file(uuid, path, "stmt_hashes")? };    
-    let ownr = Ownrs { ownrs: read_hash_map_from_file(uuid, path, "ownr")? };
-    let mut stat = open_reader(uuid, path, "stat")?;
-    let tick = read_num(&mut stat)?;
-    let time = read_num(&mut stat)?;
-    let meta = read_num(&mut stat)?;
-    let hax0 = read_num(&mut stat)?;
-    let hax1 = read_num(&mut stat)?;
-    let funs = read_num(&mut stat)?;
-    let dups = read_num(&mut stat)?;
-    let rwts = read_num(&mut stat)?;
-    let mana = read_num(&mut stat)?;
-    let size = read_num(&mut stat)?;
-    let mcap = read_num(&mut stat)?;
-    let next = read_num(&mut stat)?;
-    Ok( Heap { uuid, memo, disk, file, arit, indx, hash, ownr, tick, time, meta, hax0, hax1, funs, dups, rwts,  mana, size, mcap, next })
-  }
-
-  fn buffer_file_path(uuid: u128, buffer_name: &str, path: &PathBuf) -> PathBuf {
-    path.join(format!("{:0>32x}.{}.bin", uuid, buffer_name))
-  }
-  fn delete_buffer(&self, uuid: u128, buffer_name: &str, path: &PathBuf) -> std::io::Result<()> {
-    std::fs::remove_file(Heap::buffer_file_path(uuid, buffer_name, path))
-  }
-  fn delete_buffers(&mut self, path: &PathBuf) -> std::io::Result<()> {
-    self.delete_buffer(self.uuid, "memo", path)?;
-    self.delete_buffer(self.uuid, "disk", path)?;
-    self.delete_buffer(self.uuid, "file", path)?;
-    self.delete_buffer(self.uuid, "arit", path)?;
-    self.delete_buffer(self.uuid, "indx", path)?;
-    self.delete_buffer(self.uuid, "ownr", path)?;
-    self.delete_buffer(self.uuid, "stat", path)?;
-    return Ok(());
+    Heap::from_bytes(uuid, &std::fs::read(Heap::file_path(uuid, path))?)
+  }
+  pub fn to_bytes(&self) -> std::io::Result<Vec<u8>> {
+    fn write_buffer(file: &mut Vec<u8>, write: impl FnOnce(&mut Vec<u8>) -> std::io::Result<usize>) -> std::io::Result<()> {
+      let mut buffer = vec![];
+      write(&mut buffer)?;
+      (buffer.len() as u64).disk_serialize(file)?;
+      file.extend(buffer);
+      Ok(())
+    }
+    let mut file = vec![];
+    write_buffer(&mut file, |buf| self.memo.nodes.disk_serialize(buf))?;
+    write_buffer(&mut file, |buf| self.disk.links.disk_serialize(buf))?;
+    write_buffer(&mut file, |buf| self.file.funcs.disk_serialize(buf))?;
+    write_buffer(&mut file, |buf| self.arit.arits.disk_serialize(buf))?;
+    write_buffer(&mut file, |buf| self.indx.indxs.disk_serialize(buf))?;
+    write_buffer(&mut file, |buf| self.hash.stmt_hashes.disk_serialize(buf))?;
+    write_buffer(&mut file, |buf| self.ownr.ownrs.disk_serialize(buf))?;
+    write_buffer(&mut file, |buf| self.xfer.indxs.disk_serialize(buf))?;
+    write_buffer(&mut file, |buf| self.runs.runs.disk_serialize(buf))?;
+    write_buffer(&mut file, |buf| self.nonc.nonces.disk_serialize(buf))?;
+    write_buffer(&mut file, |buf| self.sche.scheds.values().flatten().cloned().collect::<Vec<_>>().disk_serialize(buf))?;
+    write_buffer(&mut file, |buf| self.leaf.leafs.disk_serialize(buf))?;
+    write_buffer(&mut file, |stat| {
+      let mut written = 0;
+      written += self.tick.disk_serialize(stat)?;
+      written += self.time.disk_serialize(stat)?;
+      written += self.meta.disk_serialize(stat)?;
+      written += self.hax0.disk_serialize(stat)?;
+      written += self.hax1.disk_serialize(stat)?;
+      written += self.funs.disk_serialize(stat)?;
+      written += self.dups.disk_serialize(stat)?;
+      written += self.rwts.disk_serialize(stat)?;
+      written += self.mana.disk_serialize(stat)?;
+      written += self.size.disk_serialize(stat)?;
+      written += self.mcap.disk_serialize(stat)?;
+      written += self.next.disk_serialize(stat)?;
+      Ok(written)
+    })?;
+    Ok(file)
+  }
+  pub fn from_bytes(uuid: u128, bytes: &[u8]) -> std::io::Result<Heap> {
+    fn read_num<T: DiskSer>(source: &mut impl std::io::Read) -> std::io::Result<T>{
+      T::disk_deserialize(source)?.ok_or_else(|| std::io::Error::from(std::io::ErrorKind::UnexpectedEof))
+    }
+    fn read_buffer<'a>(file: &mut &'a [u8]) -> std::io::Result<&'a [u8]> {
+      let len = read_num::<u64>(file)? as usize;
+      if len > file.len() {
+        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof));
+      }
+      let (buffer, rest) = file.split_at(len);
+      *file = rest;
+      Ok(buffer)
+    }
+    fn read_hash_map<K: DiskSer + Eq + std::hash::Hash + crate::NoHashHasher::IsEnabled, V: DiskSer>
+      (file: &mut &[u8]) -> std::io::Result<HashMap<K, V, std::hash::BuildHasherDefault<NoHashHasher<K>>>> {
+      read_num(&mut read_buffer(file)?)
+    }
+    let source = &mut &bytes[..];
+    let memo = Nodes { nodes: read_hash_map(source)? };
+    let disk = Store { links: read_hash_map(source)? };
+    let file = Funcs { funcs: read_hash_map(source)? };
+    let arit = Arits { arits: read_hash_map(source)? };
+    let indx = Indxs { indxs: read_hash_map(source)? };
+    let hash = Hashs { stmt_hashes: read_hash_map(source)? };
+    let ownr = Ownrs { ownrs: read_hash_map(source)? };
+    let xfer = Indxs { indxs: read_hash_map(source)? };
+    let runs = Runs { runs: read_hash_map(source)? };
+    let nonc = Nonces { nonces: read_hash_map(source)? };
+    let mut sche = Scheds { scheds: init_u128_map() };
+    let calls: Vec<Sched> = read_num(&mut read_buffer(source)?)?;
+    for call in calls {
+      sche.scheds.entry(call.tick as u128).or_insert_with(Vec::new).push(call);
+    }
+    let leaf = Leafs { leafs: read_hash_map(source)? };
+    let stat = &mut read_buffer(source)?;
+    let tick = read_num(stat)?;
+    let time = read_num(stat)?;
+    let meta = read_num(stat)?;
+    let hax0 = read_num(stat)?;
+    let hax1 = read_num(stat)?;
+    let funs = read_num(stat)?;
+    let dups = read_num(stat)?;
+    let rwts = read_num(stat)?;
+    let mana = read_num(stat)?;
+    let size = read_num(stat)?;
+    let mcap = read_num(stat)?;
+    let next = read_num(stat)?;
+    Ok( Heap { uuid, memo, disk, file, arit, indx, hash, ownr, xfer, runs, nonc, sche, leaf, tick, time, meta, hax0, hax1, funs, dups, rwts,  mana, size, mcap, next })
+  }
+
+  fn file_path(uuid: u128, path: &PathBuf) -> PathBuf {
+    path.join(format!("{:0>32x}.heap.bin", uuid))
   }
   pub fn get_fn_count(&self) -> u64 {
     return self.file.funcs.len() as u64
@@ -1352,8 +1830,13 @@ pub fn init_heap() -> Heap {
     file: Funcs { funcs: init_name_map() },
     arit: Arits { arits: init_name_map() },
     ownr: Ownrs { ownrs: init_name_map() },
+    xfer: Indxs { indxs: init_name_map() },
     indx: Indxs { indxs: init_name_map() },
     hash: Hashs { stmt_hashes: init_u128_map() },
+    runs: Runs { runs: init_u128_map() },
+    nonc: Nonces { nonces: init_u120_map() },
+    sche: Scheds { scheds: init_u128_map() },
+    leaf: Leafs { leafs: init_name_map() },
     tick: U64_NONE,
     time: U128_NONE,
     meta: U128_NONE,
@@ -1448,7 +1931,7 @@ impl Arits {
 
 impl Ownrs {
   fn write(&mut self, name: Name, val: U120) {
-    self.ownrs.entry(name).or_insert(val);
+    self.ownrs.insert(name, val);
   }
   fn read(&self, name: &Name) -> Option<U120> {
     return self.ownrs.get(name).map(|x| *x);
@@ -1503,22 +1986,130 @@ impl Hashs {
   }
 }
 
+impl Runs {
+  fn write(&mut self, pos: u128, result: U120) {
+    self.runs.insert(pos, result);
+  }
+  fn read(&self, pos: &u128) -> Option<U120> {
+    return self.runs.get(pos).map(|x| *x);
+  }
+  fn clear(&mut self) {
+    self.runs.clear();
+  }
+  fn absorb(&mut self, other: &mut Self, overwrite: bool) {
+    for (pos, result) in other.runs.drain() {
+      if overwrite || !self.runs.contains_key(&pos) {
+        self.runs.insert(pos, result);
+      }
+    }
+  }
+}
+
+impl Nonces {
+  fn write(&mut self, subj: U120, nonce: U120) {
+    self.nonces.insert(subj, nonce);
+  }
+  fn read(&self, subj: &U120) -> Option<U120> {
+    return self.nonces.get(subj).map(|x| *x);
+  }
+  fn clear(&mut self) {
+    self.nonces.clear();
+  }
+  fn absorb(&mut self, other: &mut Self, overwrite: bool) {
+    for (subj, nonce) in other.nonces.drain() {
+      if overwrite || !self.nonces.contains_key(&subj) {
+        self.nonces.insert(subj, nonce);
+      }
+    }
+  }
+}
+
+impl Leafs {
+  fn write(&mut self, name: Name, leaf: crypto::Hash) {
+    self.leafs.insert(name, leaf);
+  }
+  fn read(&self, name: &Name) -> Option<crypto::Hash> {
+    return self.leafs.get(name).cloned();
+  }
+  fn clear(&mut self) {
+    self.leafs.clear();
+  }
+  fn absorb(&mut self, other: &mut Self, overwrite: bool) {
+    for (name, leaf) in other.leafs.drain() {
+      if overwrite || !self.leafs.contains_key(&name) {
+        self.leafs.insert(name, leaf);
+      }
+    }
+  }
+}
+
+impl Scheds {
+  fn write(&mut self, tick: u64, calls: Vec<Sched>) {
+    self.scheds.insert(tick as u128, calls);
+  }
+  fn read(&self, tick: u64) -> Option<Vec<Sched>> {
+    return self.scheds.get(&(tick as u128)).cloned();
+  }
+  fn count(&self, tick: u64) -> Option<usize> {
+    return self.scheds.get(&(tick as u128)).map(|calls| calls.len());
+  }
+  fn push(&mut self, call: Sched) {
+    self.scheds.entry(call.tick as u128).or_default().push(call);
+  }
+  // Drops the empty entries left by ticks whose calls ran. Only the oldest heap can do that, as
+  // on newer ones they hide the calls stored on the heaps below.
+  fn prune(&mut self) {
+    self.scheds.retain(|_, calls| !calls.is_empty());
+  }
+  fn clear(&mut self) {
+    self.scheds.clear();
+  }
+  fn absorb(&mut self, other: &mut Self, overwrite: bool) {
+    for (tick, calls) in other.scheds.drain() {
+      if overwrite || !self.scheds.contains_key(&tick) {
+        self.scheds.insert(tick, calls);
+      }
+    }
+  }
+}
 
-pub fn init_runtime(heaps_path: PathBuf, init_stmts: &[Statement]) -> Runtime {
-  // Default runtime store path
-  std::fs::create_dir_all(&heaps_path).unwrap(); // TODO: handle unwrap
+// Allocates the draw and current heaps, plus one heap per snapshot of the rollback window
+fn new_runtime(heaps_path: Option<PathBuf>, rollback: &RollbackConfig) -> Runtime {
+  if let Some(heaps_path) = &heaps_path {
+    std::fs::create_dir_all(heaps_path).unwrap(); // TODO: handle unwrap
+  }
   let mut heap = Vec::new();
-  for i in 0 .. MAX_HEAPS {
+  for i in 0 .. rollback.heaps + 2 {
     heap.push(init_heap());
   }
-  let mut rt = Runtime {
+  Runtime {
     heap,
     draw: 0,
     curr: 1,
-    nuls: (2 .. MAX_HEAPS).collect(),
-    back: Arc::new(Rollback::Nil),
+    nuls: (2 .. rollback.heaps + 2).collect(),
+    back: Rollback::new(rollback.heaps, rollback.spacing),
     path: heaps_path,
-  };
+    tip: U256::zero(),
+    logs: Vec::new(),
+    dirt: HashSet::new(),
+    root: StateTree::new(vec![]),
+    trace: None,
+    profile: None,
+  }
+}
+
+pub fn init_runtime(heaps_path: PathBuf, init_stmts: &[Statement], rollback: &RollbackConfig) -> Runtime {
+  let mut rt = new_runtime(Some(heaps_path), rollback);
+
+  rt.run_statements(init_stmts, true, false);
+  rt.commit();
+
+  rt
+}
+
+// Like `init_runtime`, but its state is never saved, for dry runs
+pub fn init_volatile_runtime(init_stmts: &[Statement], rollback: &RollbackConfig) -> Runtime {
+  let mut rt = new_runtime(None, rollback);
 
   rt.run_statements(init_stmts, true, false);
   rt.commit();
@@ -1526,6 +2117,27 @@ pub fn init_runtime(heaps_path: PathBuf, init_stmts: &[Statement]) -> Runtime {
   rt
 }
 
+// Loads the runtime state persisted at `heaps_path`
+pub fn restore_runtime(heaps_path: PathBuf, rollback: &RollbackConfig) -> std::io::Result<Runtime> {
+  let mut rt = new_runtime(Some(heaps_path), rollback);
+  rt.restore_state()?;
+  Ok(rt)
+}
+
+// Persists a runtime at `heaps_path` whose whole state is `heap`, the state at block `tip`, as
+// exported by `Runtime::export_state`. It replaces the state persisted there.
+pub fn install_runtime(heaps_path: PathBuf, mut heap: Heap, tip: U256, rollback: &RollbackConfig) -> std::io::Result<Runtime> {
+  let mut rt = new_runtime(Some(heaps_path), rollback);
+  heap.uuid = fastrand::u128(..);
+  rt.heap[rt.curr as usize] = heap;
+  rt.back.push(rt.curr);
+  rt.curr = rt.nuls.pop().expect("No heap available!");
+  rt.tip = tip;
+  rt.build_state_tree();
+  rt.persist_state()?;
+  Ok(rt)
+}
+
 impl Runtime {
 
   // API
@@ -1536,12 +2148,14 @@ impl Runtime {
   }
 
   pub fn define_function(&mut self, name: Name, func: CompFunc, stmt_index: Option<usize>, stmt_hash: crypto::Hash) {
+    self.dirt.insert(name);
     self.get_heap_mut(self.draw).write_arit(name, func.arity);
     self.get_heap_mut(self.draw).write_file(name, Arc::new(func));
     self.save_stmt_name(name, stmt_index, stmt_hash);
   }
 
   pub fn define_constructor(&mut self, name: Name, arity: u64, stmt_index: Option<usize>, stmt_hash: crypto::Hash) {
+    self.dirt.insert(name);
     self.get_heap_mut(self.draw).write_arit(name, arity);
     self.save_stmt_name(name, stmt_index, stmt_hash);
   }
@@ -1553,13 +2167,56 @@ impl Runtime {
 
   pub fn save_stmt_name(&mut self, name: Name, stmt_index: Option<usize>, stmt_hash: crypto::Hash) {
     if let Some(idx) = stmt_index {
-      let tick = self.get_tick() as u128;
-      let pos = tick.wrapping_shl(60) | (idx as u128); //TODO: refactor to use less bits
+      let pos = stmt_position(self.get_tick() as u128, idx as u128);
       self.get_heap_mut(self.draw).write_indx(name, pos);
       self.get_heap_mut(self.draw).write_stmt_hash(pos, stmt_hash);
     }
   }
 
+  pub fn save_transfer(&mut self, name: Name, stmt_index: Option<usize>, stmt_hash: crypto::Hash) {
+    if let Some(idx) = stmt_index {
+      let pos = stmt_position(self.get_tick() as u128, idx as u128);
+      self.get_heap_mut(self.draw).write_xfer(name, pos);
+      self.get_heap_mut(self.draw).write_stmt_hash(pos, stmt_hash);
+    }
+  }
+
+  pub fn save_run_result(&mut self, stmt_index: Option<usize>, result: U120) {
+    if let Some(idx) = stmt_index {
+      let pos = stmt_position(self.get_tick() as u128, idx as u128);
+      self.get_heap_mut(self.draw).write_run(pos, result);
+    }
+  }
+
+  /// Nonce that the next signed `run` or `own` of `subj` must have.
+  pub fn get_nonce(&self, subj: &U120) -> U120 {
+    return self.get_with(None, None, |heap| heap.read_nonce(subj)).unwrap_or(U120::ZERO);
+  }
+
+  pub fn set_nonce(&mut self, subj: U120, nonce: U120) {
+    self.get_heap_mut(self.draw).write_nonce(subj, nonce);
+  }
+
+  pub fn schedule(&mut self, call: Sched) {
+    let tick = call.tick;
+    // The calls scheduled by past statements are copied to the draw heap once, then appended to
+    if self.get_heap(self.draw).read_sched_count(tick).is_none() {
+      let calls = self.get_scheduled(tick);
+      self.get_heap_mut(self.draw).write_sched(tick, calls);
+    }
+    self.get_heap_mut(self.draw).push_sched(call);
+  }
+
+  /// Number of calls scheduled to run at a tick.
+  pub fn count_scheduled(&self, tick: u64) -> usize {
+    return self.get_with(None, None, |heap| heap.read_sched_count(tick)).unwrap_or(0);
+  }
+
+  /// Calls scheduled to run at a tick, in the order they were scheduled.
+  pub fn get_scheduled(&self, tick: u64) -> Vec<Sched> {
+    return self.get_with(None, None, |heap| heap.read_sched(tick)).unwrap_or_default();
+  }
+
   pub fn create_term(&mut self, term: &Term, loc: Loc, vars_data: &mut NameMap<Vec<RawCell>>) -> Result<RawCell, RuntimeError> {
     return create_term(self, term, loc, vars_data);
   }
@@ -1593,21 +2250,96 @@ impl Runtime {
     statements.iter().enumerate().map(
       |(i, s)| {
         let res = self.run_statement(s, silent, debug, Some(i));
-        if let Ok(..) = res {
-          self.draw();
+        if let Err(..) = res {
+          self.burn_nonce(s);
         }
+        self.draw();
         res
       }
     ).collect()
   }
 
-  pub fn run_statements_from_code(&mut self, code: &str, silent: bool, debug: bool) -> Vec<StatementResult> {
-    let stataments = read_statements(code);
-    match stataments {
-      Ok((.., statements)) => self.run_statements(&statements, silent, debug),
-      Err(ParseErr { erro , .. }) => {
-        return vec![Err(StatementErr { err: erro })];
+  // A failed signed run is undone, but still uses up its nonce, so that it
+  // can't be replayed once its failure cause is gone.
+  fn burn_nonce(&mut self, statement: &Statement) {
+    if let Statement::Run { nonce: Some(nonce), sign: sign @ Some(..), .. } = statement {
+      let subj = self.get_subject(sign, &hash_statement(statement));
+      if *nonce == self.get_nonce(&subj) {
+        self.set_nonce(subj, U120::from_u128_unchecked(**nonce + 1));
+      }
+    }
+  }
+
+  /// Runs the calls scheduled for the current tick, before the statements of
+  /// its block. Together, they can spend at most `BLOCK_SCHED_MANA`.
+  pub fn run_scheduled(&mut self, silent: bool) -> Vec<StatementResult> {
+    let tick = self.get_tick();
+    let mana_lim = std::cmp::min(self.get_mana() + BLOCK_SCHED_MANA, self.get_mana_limit());
+    let calls = self.get_scheduled(tick);
+    let results = calls.iter().map(
+      |call| {
+        let res = self.run_sched(call, silent, mana_lim);
+        self.draw();
+        res
       }
+    ).collect();
+    // Deletes the calls that ran, freeing their space
+    if !calls.is_empty() {
+      let size = calls.iter().map(sched_size).sum::<u64>();
+      self.get_heap_mut(self.draw).write_sched(tick, vec![]);
+      self.set_size(self.get_size().saturating_sub(size));
+      self.draw();
+    }
+    results
+  }
+
+  fn run_sched(&mut self, call: &Sched, silent: bool, mana_lim: u64) -> StatementResult {
+    fn error(rt: &mut Runtime, err: String) -> StatementResult {
+      // A failed call is undone, but the mana it spent stays spent, so that
+      // failing calls can't go past `BLOCK_SCHED_MANA` either
+      let mana = rt.get_mana();
+      rt.undo();
+      rt.set_mana(mana);
+      println!("{:02$} [sched] ERROR: {}", rt.get_tick(), err, 10);
+      return Err(StatementErr { err });
+    }
+    let mana_ini = self.get_mana();
+    let size_ini = self.get_size();
+    let size_lim = self.get_size_limit();
+    self.logs.clear();
+    let args = call.args.iter().map(|arg| Num(**arg)).collect::<Vec<_>>();
+    let argm = create_ctr(self, call.name, &args);
+    let host = alloc_fun(self, call.callee, &[argm]);
+    let done = self.run_io(U120::from(call.callee), call.caller, host, mana_lim);
+    let done = done.and_then(|done| self.compute(done, mana_lim));
+    let done = match done {
+      Ok(done) => done,
+      Err(err) => return error(self, show_runtime_error(err)),
+    };
+    let done_term = readback_term(self, done, Some(1 << 16)).unwrap_or_else(|| Term::num(U120::ZERO));
+    self.collect(done);
+    let size_end = self.get_size();
+    if size_end > size_lim {
+      return error(self, "Not enough space.".to_string());
+    }
+    let res = StatementInfo::Sched {
+      name: call.callee,
+      caller: call.caller,
+      done_term,
+      used_mana: self.get_mana() - mana_ini,
+      size_diff: (size_end as i64) - (size_ini as i64),
+      logs: std::mem::take(&mut self.logs),
+    };
+    if !silent {
+      println!("{:02$} {}", self.get_tick(), res, 10);
+    }
+    Ok(res)
+  }
+
+  pub fn run_statements_from_code(&mut self, code: &str, silent: bool, debug: bool) -> Vec<StatementResult> {
+    match parse_code(code) {
+      Ok(statements) => self.run_statements(&statements, silent, debug),
+      Err(err) => vec![Err(StatementErr { err })],
     }
   }
 
@@ -1630,15 +2362,26 @@ impl Runtime {
   }
 
   pub fn test_statements_from_code(&mut self, code: &str) -> Vec<StatementResult> {
-    let stataments = read_statements(code);
-    match stataments {
-      Ok((.., statements)) => self.test_statements(&statements),
-      Err(ParseErr { erro , .. }) => {
-        return vec![Err(StatementErr { err: erro })];
-      }
+    match parse_code(code) {
+      Ok(statements) => self.test_statements(&statements),
+      Err(err) => vec![Err(StatementErr { err })],
     }
   }
 
+  /// Enables or disables recording of the rewrites fired by `reduce`. When
+  /// enabled, each `run` statement returns its trace in `StatementInfo::Run`,
+  /// truncated after `MAX_TRACE_STEPS` steps.
+  pub fn set_tracing(&mut self, enabled: bool) {
+    self.trace = if enabled { Some(Vec::new()) } else { None };
+  }
+
+  /// Enables or disables the aggregation of the cost of each rewrite rule.
+  /// When enabled, each `run` statement returns its profile in
+  /// `StatementInfo::Run`.
+  pub fn set_profiling(&mut self, enabled: bool) {
+    self.profile = if enabled { Some(Profiler::new(self.get_dups())) } else { None };
+  }
+
   pub fn compute_at(&mut self, loc: Loc, mana: u64) -> Result<RawCell, RuntimeError> {
     compute_at(self, loc, mana)
   }
@@ -1696,191 +2439,305 @@ impl Runtime {
   // IO
   // --
 
-  pub fn run_io(&mut self, subject: U120, caller: U120, host: Loc, mana: u64) -> Result<RawCell, RuntimeError> {
-    // eprintln!("-- {}", show_term(self, host, None));
-    let term = reduce(self, host, mana)?;
-    // eprintln!("-- {}", show_term(self, term, None));
-    match get_tag(term) {
-      CTR => {
-        let ext = get_ext(term);
-        match ext {
-          IO_DONE => {
-            let retr = ask_arg(self, term, 0);
-            clear(self, host, 1);
-            clear(self, get_loc(term, 0), 1);
-            return Ok(retr);
-          }
-          IO_TAKE => {
-            //println!("- IO_TAKE subject is {} {}", u128_to_name(subject), subject);
-            let cont = ask_arg(self, term, 0);
-            if let Some(state) = self.read_disk(subject) {
-              if state != RawCell(U128_NONE) {
-                self.write_disk(subject, RawCell(U128_NONE));
-                let cont = alloc_app(self, cont, state);
-                let done = self.run_io(subject, subject, cont, mana);
-                clear(self, host, 1);
-                clear(self, get_loc(term, 0), 1);
-                return done;
-              } else {
-                return Err(RuntimeError::EffectFailure(
-                  EffectFailure::NoSuchState { state: subject },
-                ));
-              }
-            }
-            return Err(RuntimeError::EffectFailure(
-              EffectFailure::NoSuchState { state: subject },
-            ));
-          }
-          IO_SAVE => {
-            //println!("- IO_SAVE subject is {} {}", u128_to_name(subject), subject);
-            let expr = ask_arg(self, term, 0);
-            let save = self.compute(expr, mana)?;
-            self.write_disk(subject, save);
-            let cont = ask_arg(self, term, 1);
-            let cont = alloc_app(self, cont, Num(0));
-            let done = self.run_io(subject, subject, cont, mana);
-            clear(self, host, 1);
-            clear(self, get_loc(term, 0), 2);
-            return done;
+  // Runs an IO term as an explicit loop, so that long chains of effects and nested
+  // contract calls don't grow the native stack. Each step applies its continuation
+  // to the effect's result; the step's cells are only cleared once the enclosing
+  // call returns, innermost first.
+  pub fn run_io(&mut self, mut subject: U120, mut caller: U120, mut host: Loc, mana: u64) -> Result<RawCell, RuntimeError> {
+    // Pending `Call` continuations: (subject, caller, cont, clears mark)
+    let mut calls: Vec<(U120, U120, RawCell, usize)> = vec![];
+    // Cells to be cleared, innermost last
+    let mut clears: Vec<(Loc, u64)> = vec![];
+    loop {
+      // eprintln!("-- {}", show_term(self, host, None));
+      let term = reduce(self, host, mana)?;
+      // eprintln!("-- {}", show_term(self, term, None));
+      if get_tag(term) != CTR {
+        return Err(RuntimeError::EffectFailure(
+          EffectFailure::InvalidIONonCtr { ptr: term },
+        ));
+      }
+      let ext = get_ext(term);
+      let (cont, value, arit) = match ext {
+        IO_DONE => {
+          let retr = ask_arg(self, term, 0);
+          clear(self, host, 1);
+          clear(self, get_loc(term, 0), 1);
+          let mark = calls.last().map_or(0, |call| call.3);
+          while clears.len() > mark {
+            let (loc, size) = clears.pop().unwrap();
+            clear(self, loc, size);
           }
-          IO_CALL => {
-            let fnid = ask_arg(self, term, 0);
-            let argm = ask_arg(self, term, 1);
-            let cont = ask_arg(self, term, 2);
-            let fnid = self.check_num(fnid, mana)?;
-            
-            let arg_name = Name::new(get_ext(argm)).ok_or_else(|| RuntimeError::NameTooBig { numb: *argm })?;
-            let arg_arit = self
-              .get_arity(&arg_name)
-              .ok_or_else(|| RuntimeError::CtrOrFunNotDefined { name: arg_name })?;
-            // Checks if the argument is a constructor with numeric fields. This is needed since
-            // Kindelia's language is untyped, yet contracts can call each other freely. That would
-            // allow a contract to pass an argument with an unexpected type to another, corrupting
-            // its state. To avoid that, we only allow contracts to communicate by passing flat
-            // constructors of numbers, like `{Send 'Alice' #123}` or `{Inc}`.
-            for i in 0 .. arg_arit {
-              let argm = reduce(self, get_loc(argm, 0), mana)?;
-              if get_tag(argm) != NUM {
-                let f = EffectFailure::InvalidCallArg { caller: subject, callee: fnid, arg: argm };
-                return Err(RuntimeError::EffectFailure(f));
-              }
+          match calls.pop() {
+            None => {
+              return Ok(retr);
             }
-            // Calls called function IO, changing the subject
-            // TODO: this should not alloc a Fun as it's limited to 72-bit names
-            let name = Name::new(*fnid).ok_or_else(|| RuntimeError::NameTooBig { numb: *fnid })?;
-            let ioxp = alloc_fun(self, name, &[argm]);
-            let retr = self.run_io(fnid, subject, ioxp, mana)?;
             // Calls the continuation with the value returned
-            let cont = alloc_app(self, cont, retr);
-            let done = self.run_io(subject, caller, cont, mana);
-            // Clears memory
-            clear(self, host, 1);
-            //clear(self, get_loc(argm, 0), arit);
-            clear(self, get_loc(term, 0), 3);
-            return done;
-          }
-          IO_GIDX => {
-            let fnid = ask_arg(self, term, 0);
-            let cont = ask_arg(self, term, 1);
-            let name = self.check_name(fnid, mana)?;
-            let indx = self.get_index(&name).ok_or_else(|| RuntimeError::CtrOrFunNotDefined { name })?;
-            let cont = alloc_app(self, cont, Num(indx));
-            let done = self.run_io(subject, caller, cont, mana);
-            clear(self, host, 1);
-            clear(self, get_loc(term, 0), 2);
-            return done;
+            Some((call_subject, call_caller, cont, _)) => {
+              subject = call_subject;
+              caller = call_caller;
+              host = alloc_app(self, cont, retr);
+              continue;
+            }
           }
-          IO_STH0 => {
-            let indx = ask_arg(self, term, 0);
-            let cont = ask_arg(self, term, 1);
-            let indx = self.check_num(indx, mana)?;
-            let stmt_hash = self.get_sth0(*indx).ok_or_else(|| RuntimeError::StmtDoesntExist { stmt_index: *indx })?;
-            let cont = alloc_app(self, cont, Num(stmt_hash));
-            let done = self.run_io(subject, caller, cont, mana);
-            clear(self, host, 1);
-            clear(self, get_loc(term, 0), 2);
-            return done;
+        }
+        IO_TAKE => {
+          //println!("- IO_TAKE subject is {} {}", u128_to_name(subject), subject);
+          let cont = ask_arg(self, term, 0);
+          match self.read_disk(subject) {
+            Some(state) if state != RawCell(U128_NONE) => {
+              self.write_disk(subject, RawCell(U128_NONE));
+              caller = subject;
+              (cont, state, 1)
+            }
+            _ => {
+              return Err(RuntimeError::EffectFailure(
+                EffectFailure::NoSuchState { state: subject },
+              ));
+            }
           }
-          IO_STH1 => {
-            let indx = ask_arg(self, term, 0);
-            let cont = ask_arg(self, term, 1);
-            let indx = self.check_num(indx, mana)?;
-            let stmt_hash = self.get_sth1(*indx).ok_or_else(|| RuntimeError::StmtDoesntExist { stmt_index: *indx })?;
-            let cont = alloc_app(self, cont, Num(stmt_hash));
-            let done = self.run_io(subject, caller, cont, mana);
-            clear(self, host, 1);
-            clear(self, get_loc(term, 0), 2);
-            return done;
+        }
+        IO_SAVE => {
+          //println!("- IO_SAVE subject is {} {}", u128_to_name(subject), subject);
+          let expr = ask_arg(self, term, 0);
+          let save = self.compute(expr, mana)?;
+          self.write_disk(subject, save);
+          caller = subject;
+          (ask_arg(self, term, 1), Num(0), 2)
+        }
+        IO_PEEK => {
+          let fnid = ask_arg(self, term, 0);
+          let cont = ask_arg(self, term, 1);
+          let fnid = self.check_num(fnid, mana)?;
+          match self.read_disk(fnid) {
+            Some(state) if state != RawCell(U128_NONE) => {
+              // Clones the state with a dup node, normalizing both sides, so the owner
+              // keeps its copy. The dup rewrites charge mana proportional to its size.
+              let node = alloc(self, 3);
+              let dupk = self.fresh_dups() as u128;
+              link(self, node + 2, state);
+              let host0 = alloc_lnk(self, Dp0(dupk, node));
+              let host1 = alloc_lnk(self, Dp1(dupk, node));
+              let copy0 = self.compute_at(host0, mana)?;
+              let copy1 = self.compute_at(host1, mana)?;
+              clear(self, host0, 1);
+              clear(self, host1, 1);
+              self.write_disk(fnid, copy0);
+              (cont, copy1, 2)
+            }
+            _ => {
+              return Err(RuntimeError::EffectFailure(
+                EffectFailure::NoSuchState { state: fnid },
+              ));
+            }
           }
-          IO_SUBJ => {
-            let cont = ask_arg(self, term, 0);
-            let cont = alloc_app(self, cont, Num(*subject));
-            let done = self.run_io(subject, caller, cont, mana);
-            clear(self, host, 1);
-            clear(self, get_loc(term, 0), 1);
-            return done;
+        }
+        IO_CALL => {
+          let fnid = ask_arg(self, term, 0);
+          let cont = ask_arg(self, term, 2);
+          let fnid = self.check_num(fnid, mana)?;
+
+          // Checks if the argument is a tree of constructors and numbers. This is needed since
+          // Kindelia's language is untyped, yet contracts can call each other freely. That would
+          // allow a contract to pass an argument with an unexpected type to another, corrupting
+          // its state. To avoid that, we only allow contracts to communicate by passing bounded
+          // constructors of numbers, like `{Send 'Alice' #123}` or `{Cons {Pay #1} {Nil}}`. The
+          // callee gets a fresh copy of it, so it shares no memory with the caller.
+          let argm = reduce(self, get_loc(term, 1), mana)?;
+          if get_tag(argm) != CTR {
+            let f = EffectFailure::InvalidCallArg { caller: subject, callee: fnid, arg: argm };
+            return Err(RuntimeError::EffectFailure(f));
           }
-          IO_FROM => {
-            let cont = ask_arg(self, term, 0);
-            let cont = alloc_app(self, cont, Num(*caller));
-            let done = self.run_io(subject, caller, cont, mana);
-            clear(self, host, 1);
-            clear(self, get_loc(term, 0), 1);
-            return done;
+          let mut size = 0;
+          let copy = self.copy_call_arg(argm, subject, fnid, 0, &mut size, mana)?;
+          charge(self, Rewrite::IoCall, None, None, IoCallMana(size));
+          if self.get_mana() > mana {
+            return Err(RuntimeError::NotEnoughMana);
           }
-          IO_TICK => {
-            let cont = ask_arg(self, term, 0);
-            let cont = alloc_app(self, cont, Num(self.get_tick() as u128));
-            let done = self.run_io(subject, subject, cont, mana);
-            clear(self, host, 1);
-            clear(self, get_loc(term, 0), 1);
-            return done;
+          self.collect(argm);
+          let argm = copy;
+          if calls.len() >= MAX_CALL_DEPTH {
+            let f = EffectFailure::CallDepthExceeded { caller: subject, callee: fnid };
+            return Err(RuntimeError::EffectFailure(f));
           }
-          IO_TIME => {
-            let cont = ask_arg(self, term, 0);
-            let cont = alloc_app(self, cont, Num(self.get_time()));
-            let done = self.run_io(subject, subject, cont, mana);
-            clear(self, host, 1);
-            clear(self, get_loc(term, 0), 1);
-            return done;
+          // Calls called function IO, changing the subject
+          // TODO: this should not alloc a Fun as it's limited to 72-bit names
+          let name = Name::new(*fnid).ok_or_else(|| RuntimeError::NameTooBig { numb: *fnid })?;
+          let ioxp = alloc_fun(self, name, &[argm]);
+          // Clears memory once the continuation returns
+          clears.push((get_loc(term, 0), 3));
+          clears.push((host, 1));
+          calls.push((subject, caller, cont, clears.len()));
+          caller = subject;
+          subject = fnid;
+          host = ioxp;
+          continue;
+        }
+        IO_SCHE => {
+          let tick = ask_arg(self, term, 0);
+          let fnid = ask_arg(self, term, 1);
+          let cont = ask_arg(self, term, 3);
+          let tick = self.check_num(tick, mana)?;
+          let callee = self.check_name(fnid, mana)?;
+          if *tick <= self.get_tick() as u128 || *tick > u64::MAX as u128 {
+            let f = EffectFailure::InvalidSchedTick { tick };
+            return Err(RuntimeError::EffectFailure(f));
           }
-          IO_META => {
-            let cont = ask_arg(self, term, 0);
-            let cont = alloc_app(self, cont, Num(self.get_meta()));
-            let done = self.run_io(subject, subject, cont, mana);
-            clear(self, host, 1);
-            clear(self, get_loc(term, 0), 1);
-            return done;
+          if self.count_scheduled(*tick as u64) >= MAX_TICK_SCHEDS {
+            let f = EffectFailure::SchedTickFull { tick };
+            return Err(RuntimeError::EffectFailure(f));
           }
-          IO_HAX0 => {
-            let cont = ask_arg(self, term, 0);
-            let cont = alloc_app(self, cont, Num(self.get_hax0()));
-            let done = self.run_io(subject, subject, cont, mana);
-            clear(self, host, 1);
-            clear(self, get_loc(term, 0), 1);
-            return done;
+          // Unlike with CALL, the argument must be a flat constructor of numbers, since
+          // scheduled calls store their arguments outside of the HVM memory
+          let argm = reduce(self, get_loc(term, 2), mana)?;
+          if get_tag(argm) != CTR {
+            let f = EffectFailure::InvalidCallArg { caller: subject, callee: U120::from(callee), arg: argm };
+            return Err(RuntimeError::EffectFailure(f));
           }
-          IO_HAX1 => {
-            let cont = ask_arg(self, term, 0);
-            let cont = alloc_app(self, cont, Num(self.get_hax1()));
-            let done = self.run_io(subject, subject, cont, mana);
-            clear(self, host, 1);
-            clear(self, get_loc(term, 0), 1);
-            return done;
+          let name = Name::new_unsafe(get_ext(argm));
+          let arit = self.get_arity(&name).ok_or_else(|| RuntimeError::CtrOrFunNotDefined { name })?;
+          let mut args = Vec::with_capacity(arit as usize);
+          for i in 0 .. arit {
+            let arg = reduce(self, get_loc(argm, i), mana)?;
+            if get_tag(arg) != NUM {
+              let f = EffectFailure::InvalidCallArg { caller: subject, callee: U120::from(callee), arg };
+              return Err(RuntimeError::EffectFailure(f));
+            }
+            args.push(get_num(arg));
           }
-          _ => {
-            let name = Name::new_unsafe(ext);
-            return Err(RuntimeError::EffectFailure(
-              EffectFailure::InvalidIOCtr { name },
-            ));
+          charge(self, Rewrite::IoSche, None, None, IoScheMana());
+          if self.get_mana() > mana {
+            return Err(RuntimeError::NotEnoughMana);
           }
+          self.collect(argm);
+          let call = Sched { tick: *tick as u64, caller: subject, callee, name, args };
+          self.set_size(self.get_size() + sched_size(&call));
+          self.schedule(call);
+          (cont, Num(0), 4)
         }
-      }
-      _ => {
-        return Err(RuntimeError::EffectFailure(
-          EffectFailure::InvalidIONonCtr { ptr: term },
-        ));
-      }
+        IO_GIDX => {
+          let fnid = ask_arg(self, term, 0);
+          let cont = ask_arg(self, term, 1);
+          let name = self.check_name(fnid, mana)?;
+          let indx = self.get_index(&name).ok_or_else(|| RuntimeError::CtrOrFunNotDefined { name })?;
+          (cont, Num(indx), 2)
+        }
+        IO_STH0 => {
+          let indx = ask_arg(self, term, 0);
+          let cont = ask_arg(self, term, 1);
+          let indx = self.check_num(indx, mana)?;
+          let stmt_hash = self.get_sth0(*indx).ok_or_else(|| RuntimeError::StmtDoesntExist { stmt_index: *indx })?;
+          (cont, Num(stmt_hash), 2)
+        }
+        IO_STH1 => {
+          let indx = ask_arg(self, term, 0);
+          let cont = ask_arg(self, term, 1);
+          let indx = self.check_num(indx, mana)?;
+          let stmt_hash = self.get_sth1(*indx).ok_or_else(|| RuntimeError::StmtDoesntExist { stmt_index: *indx })?;
+          (cont, Num(stmt_hash), 2)
+        }
+        IO_GRUN => {
+          let blck = ask_arg(self, term, 0);
+          let stmt = ask_arg(self, term, 1);
+          let cont = ask_arg(self, term, 2);
+          let blck = self.check_num(blck, mana)?;
+          let stmt = self.check_num(stmt, mana)?;
+          let result = self.get_run_result(*blck, *stmt).ok_or_else(|| RuntimeError::StmtDoesntExist { stmt_index: stmt_position(*blck, *stmt) })?;
+          (cont, Num(*result), 3)
+        }
+        IO_SUBJ => {
+          (ask_arg(self, term, 0), Num(*subject), 1)
+        }
+        IO_FROM => {
+          (ask_arg(self, term, 0), Num(*caller), 1)
+        }
+        IO_TICK => {
+          caller = subject;
+          (ask_arg(self, term, 0), Num(self.get_tick() as u128), 1)
+        }
+        IO_TIME => {
+          caller = subject;
+          (ask_arg(self, term, 0), Num(self.get_time()), 1)
+        }
+        IO_META => {
+          caller = subject;
+          (ask_arg(self, term, 0), Num(self.get_meta()), 1)
+        }
+        IO_HAX0 => {
+          caller = subject;
+          (ask_arg(self, term, 0), Num(self.get_hax0()), 1)
+        }
+        IO_HAX1 => {
+          caller = subject;
+          (ask_arg(self, term, 0), Num(self.get_hax1()), 1)
+        }
+        IO_HASH => {
+          let val0 = ask_arg(self, term, 0);
+          let val1 = ask_arg(self, term, 1);
+          let cont = ask_arg(self, term, 2);
+          let val0 = self.check_num(val0, mana)?;
+          let val1 = self.check_num(val1, mana)?;
+          charge(self, Rewrite::IoHash, None, None, IoHashMana());
+          if self.get_mana() > mana {
+            return Err(RuntimeError::NotEnoughMana);
+          }
+          let bytes = u120s_to_bytes(&[val0, val1], 30).unwrap();
+          let (hash0, hash1) = hash_to_u120s(&crypto::Hash::keccak256_from_bytes(&bytes));
+          let pair = create_ctr(self, Name::new_unsafe(TUP2), &[Num(hash0), Num(hash1)]);
+          (cont, pair, 3)
+        }
+        IO_SIGN => {
+          let mut nums = Vec::with_capacity(8);
+          for i in 0 .. 8 {
+            let num = ask_arg(self, term, i);
+            nums.push(self.check_num(num, mana)?);
+          }
+          let cont = ask_arg(self, term, 8);
+          charge(self, Rewrite::IoSign, None, None, IoSignMana());
+          if self.get_mana() > mana {
+            return Err(RuntimeError::NotEnoughMana);
+          }
+          // Returns #0 if the hash or the signature are malformed, or if no signer is recovered
+          let hash = u120s_to_bytes(&nums[0 .. 3], 32).map(|bytes| crypto::Hash(bytes.try_into().unwrap()));
+          let sign = u120s_to_bytes(&nums[3 .. 8], 65).and_then(|bytes| crypto::Signature::from_bytes(&bytes));
+          let signer = match (hash, sign) {
+            (Some(hash), Some(sign)) => sign.signer_name(&hash).map_or(0, |name| *name),
+            _ => 0,
+          };
+          (cont, Num(signer), 9)
+        }
+        IO_LOG => {
+          if self.logs.len() >= MAX_LOGS {
+            return Err(RuntimeError::EffectFailure(EffectFailure::TooManyLogs { subject }));
+          }
+          let expr = ask_arg(self, term, 0);
+          let logged = self.compute(expr, mana)?;
+          // Reading back a term takes at most two steps per cell, so it's bounded by the room left
+          // for this statement's logs
+          let used = self.logs.iter().map(log_size).sum::<u64>();
+          let room = MAX_LOGS_SIZE - used;
+          let log = readback_term(self, logged, Some(2 * room as usize + 1))
+            .filter(|log| log_size(log) <= room)
+            .ok_or(RuntimeError::EffectFailure(EffectFailure::LogsTooBig { subject }))?;
+          charge(self, Rewrite::IoLog, None, None, IoLogMana(log_size(&log)));
+          if self.get_mana() > mana {
+            return Err(RuntimeError::NotEnoughMana);
+          }
+          self.logs.push(log);
+          self.collect(logged);
+          (ask_arg(self, term, 1), Num(0), 2)
+        }
+        _ => {
+          let name = Name::new_unsafe(ext);
+          return Err(RuntimeError::EffectFailure(
+            EffectFailure::InvalidIOCtr { name },
+          ));
+        }
+      };
+      // Calls the continuation with the effect's result
+      clears.push((get_loc(term, 0), arit));
+      clears.push((host, 1));
+      host = alloc_app(self, cont, value);
     }
   }
 
@@ -1905,6 +2762,34 @@ impl Runtime {
     }
   }
 
+  /// Copies a reduced argument of a contract call, checking that it's a tree
+  /// of constructors and numbers within `MAX_CALL_ARG_DEPTH` and
+  /// `MAX_CALL_ARG_SIZE`. `size` counts the cells copied so far.
+  fn copy_call_arg(&mut self, arg: RawCell, caller: U120, callee: U120, depth: usize, size: &mut u64, mana: u64) -> Result<RawCell, RuntimeError> {
+    *size += 1;
+    if *size > MAX_CALL_ARG_SIZE {
+      return Err(RuntimeError::EffectFailure(EffectFailure::CallArgTooBig { caller, callee }));
+    }
+    match get_tag(arg) {
+      NUM => Ok(arg),
+      CTR => {
+        if depth >= MAX_CALL_ARG_DEPTH {
+          return Err(RuntimeError::EffectFailure(EffectFailure::CallArgTooDeep { caller, callee }));
+        }
+        let name = Name::new_unsafe(get_ext(arg));
+        let arit = self.get_arity(&name).ok_or(RuntimeError::CtrOrFunNotDefined { name })?;
+        let node = alloc(self, arit);
+        for i in 0 .. arit {
+          let field = reduce(self, get_loc(arg, i), mana)?;
+          let field = self.copy_call_arg(field, caller, callee, depth + 1, size, mana)?;
+          link(self, node + i, field);
+        }
+        Ok(Ctr(name, node))
+      }
+      _ => Err(RuntimeError::EffectFailure(EffectFailure::InvalidCallArg { caller, callee, arg })),
+    }
+  }
+
   pub fn check_name(&mut self, ptr: RawCell, mana: u64) -> Result<Name, RuntimeError> {
     let num = self.check_num(ptr, mana)?;
     match Name::new(*num) {
@@ -1944,6 +2829,11 @@ impl Runtime {
     }
   }
 
+  pub fn can_transfer(&mut self, subj: U120, name: &Name) -> bool {
+    // Only the current owner can transfer a namespace
+    Some(subj) == self.get_owner(name)
+  }
+
   /// Run statement in the `draw` heap.
   ///
   /// It doesn't alter `curr` heap.
@@ -1963,8 +2853,20 @@ impl Runtime {
       })
     }
     let hash = hash_statement(statement);
+    if let Some(until) = get_until(statement) {
+      if self.get_tick() > until {
+        let tag = match statement {
+          Statement::Fun { .. } => "fun",
+          Statement::Ctr { .. } => "ctr",
+          Statement::Run { .. } => "run",
+          Statement::Reg { .. } => "reg",
+          Statement::Own { .. } => "own",
+        };
+        return error(self, tag, format!("Statement expired after tick {}.", until));
+      }
+    }
     let res = match statement {
-      Statement::Fun { name, args, func, init, sign } => {
+      Statement::Fun { name, args, func, init, sign, .. } => {
         if self.exists(name) {
           return error(self, "fun", format!("Can't redefine '{}'.", name));
         }
@@ -1973,11 +2875,23 @@ impl Runtime {
           return error(self, "fun", format!("Subject '#x{:0>30x}' not allowed to deploy '{}'.", *subj, name));
         }
         handle_runtime_err(self, "fun", check_func(&func))?;
-        let func = compile_func(func, true);
-        let func = handle_runtime_err(self, "fun", func)?;
+        let funcs = handle_runtime_err(self, "fun", flatten_func(*name, func))?;
+        let mut comp_funcs = Vec::new();
+        for (func_name, func) in funcs {
+          if func_name != *name && self.exists(&func_name) {
+            return error(self, "fun", format!("Can't redefine '{}'.", func_name));
+          }
+          let func = compile_func(&func, true);
+          let func = handle_runtime_err(self, "fun", func)?;
+          comp_funcs.push((func_name, func));
+        }
         let name = *name;
-        self.set_arity(name, args.len() as u64);
-        self.define_function(name, func, stmt_index, hash);
+        // Auxiliary functions of nested matches are deployed along the function
+        for (func_name, func) in comp_funcs {
+          let arity = if func_name == name { args.len() as u64 } else { func.arity };
+          self.set_arity(func_name, arity);
+          self.define_function(func_name, func, stmt_index, hash.clone());
+        }
         if let Some(state) = init {
           let state = self.create_term(state, Loc(0), &mut init_name_map());
           let state = handle_runtime_err(self, "fun", state)?;
@@ -1988,7 +2902,7 @@ impl Runtime {
         let args = args.iter().map(|x| *x).collect::<Vec<_>>();
         StatementInfo::Fun { name, args }
       }
-      Statement::Ctr { name, args, sign } => {
+      Statement::Ctr { name, args, sign, .. } => {
         if self.exists(name) {
           return error(self, "ctr", format!("Can't redefine '{}'.", name));
         }
@@ -2004,15 +2918,38 @@ impl Runtime {
         let args = args.iter().map(|x| *x).collect::<Vec<_>>();
         StatementInfo::Ctr { name, args }
       }
-      Statement::Run { expr, sign } => {
+      Statement::Run { expr, mana, nonce, sign, .. } => {
         let mana_ini = self.get_mana();
         let mana_lim = if !sudo { self.get_mana_limit() } else { u64::MAX }; // ugly
+        // A run can cap its own mana below the global limit
+        let mana_lim = match mana {
+          Some(mana) => std::cmp::min(mana_lim, mana_ini.saturating_add(*mana)),
+          None       => mana_lim,
+        };
         let size_ini = self.get_size();
         let size_lim = self.get_size_limit();
         handle_runtime_err(self, "run", check_term(&expr))?; 
         let subj = self.get_subject(&sign, &hash);
+        // A signed run must have the next nonce of its subject, so that it
+        // can't be replayed. If the run fails, `run_statements` uses the
+        // nonce up anyway.
+        if sign.is_some() {
+          let next = self.get_nonce(&subj);
+          if *nonce != Some(next) {
+            let nonce = nonce.map_or("no nonce".to_string(), |nonce| format!("nonce #{}", *nonce));
+            return error(self, "run", format!("Signed run of subject '{}' has {}, expected #{}.", subj, nonce, *next));
+          }
+          self.set_nonce(subj, U120::from_u128_unchecked(*next + 1));
+        }
         let host = self.alloc_term(expr);
         let host = handle_runtime_err(self, "run", host)?;
+        self.logs.clear();
+        if let Some(trace) = &mut self.trace {
+          trace.clear();
+        }
+        if self.profile.is_some() {
+          self.profile = Some(Profiler::new(self.get_dups()));
+        }
         let done = self.run_io(subj, U120::from_u128_unchecked(0), host, mana_lim);
         if let Err(err) = done {
           return error(self, "run", show_runtime_error(err));
@@ -2027,7 +2964,7 @@ impl Runtime {
         // The term return by Done is only read and stored in debug mode for
         // testing purpouses. In the future, the Done return value will be
         // limited to `Term::Num`s and the U120s will be stored as part of the
-        // protocol. Also, a `Log` primitive should be added.
+        // protocol.
         let done_term =
           // if debug {
           if let Some(term) = readback_term(self, done, Some(1 << 16)) {
@@ -2035,6 +2972,9 @@ impl Runtime {
            } else {
             Term::num(U120::ZERO)
           };
+        if get_tag(done) == NUM {
+          self.save_run_result(stmt_index, get_num(done));
+        }
         self.collect(done);
         let size_end = self.get_size() as u64;
         let mana_dif = self.get_mana() - mana_ini;
@@ -2047,10 +2987,13 @@ impl Runtime {
           used_mana: mana_dif,
           size_diff: size_dif,
           end_size: size_end, // TODO: rename to done_size for consistency?
+          logs: std::mem::take(&mut self.logs),
+          trace: self.trace.as_mut().map(std::mem::take),
+          profile: self.take_profile(),
         }
         // TODO: save run to statement array?
       }
-      Statement::Reg { name, ownr, sign } => {
+      Statement::Reg { name, ownr, sign, .. } => {
         let ownr = *ownr;
 
         if self.exists(name) {
@@ -2065,13 +3008,61 @@ impl Runtime {
         self.set_owner(name, ownr);
         StatementInfo::Reg { name, ownr }
       }
+      Statement::Own { name, ownr, nonce, sign, .. } => {
+        let ownr = *ownr;
+
+        if name.is_empty() {
+          // The empty namespace is given away on the Genesis Block, once
+          return error(self, "own", format!("Can't transfer the empty namespace."));
+        }
+        let prev = match self.get_owner(name) {
+          Some(prev) => prev,
+          None => return error(self, "own", format!("Namespace '{}' is not registered.", name)),
+        };
+        let subj = self.get_subject(sign, &hash);
+        if !(self.can_transfer(subj, name) || sudo) {
+          return error(self, "own", format!("Subject '{}' not allowed to transfer '{}'.", subj, name));
+        }
+        // Like a signed run, a signed transfer uses up its subject's next nonce, so that it can't
+        // be replayed if the namespace comes back to the subject
+        if sign.is_some() {
+          let next = self.get_nonce(&subj);
+          if *nonce != Some(next) {
+            let nonce = nonce.map_or("no nonce".to_string(), |nonce| format!("nonce #{}", *nonce));
+            return error(self, "own", format!("Signed own of subject '{}' has {}, expected #{}.", subj, nonce, *next));
+          }
+          self.set_nonce(subj, U120::from_u128_unchecked(*next + 1));
+        }
+        let name = *name;
+        self.save_transfer(name, stmt_index, hash);
+        self.set_owner(name, ownr);
+        StatementInfo::Own { name, prev, ownr }
+      }
     };
     if !silent {
       println!("{:02$} {}", self.get_tick(), res, 10);
+      if let StatementInfo::Run { logs, trace, profile, .. } = &res {
+        for log in logs {
+          println!("{:02$} [log] {}", self.get_tick(), view_term(log), 10);
+        }
+        for step in trace.iter().flatten() {
+          println!("{:02$} [trace] {}", self.get_tick(), step, 10);
+        }
+        if let Some(profile) = profile {
+          for line in view_profile(profile) {
+            println!("{:02$} [prof] {}", self.get_tick(), line, 10);
+          }
+        }
+      }
     }
     Ok(res)
   }
 
+  fn take_profile(&mut self) -> Option<Vec<ProfileRow>> {
+    let dups = self.get_dups();
+    self.profile.as_mut().map(|profile| profile.finish(dups))
+  }
+
   // Maximum mana = 42m * block_number
   pub fn get_mana_limit(&self) -> u64 {
     (self.get_tick() + 1) * BLOCK_MANA_LIMIT
@@ -2085,9 +3076,9 @@ impl Runtime {
   // Rollback
   // --------
 
-  // Returns a clone of a reference to the current rollback state.
-  pub fn get_back(&self) -> Arc<Rollback> {
-    return self.back.clone();
+  // Returns a reference to the current rollback state.
+  pub fn get_back(&self) -> &Rollback {
+    return &self.back;
   }
 
   /// Advances the heap time counter.
@@ -2097,37 +3088,51 @@ impl Runtime {
 
   /// Saves past states for rollback.
   pub fn commit(&mut self) {
+    self.update_leafs();
     self.draw();
     self.snapshot();
   }
 
+  // Pushes the current heap to the rollback ring if a snapshot is due at this tick, which is when
+  // the ring is empty or the tick is a multiple of its spacing. If the ring is full, its oldest
+  // snapshot absorbs the next one first, freeing a heap.
   pub fn snapshot(&mut self) {
-    //println!("tick self.curr={}", self.curr);
-    let (included, absorber, deleted, rollback) = rollback_push(self.curr, self.back.clone(), 0);
-    // println!("- tick={} self.curr={}, included={:?} absorber={:?} deleted={:?} rollback={}", self.get_tick(), self.curr, included, absorber, deleted, view_rollback(&self.back));
-    self.back = rollback;
-    // println!(" - back {}", view_rollback(&self.back));
-    if included {
-      self.save_state_metadata().expect("Error saving state metadata.");
-      let path = &self.get_dir_path();
-      // let _ = &self.heap[self.curr as usize].serialize(path, true).expect("Error saving buffers."); // heap persistence disabled
-      if let Some(deleted) = deleted {
-        if let Some(absorber) = absorber {
-          self.absorb_heap(absorber, deleted, false);
-          // let _ = self.heap[absorber as usize].serialize(path, false).expect("Couldn't append buffers."); // heap persistence disabled
-        }
-        // self.heap[deleted as usize].delete_buffers(path).expect("Couldn't delete buffers."); // heap persistence disabled
-        self.clear_heap(deleted);
-        self.curr = deleted;
-      } else if let Some(empty) = self.nuls.pop() {
-        self.curr = empty;
-      } else {
-        //println!("- {} {} {:?} {}", self.draw, self.curr, self.nuls, view_rollback(&self.back));
-        panic!("Not enough heaps.");
+    let tick = self.get_tick();
+    let due = match self.back.newest() {
+      None => true,
+      Some(newest) => tick.is_multiple_of(self.back.get_spacing()) && tick > self.get_heap_tick(newest),
+    };
+    if due {
+      if self.back.is_full() {
+        self.merge_oldest_snapshots();
+      }
+      self.back.push(self.curr);
+      self.curr = self.nuls.pop().expect("No heap available!");
+      // A failure leaves the last manifest on disk, so it is reported and retried on the next one
+      if let Err(err) = self.persist_state() {
+        eprintln!("WARN: Could not persist the runtime state: {}", err);
       }
     }
   }
 
+  // Merges the two oldest snapshots of the rollback ring into the oldest one's heap
+  fn merge_oldest_snapshots(&mut self) {
+    if let Some((absorber, absorbed)) = self.back.merge() {
+      self.absorb_heap(absorber, absorbed, true);
+      self.get_heap_mut(absorber).sche.prune();
+      // The absorber changed, so it must be saved again, under a new uuid
+      self.get_heap_mut(absorber).uuid = fastrand::u128(..);
+      self.clear_heap(absorbed);
+      self.nuls.push(absorbed);
+    }
+  }
+
+  // The tick of a snapshot heap. A heap without one was committed at tick 0.
+  fn get_heap_tick(&self, index: u64) -> u64 {
+    let tick = self.get_heap(index).tick;
+    if tick == U64_NONE { 0 } else { tick }
+  }
+
   // Rolls back to the earliest state before or equal `tick`
   pub fn rollback(&mut self, tick: u64) {
     // If target tick is older than current tick
@@ -2135,22 +3140,19 @@ impl Runtime {
       eprintln!("- rolling back from {} to {}", self.get_tick(), tick);
       self.clear_heap(self.curr);
       self.nuls.push(self.curr);
-      let mut cuts = 0;
-      let path = self.get_dir_path();
       // Removes heaps until the runtime's tick is larger than, or equal to, the target tick
       while tick < self.get_tick() {
-        if let Rollback::Cons { keep, life, head, tail } = &*self.back.clone() {
-          // self.heap[*head as usize].delete_buffers(&path).expect("Couldn't delete buffers."); // heap persistence disabled
-          self.clear_heap(*head);
-          self.nuls.push(*head);
-          self.back = tail.clone();
-          cuts += 1 + life;
+        match self.back.pop() {
+          // Its buffers are kept until the next snapshot, as the manifest still points to them
+          Some(head) => {
+            self.clear_heap(head);
+            self.nuls.push(head);
+          }
+          None => break,
         }
       }
-      if let Rollback::Cons { keep, life, head, tail } = &*self.back {
-        self.back = Arc::new(Rollback::Cons { keep: 0, life: *life + cuts, head: *head, tail: tail.clone() });
-      }
       self.curr = self.nuls.pop().expect("No heap available!");
+      self.build_state_tree();
     }
     // println!("- rolled back to {}", self.get_tick());
   }
@@ -2158,85 +3160,117 @@ impl Runtime {
   // Persistence
   // -----------
 
-  pub fn get_dir_path(&self) -> PathBuf {
+  pub fn get_dir_path(&self) -> Option<PathBuf> {
     return self.path.clone();
   }
 
-  // Persists the current state. Since heaps are automatically saved to disk, function only saves
-  // their uuids. Note that this will NOT save the current heap, nor anything after the last heap
-  // included on the Rollback list. In other words, it forgets up to ~16 recent blocks. This
-  // function is used to avoid re-processing the entire block history on node startup.
-  pub fn save_state_metadata(&self) -> std::io::Result<()> {
-    fn build_persistence_buffers(rt: &Runtime, rollback: &Rollback, keeps: &mut Vec<u128>, lifes: &mut Vec<u128>, uuids: &mut Vec<u128>) {
-      match rollback {
-        Rollback::Cons { keep, life, head, tail } => {
-          keeps.push(*keep as u128);
-          lifes.push(*life as u128);
-          uuids.push(rt.heap[*head as usize].uuid);
-          build_persistence_buffers(rt, tail, keeps, lifes, uuids);
+  // Stops saving the runtime state, leaving the one persisted at its path untouched
+  pub fn detach(&mut self) {
+    self.path = None;
+  }
+
+  pub fn get_tip(&self) -> U256 {
+    return self.tip;
+  }
+
+  pub fn set_tip(&mut self, tip: U256) {
+    self.tip = tip;
+  }
+
+  // Persists the state of the Rollback ring, so that a restarted node doesn't need to re-process
+  // the blocks it covers. Heaps on the ring don't change, so each one is saved once, under its
+  // uuid. The draw heap, which `commit` has already absorbed into the ring, is saved too, empty.
+  // Then, the `_manifest_` file, which ties the `uuids` of the ring, from the oldest snapshot to
+  // the newest, to the tip, is replaced atomically. Only after that are the buffers it doesn't
+  // point to deleted, so a crash at any point leaves the last manifest, and every heap it points
+  // to, on disk. The current heap isn't saved, so the blocks after the newest snapshot (up to
+  // the rollback spacing, 64 by default) are recomputed on startup. Does nothing for a runtime
+  // without a path.
+  pub fn persist_state(&mut self) -> std::io::Result<()> {
+    let path = match self.get_dir_path() {
+      Some(path) => path,
+      None => return Ok(()),
+    };
+    let mut uuids : Vec<u128> = vec![];
+    for index in self.back.iter().rev() {
+      let heap = self.get_heap(index);
+      if !Heap::file_path(heap.uuid, &path).exists() {
+        heap.serialize(&path)?;
+      }
+      uuids.push(heap.uuid);
+    }
+    let draw = self.draw;
+    self.get_heap_mut(draw).uuid = fastrand::u128(..);
+    self.get_heap(draw).serialize(&path)?;
+    let mut manifest = vec![(self.tip >> 128).low_u128(), self.tip.low_u128(), self.get_heap(draw).uuid];
+    manifest.extend(&uuids);
+    persistence::write_atomic(&path.join("_manifest_"), &util::u128s_to_u8s(&manifest))?;
+    let live : HashSet<u128> = uuids.iter().chain([self.get_heap(draw).uuid].iter()).copied().collect();
+    for entry in std::fs::read_dir(&path)? {
+      let file_path = entry?.path();
+      let uuid = file_path.file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.split('.').next())
+        .and_then(|uuid| u128::from_str_radix(uuid, 16).ok());
+      if let Some(uuid) = uuid {
+        if !live.contains(&uuid) {
+          std::fs::remove_file(file_path)?;
         }
-        Rollback::Nil => {}
       }
     }
-    let mut keeps : Vec<u128> = vec![];
-    let mut lifes : Vec<u128> = vec![];
-    let mut uuids : Vec<u128> = vec![];
-    build_persistence_buffers(self, &self.back,  &mut keeps, &mut lifes, &mut uuids);
-    std::fs::write(self.path.join("_keeps_"), &util::u128s_to_u8s(&keeps))?;
-    std::fs::write(self.path.join("_lifes_"), &util::u128s_to_u8s(&lifes))?;
-    std::fs::write(self.path.join("_uuids_"), &util::u128s_to_u8s(&uuids))?;
     return Ok(());
   }
 
-  // Restores the saved state. This loads the persisted Rollback list and its heaps.
+  // Merges every heap, from the oldest to the draw one, into a single heap with the whole state
+  pub fn export_state(&self) -> Heap {
+    let mut heaps = vec![self.draw, self.curr];
+    heaps.extend(self.back.iter());
+    let mut state = init_heap();
+    for index in heaps.iter().rev() {
+      state.merge(self.get_heap(*index));
+    }
+    state.sche.prune();
+    state
+  }
+
+  // Restores the saved state. This loads the Rollback ring, its heaps, the draw heap and the tip
+  // of the last manifest. The current heap starts empty, since it isn't persisted. If the ring
+  // holds fewer snapshots than the manifest, the oldest ones are merged.
   pub fn restore_state(&mut self) -> std::io::Result<()> {
-    for i in 0 .. MAX_HEAPS {
-      self.heap[i as usize].clear();
+    let path = self.get_dir_path().ok_or_else(|| {
+      std::io::Error::new(std::io::ErrorKind::NotFound, "Runtime state isn't persisted.")
+    })?;
+    let manifest = util::u8s_to_u128s(&std::fs::read(path.join("_manifest_"))?);
+    if manifest.len() < 3 {
+      return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Corrupted manifest."));
+    }
+    let tip = (U256::from(manifest[0]) << 128) + U256::from(manifest[1]);
+    let draw = Heap::deserialize(manifest[2], &path)?;
+    for heap in &mut self.heap {
+      heap.clear();
     }
-    self.nuls = (2 .. MAX_HEAPS).collect();
-    // for i in 0 .. std::cmp::max(uuids.len(), 8) {
-    //   self.heap[i + 2].load_buffers(uuids[i])?;
-    // }
-    let mut keeps = util::u8s_to_u128s(&std::fs::read(self.path.join("_keeps_"))?);
-    let mut lifes = util::u8s_to_u128s(&std::fs::read(self.path.join("_lifes_"))?);
-    let mut uuids = util::u8s_to_u128s(&std::fs::read(self.path.join("_uuids_"))?);
-    fn load_heaps(rt: &mut Runtime, keeps: &mut Vec<u128>, lifes: &mut Vec<u128>, uuids: &mut Vec<u128>, index: u64, back: Arc<Rollback>) -> std::io::Result<Arc<Rollback>> {
-      let keep = keeps.pop();
-      let life = lifes.pop();
-      let uuid = uuids.pop();
-      match (keep, life, uuid) {
-        (Some(keep), Some(life), Some(uuid)) => {
-          let next = rt.nuls.pop();
-          match next {
-            Some(next) => {
-              let path = rt.get_dir_path();
-              rt.heap[index as usize] = Heap::deserialize(uuid, &path)?;
-              rt.curr = index;
-              return load_heaps(rt, keeps, lifes, uuids, next, Arc::new(Rollback::Cons { keep: keep as u64, life: life as u64, head: index, tail: back }));
-            }
-            None => {
-              panic!("Not enough heaps.");
-            }
-          }
-        }
-        (None, None, None) => {
-          return Ok(back);
-        }
-        _ => {
-          panic!("Corrupted persistence files.");
-        }
+    self.draw = 0;
+    self.nuls = (1 .. self.heap.len() as u64).rev().collect();
+    self.back.clear();
+    for uuid in &manifest[3 ..] {
+      if self.back.is_full() {
+        self.merge_oldest_snapshots();
       }
+      let index = self.nuls.pop().expect("No heap available!");
+      self.heap[index as usize] = Heap::deserialize(*uuid, &path)?;
+      self.back.push(index);
     }
-    self.draw = 0;
-    self.curr = 1;
-    self.back = load_heaps(self, &mut keeps, &mut lifes, &mut uuids, self.curr, Arc::new(Rollback::Nil))?;
     self.curr = self.nuls.pop().expect("No heap available!");
+    self.heap[self.draw as usize] = draw;
+    self.tip = tip;
+    self.build_state_tree();
     return Ok(());
   }
 
   // Reverts until the last 
   pub fn clear_current_heap(&mut self) {
     self.heap[self.curr as usize].clear();
+    self.build_state_tree();
   }
 
   // Heap writers and readers
@@ -2253,21 +3287,13 @@ impl Runtime {
     if none != got {
       return got;
     }
-    let mut back = &self.back;
-    loop {
-      match &**back {
-        Rollback::Cons { keep, life, head, tail } => {
-          let val = get(self.get_heap(*head));
-          if val != none {
-            return val;
-          }
-          back = tail;
-        }
-        Rollback::Nil => {
-          return zero;
-        }
+    for index in self.back.iter() {
+      let val = get(self.get_heap(index));
+      if val != none {
+        return val;
       }
     }
+    return zero;
   }
 
   // Same as get_with, but gets a function
@@ -2281,30 +3307,20 @@ impl Runtime {
     if let Some(func) = got {
       return Some(func);
     }
-    let mut back = &self.back;
-    loop {
-      match &**back {
-        Rollback::Cons { keep, life, head, tail } => {
-          let got = self.get_heap(*head).file.read(name);
-          if let Some(func) = got {
-            return Some(func);
-          }
-          back = tail;
-        }
-        Rollback::Nil => {
-          return None;
-        }
+    for index in self.back.iter() {
+      let got = self.get_heap(index).file.read(name);
+      if let Some(func) = got {
+        return Some(func);
       }
     }
+    return None;
   }
 
   pub fn reduce_with<A>(&self, acc: &mut A, reduce: impl Fn(&mut A, &Heap)) {
     reduce(acc, &self.get_heap(self.draw));
     reduce(acc, &self.get_heap(self.curr));
-    let mut back = &self.back;
-    while let Rollback::Cons { keep: _, life, head, tail } = &**back {
-      reduce(acc, self.get_heap(*head));
-      back = tail;
+    for index in self.back.iter() {
+      reduce(acc, self.get_heap(index));
     }
   }
 
@@ -2317,6 +3333,7 @@ impl Runtime {
   }
 
   pub fn write_disk(&mut self, name: U120, val: RawCell) {
+    self.dirt.insert(Name::from(name));
     return self.get_heap_mut(self.draw).write_disk(name, val);
   }
 
@@ -2324,6 +3341,11 @@ impl Runtime {
     return self.get_with(None, None, |heap| heap.read_disk(name));
   }
 
+  /// The state of the function `name`, unless it has none, as after a TAKE.
+  pub fn read_state(&self, name: U120) -> Option<RawCell> {
+    self.read_disk(name).filter(|state| *state != RawCell(U128_NONE))
+  }
+
   pub fn read_disk_as_term(&mut self, name: U120, limit: Option<usize>) -> Option<Term> {
     let host = self.read_disk(name)?;
     readback_term(self, host, limit)
@@ -2338,6 +3360,7 @@ impl Runtime {
   }
 
   pub fn set_arity(&mut self, name: Name, arity: u64) {
+    self.dirt.insert(name);
     self.get_heap_mut(self.draw).write_arit(name, arity);
   }
 
@@ -2346,6 +3369,7 @@ impl Runtime {
   }
 
   pub fn set_owner(&mut self, name: Name, owner: U120) {
+    self.dirt.insert(name);
     self.get_heap_mut(self.draw).write_ownr(name, owner);
   }
 
@@ -2353,29 +3377,26 @@ impl Runtime {
     self.get_with(None, None, |heap| heap.read_indx(name))
   }
 
+  /// Position of the statement that last transferred the namespace `name`.
+  pub fn get_transfer(&self, name: &Name) -> Option<u128> {
+    self.get_with(None, None, |heap| heap.read_xfer(name))
+  }
+
+  /// Gets the numeric result of the `run` statement at `stmt_idx` on block `block_idx`.
+  pub fn get_run_result(&self, block_idx: u128, stmt_idx: u128) -> Option<U120> {
+    let pos = stmt_position(block_idx, stmt_idx);
+    self.get_with(None, None, |heap| heap.read_run(&pos))
+  }
+
 
   pub fn get_sth0(&mut self, pos: u128) -> Option<u128> {
-    let stmt_hash = self.get_with(None, None, |heap| heap.read_stmt_hash(&pos).map(|h| h.clone()));
-    if let Some(stmt_hash) = stmt_hash { // is cloning here really necessary?
-      let mut bytes: [u8; 16] = [0; 16];
-      bytes.copy_from_slice(&stmt_hash.0[0..16]);
-      Some(u128::from_le_bytes(bytes) & *U120::MAX)
-    }
-    else {
-      None
-    }
+    let stmt_hash = self.get_with(None, None, |heap| heap.read_stmt_hash(&pos).map(|h| h.clone())); // is cloning here really necessary?
+    stmt_hash.map(|stmt_hash| hash_to_u120s(&stmt_hash).0)
   }
 
   pub fn get_sth1(&mut self, pos: u128) -> Option<u128> {
     let stmt_hash = self.get_with(None, None, |heap| heap.read_stmt_hash(&pos).map(|h| h.clone()));
-    if let Some(stmt_hash) = stmt_hash {
-      let mut bytes: [u8; 16] = [0; 16];
-      bytes.copy_from_slice(&stmt_hash.0[15..31]); //read from 15 to 31st byte and throw the last one away.
-      Some(u128::from_le_bytes(bytes) & *U120::MAX)
-    }
-    else {
-      None
-    }
+    stmt_hash.map(|stmt_hash| hash_to_u120s(&stmt_hash).1)
   }
 
   
@@ -2523,57 +3544,198 @@ impl Runtime {
           .collect();
       acc.append(&mut heap_ns);
     });
+    // A namespace transferred after its registration has an owner on several heaps
+    let mut seen = HashSet::new();
+    ns.retain(|name| seen.insert(*name));
     ns
   }
-}
 
-// Attempts to include a heap state on the list of past heap states. It only keeps at most
-// `log_16(tick)` heaps in memory, rejecting heaps that it doesn't need to store. It returns:
-// - included : Bool             = true if the heap was included, false if it was rejected
-// - absorber : Option<Box<u64>> = the index of the dropped heap absorber (if any)
-// - deleted  : Option<Box<u64>> = the index of the dropped heap (if any)
-// - rollback : Rollback         = the updated rollback object
-pub fn rollback_push(elem: u64, back: Arc<Rollback>, depth: u64) -> (bool, Option<u64>, Option<u64>, Arc<Rollback>) {
-  if depth >= MAX_ROLLBACK {
-    return (false, None, Some(elem), Arc::new(Rollback::Nil));
-  } else {
-    match &*back {
-      Rollback::Nil => {
-        let rollback = Arc::new(Rollback::Cons { keep: 0, life: 0, head: elem, tail: Arc::new(Rollback::Nil) });
-        return (true, None, None, rollback);
-      }
-      Rollback::Cons { keep, life, head, tail } => {
-        if *keep == 0xF {
-          if *life > 0 {
-            let tail = Arc::new(Rollback::Cons { keep: 0, life: life - 1, head: *head, tail: tail.clone() });
-            let back = Arc::new(Rollback::Cons { keep: 0, life: 0, head: elem, tail });
-            return (true, None, None, back);
-          } else {
-            let (included, absorber, deleted, tail) = rollback_push(*head, tail.clone(), depth + 1);
-            let absorber = if !included { Some(elem) } else { absorber };
-            let rollback = Arc::new(Rollback::Cons { keep: 0, life: *life, head: elem, tail });
-            return (true, absorber, deleted, rollback);
-          }
-        } else {
-          let rollback = Arc::new(Rollback::Cons { keep: keep + 1, life: *life, head: *head, tail: tail.clone() });
-          return (false, None, Some(elem), rollback);
-        }
+  // State root
+  // ----------
+
+  // Recomputes the leaves of the names changed since the last commit
+  fn update_leafs(&mut self) {
+    let names: Vec<Name> = self.dirt.drain().collect();
+    let mut leafs = vec![];
+    for name in names {
+      let state = self.read_state(U120::from(name));
+      // Names only touched by discarded changes, like dry runs, don't get a leaf
+      let empty = self.get_arity(&name).is_none() && self.get_owner(&name).is_none() && state.is_none();
+      if empty && self.get_with(None, None, |heap| heap.read_leaf(&name)).is_none() {
+        continue;
       }
+      let defn = self.get_definition_hash(&name);
+      let state = state.and_then(|state| readback_term(self, state, None));
+      let leaf = state_leaf(name, &defn, &hash_state(state.as_ref()));
+      self.get_heap_mut(self.draw).write_leaf(name, leaf.clone());
+      leafs.push((name, leaf));
     }
+    self.root.update(leafs);
+  }
+
+  // Rebuilds the state root tree from the leaves on the heaps, after they were swapped for
+  // others, as on rollbacks and restores
+  fn build_state_tree(&mut self) {
+    self.root = StateTree::new(self.get_state_leafs());
+  }
+
+  /// Hash of the arity, owner and function of `name`, as committed to by its state root leaf.
+  pub fn get_definition_hash(&self, name: &Name) -> crypto::Hash {
+    let func = self.get_func(name);
+    hash_definition(self.get_arity(name), self.get_owner(name), func.as_ref().map(|func| &func.func))
+  }
+
+  /// Root of a Merkle tree over the leaves of every defined name, sorted by name. Each leaf
+  /// commits to the arity, owner, function and state of its name, as of the last commit.
+  pub fn get_state_root(&self) -> crypto::Hash {
+    self.root.root()
+  }
+
+  /// The Merkle tree of the state root, as of the last commit.
+  pub fn get_state_tree(&self) -> &StateTree {
+    &self.root
+  }
+
+  /// The state root leaves, sorted by name, read from the heaps.
+  pub fn get_state_leafs(&self) -> Vec<(Name, crypto::Hash)> {
+    let mut names: Vec<Name> = Vec::new();
+    self.reduce_with(&mut names, |acc, heap| acc.extend(heap.leaf.leafs.keys()));
+    names.sort_unstable_by_key(|name| **name);
+    names.dedup();
+    names.into_iter().filter_map(|name| {
+      let leaf = self.get_with(None, None, |heap| heap.read_leaf(&name))?;
+      Some((name, leaf))
+    }).collect()
   }
 }
 
-pub fn view_rollback(back: &Arc<Rollback>) -> String {
-  match &**back {
-    Rollback::Nil => {
-      return String::new();
+impl StateTree {
+  pub fn new(leafs: Vec<(Name, crypto::Hash)>) -> Self {
+    let (names, leafs) = leafs.into_iter().unzip();
+    StateTree { names, tree: crypto::MerkleTree::new(leafs) }
+  }
+
+  pub fn root(&self) -> crypto::Hash {
+    self.tree.root()
+  }
+
+  pub fn len(&self) -> usize {
+    self.names.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.names.is_empty()
+  }
+
+  /// Position of the leaf of `name`.
+  pub fn index(&self, name: &Name) -> Option<usize> {
+    self.names.binary_search_by_key(&**name, |name| **name).ok()
+  }
+
+  /// Siblings of the path from the leaf at `index` up to the root, as by `crypto::merkle_proof`.
+  pub fn proof(&self, index: usize) -> Vec<crypto::Hash> {
+    self.tree.proof(index)
+  }
+
+  // Sets the leaves of some names. Changed leaves only rehash their paths, while new names
+  // shift the leaves after them, so these are rehashed from the first new name on.
+  fn update(&mut self, mut leafs: Vec<(Name, crypto::Hash)>) {
+    leafs.sort_unstable_by_key(|(name, _)| **name);
+    let mut added = vec![];
+    for (name, leaf) in leafs {
+      match self.index(&name) {
+        Some(index) => self.tree.set(index, leaf),
+        None => added.push((name, leaf)),
+      }
     }
-    Rollback::Cons { keep, life, head, tail } => {
-      return format!("[{:x} {}] {}", keep, head, view_rollback(tail));
+    if let Some((first, _)) = added.first() {
+      let from = self.names.partition_point(|name| **name < **first);
+      let moved = self.names.split_off(from).into_iter().zip(self.tree.leaves()[from ..].iter().cloned());
+      let mut merged: Vec<(Name, crypto::Hash)> = moved.chain(added).collect();
+      merged.sort_unstable_by_key(|(name, _)| **name);
+      let (names, leafs): (Vec<Name>, Vec<crypto::Hash>) = merged.into_iter().unzip();
+      self.names.extend(names);
+      self.tree.replace_from(from, leafs);
+    }
+  }
+}
+
+impl Rollback {
+  pub fn new(heaps: u64, spacing: u64) -> Self {
+    assert!(heaps >= 2, "The rollback ring needs at least 2 heaps.");
+    assert!(spacing >= 1, "The rollback spacing must be at least 1 tick.");
+    Rollback { ring: vec![0; heaps as usize], head: 0, size: 0, spacing }
+  }
+
+  pub fn len(&self) -> usize {
+    self.size
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.size == 0
+  }
+
+  pub fn is_full(&self) -> bool {
+    self.size == self.ring.len()
+  }
+
+  pub fn get_spacing(&self) -> u64 {
+    self.spacing
+  }
+
+  // The heap index of the `nth` oldest snapshot
+  fn slot(&self, nth: usize) -> u64 {
+    self.ring[(self.head + nth) % self.ring.len()]
+  }
+
+  // The heap indices of the snapshots, from the newest to the oldest
+  pub fn iter(&self) -> impl DoubleEndedIterator<Item = u64> + '_ {
+    (0 .. self.size).rev().map(move |nth| self.slot(nth))
+  }
+
+  pub fn newest(&self) -> Option<u64> {
+    self.iter().next()
+  }
+
+  // Adds the newest snapshot. The ring must not be full.
+  fn push(&mut self, index: u64) {
+    debug_assert!(!self.is_full());
+    let slot = (self.head + self.size) % self.ring.len();
+    self.ring[slot] = index;
+    self.size += 1;
+  }
+
+  // Removes the newest snapshot
+  fn pop(&mut self) -> Option<u64> {
+    let newest = self.newest()?;
+    self.size -= 1;
+    Some(newest)
+  }
+
+  // Removes the second oldest snapshot, which the oldest one must absorb. Returns the heap
+  // indices of both.
+  fn merge(&mut self) -> Option<(u64, u64)> {
+    if self.size < 2 {
+      return None;
     }
+    let oldest = self.slot(0);
+    let absorbed = self.slot(1);
+    self.head = (self.head + 1) % self.ring.len();
+    self.ring[self.head] = oldest;
+    self.size -= 1;
+    Some((oldest, absorbed))
+  }
+
+  fn clear(&mut self) {
+    self.head = 0;
+    self.size = 0;
   }
 }
 
+pub fn view_rollback(back: &Rollback) -> String {
+  back.iter().map(|head| format!("{}", head)).collect::<Vec<_>>().join(" ")
+}
+
 
 // Constructors
 // ------------
@@ -2697,6 +3859,9 @@ pub fn alloc(rt: &mut Runtime, arity: u64) -> Loc {
         if has_space {
           rt.set_next(rt.get_next() + arity);
           rt.set_size(rt.get_size() + arity);
+          if let Some(profile) = &mut rt.profile {
+            profile.cells += arity;
+          }
           //println!("{}", show_memo(rt));
           for i in 0 .. arity {
             rt.write(index + i, RawCell(NIL * TAG_SHL)); // millions perished for forgetting this line
@@ -3045,34 +4210,300 @@ pub fn create_term(rt: &mut Runtime, term: &Term, loc: Loc, vars_data: &mut Name
         Ok(Fun(*name, node))
       }
     }
-    Term::Ctr { name, args } => {
-      let expected = rt.get_arity(name)
-        .ok_or_else(|| RuntimeError::CtrOrFunNotDefined { name: *name })?
-        as usize;
-      if args.len() != expected {
-        Err(RuntimeError::ArityMismatch { name: *name, expected, got: args.len() })
-      } else {
-        let size = args.len() as u64;
-        let node = alloc(rt, size);
-        for (i, arg) in args.iter().enumerate() {
-          let arg_lnk = create_term(rt, arg, node + i as u64, vars_data)?;
-          link(rt, node + i as u64, arg_lnk);
+    Term::Ctr { name, args } => {
+      let expected = rt.get_arity(name)
+        .ok_or_else(|| RuntimeError::CtrOrFunNotDefined { name: *name })?
+        as usize;
+      if args.len() != expected {
+        Err(RuntimeError::ArityMismatch { name: *name, expected, got: args.len() })
+      } else {
+        let size = args.len() as u64;
+        let node = alloc(rt, size);
+        for (i, arg) in args.iter().enumerate() {
+          let arg_lnk = create_term(rt, arg, node + i as u64, vars_data)?;
+          link(rt, node + i as u64, arg_lnk);
+        }
+        Ok(Ctr(*name, node))
+      }
+    }
+    Term::Num { numb } => {
+      Ok(Num(**numb))
+    }
+    Term::Op2 { oper, val0, val1 } => {
+      let node = alloc(rt, 2);
+      let val0 = create_term(rt, val0, node + 0, vars_data)?;
+      link(rt, node + 0, val0);
+      let val1 = create_term(rt, val1, node + 1, vars_data)?;
+      link(rt, node + 1, val1);
+      Ok(Op2(*oper as u128, node))
+    }
+  }
+}
+
+// Nested matches
+// --------------
+
+// The shape matched by a left-hand side argument of a nested rule.
+#[derive(Clone, Copy, PartialEq)]
+enum Shape {
+  Ctr(Name, usize),
+  Num(U120),
+  Any,
+}
+
+// Is this a pattern `compile_func` could match on, if it was flattened?
+fn is_pattern(term: &Term) -> bool {
+  match term {
+    Term::Var { .. } | Term::Num { .. } => true,
+    Term::Ctr { args, .. } => args.iter().all(is_pattern),
+    _ => false,
+  }
+}
+
+// Does this rule match on a field of a constructor?
+fn is_nested_rule(rule: &Rule) -> bool {
+  if let Term::Fun { args, .. } = &rule.lhs {
+    args.iter().any(|arg| match arg {
+      Term::Ctr { args, .. } => args.iter().any(|field| !matches!(field, Term::Var { .. })),
+      _ => false,
+    })
+  } else {
+    false
+  }
+}
+
+// Collects every name appearing in a term, bound or free.
+fn collect_names(term: &Term, names: &mut HashSet<Name>) {
+  match term {
+    Term::Var { name } => {
+      names.insert(*name);
+    }
+    Term::Dup { nam0, nam1, expr, body } => {
+      names.insert(*nam0);
+      names.insert(*nam1);
+      collect_names(expr, names);
+      collect_names(body, names);
+    }
+    Term::Lam { name, body } => {
+      names.insert(*name);
+      collect_names(body, names);
+    }
+    Term::App { func, argm } => {
+      collect_names(func, names);
+      collect_names(argm, names);
+    }
+    Term::Ctr { args, .. } | Term::Fun { args, .. } => {
+      for arg in args {
+        collect_names(arg, names);
+      }
+    }
+    Term::Num { .. } => {}
+    Term::Op2 { val0, val1, .. } => {
+      collect_names(val0, names);
+      collect_names(val1, names);
+    }
+  }
+}
+
+// Replaces the free occurrences of the variable `name` by `value`.
+fn replace_var(term: &Term, name: Name, value: &Term) -> Term {
+  match term {
+    Term::Var { name: var_name } => {
+      if *var_name == name { value.clone() } else { term.clone() }
+    }
+    Term::Dup { nam0, nam1, expr, body } => {
+      let expr = Box::new(replace_var(expr, name, value));
+      let body = if *nam0 == name || *nam1 == name { body.clone() } else { Box::new(replace_var(body, name, value)) };
+      Term::Dup { nam0: *nam0, nam1: *nam1, expr, body }
+    }
+    Term::Lam { name: lam_name, body } => {
+      let body = if *lam_name == name { body.clone() } else { Box::new(replace_var(body, name, value)) };
+      Term::Lam { name: *lam_name, body }
+    }
+    Term::App { func, argm } => {
+      let func = Box::new(replace_var(func, name, value));
+      let argm = Box::new(replace_var(argm, name, value));
+      Term::App { func, argm }
+    }
+    Term::Ctr { name: ctr_name, args } => {
+      let args = args.iter().map(|arg| replace_var(arg, name, value)).collect();
+      Term::Ctr { name: *ctr_name, args }
+    }
+    Term::Fun { name: fun_name, args } => {
+      let args = args.iter().map(|arg| replace_var(arg, name, value)).collect();
+      Term::Fun { name: *fun_name, args }
+    }
+    Term::Num { .. } => term.clone(),
+    Term::Op2 { oper, val0, val1 } => {
+      let val0 = Box::new(replace_var(val0, name, value));
+      let val1 = Box::new(replace_var(val1, name, value));
+      Term::Op2 { oper: *oper, val0, val1 }
+    }
+  }
+}
+
+// Generates variable names that don't clash with the ones of the function.
+struct FreshVars {
+  used: HashSet<Name>,
+  count: u64,
+}
+
+impl FreshVars {
+  fn next(&mut self) -> Name {
+    loop {
+      let name = Name::from_str_unsafe(&format!("_{}", self.count));
+      self.count += 1;
+      if !self.used.contains(&name) {
+        return name;
+      }
+    }
+  }
+}
+
+// Specializes a rule to the arguments matched by `shapes`, as a rule of the
+// auxiliary function `aux`. Returns `None` if it can't match these shapes.
+fn specialize_rule(rule: &Rule, shapes: &[Shape], aux: Name, fresh: &mut FreshVars) -> Option<Rule> {
+  let args = if let Term::Fun { args, .. } = &rule.lhs { args } else { return None };
+  if args.len() != shapes.len() {
+    return None;
+  }
+  let mut body = rule.rhs.clone();
+  let mut aux_args = Vec::new();
+  for (arg, shape) in args.iter().zip(shapes) {
+    match (shape, arg) {
+      (Shape::Ctr(ctr, arity), Term::Ctr { name, args }) if name == ctr && args.len() == *arity => {
+        aux_args.extend(args.iter().cloned());
+      }
+      (Shape::Ctr(ctr, arity), Term::Var { name }) => {
+        // A variable matches the constructor too: rebuild it from its fields
+        if name.is_none() {
+          aux_args.extend((0 .. *arity).map(|_| Term::Var { name: Name::NONE }));
+        } else {
+          let fields: Vec<Term> = (0 .. *arity).map(|_| Term::Var { name: fresh.next() }).collect();
+          body = replace_var(&body, *name, &Term::Ctr { name: *ctr, args: fields.clone() });
+          aux_args.extend(fields);
         }
-        Ok(Ctr(*name, node))
+      }
+      (Shape::Num(numb), Term::Num { numb: arg_numb }) if numb == arg_numb => {}
+      (Shape::Num(numb), Term::Var { name }) => {
+        if !name.is_none() {
+          body = replace_var(&body, *name, &Term::Num { numb: *numb });
+        }
+      }
+      (Shape::Any, arg) => {
+        aux_args.push(arg.clone());
+      }
+      _ => {
+        return None;
       }
     }
-    Term::Num { numb } => {
-      Ok(Num(**numb))
+  }
+  Some(Rule { lhs: Term::Fun { name: aux, args: aux_args }, rhs: body })
+}
+
+// Does every value matched by this rule also match `shapes`?
+fn is_covered(rule: &Rule, shapes: &[Shape]) -> bool {
+  let args = if let Term::Fun { args, .. } = &rule.lhs { args } else { return false };
+  args.iter().zip(shapes).all(|(arg, shape)| match (shape, arg) {
+    (Shape::Ctr(ctr, _), Term::Ctr { name, .. }) => name == ctr,
+    (Shape::Num(numb), Term::Num { numb: arg_numb }) => numb == arg_numb,
+    (Shape::Any, _) => true,
+    _ => false,
+  })
+}
+
+// Replaces the nested rule at `index` by a flat rule that calls `aux`, moving
+// it and every later rule that could match the same values into `aux`.
+fn split_rules(rules: &[Rule], index: usize, aux: Name, fresh: &mut FreshVars) -> (Vec<Rule>, Vec<Rule>) {
+  let rule = &rules[index];
+  let (name, args) = if let Term::Fun { name, args } = &rule.lhs { (*name, args) } else { unreachable!() };
+  let shapes: Vec<Shape> = args.iter().map(|arg| match arg {
+    Term::Ctr { name, args } => Shape::Ctr(*name, args.len()),
+    Term::Num { numb } => Shape::Num(*numb),
+    _ => Shape::Any,
+  }).collect();
+
+  // (F {A (B x) y} #0 z) = body
+  // ---------------------------------------
+  // (F {A a b} #0 c) = (F_0 a b c)
+  // (F_0 (B x) y z) = body
+  let mut flat_args = Vec::new();
+  let mut aux_args = Vec::new();
+  for shape in &shapes {
+    match shape {
+      Shape::Ctr(ctr, arity) => {
+        let fields: Vec<Term> = (0 .. *arity).map(|_| Term::Var { name: fresh.next() }).collect();
+        flat_args.push(Term::Ctr { name: *ctr, args: fields.clone() });
+        aux_args.extend(fields);
+      }
+      Shape::Num(numb) => {
+        flat_args.push(Term::Num { numb: *numb });
+      }
+      Shape::Any => {
+        let var = Term::Var { name: fresh.next() };
+        flat_args.push(var.clone());
+        aux_args.push(var);
+      }
     }
-    Term::Op2 { oper, val0, val1 } => {
-      let node = alloc(rt, 2);
-      let val0 = create_term(rt, val0, node + 0, vars_data)?;
-      link(rt, node + 0, val0);
-      let val1 = create_term(rt, val1, node + 1, vars_data)?;
-      link(rt, node + 1, val1);
-      Ok(Op2(*oper as u128, node))
+  }
+  let flat_rule = Rule {
+    lhs: Term::Fun { name, args: flat_args },
+    rhs: Term::Fun { name: aux, args: aux_args },
+  };
+
+  let mut func_rules = rules[.. index].to_vec();
+  func_rules.push(flat_rule);
+  let mut aux_rules = Vec::new();
+  for rule in &rules[index ..] {
+    if let Some(aux_rule) = specialize_rule(rule, &shapes, aux, fresh) {
+      aux_rules.push(aux_rule);
+      // Rules that also match other values must stay on the function
+      if !is_covered(rule, &shapes) {
+        func_rules.push(rule.clone());
+      }
+    } else {
+      func_rules.push(rule.clone());
+    }
+  }
+  (func_rules, aux_rules)
+}
+
+/// Desugars the nested pattern matches of a function into auxiliary functions
+/// that `compile_func` can handle. Returns the flattened function, followed by
+/// its auxiliary functions, named `{name}_0`, `{name}_1`, ... so that they live
+/// on the same namespace. Functions without nested matches are returned as is.
+pub fn flatten_func(name: Name, func: &Func) -> Result<Vec<(Name, Func)>, RuntimeError> {
+  let is_flattenable = func.rules.iter().all(|rule| match &rule.lhs {
+    Term::Fun { args, .. } => args.iter().all(is_pattern),
+    _ => false,
+  });
+  if !is_flattenable {
+    // Leaves the error reporting to `compile_func`
+    return Ok(vec![(name, func.clone())]);
+  }
+
+  let mut used = HashSet::new();
+  for rule in &func.rules {
+    collect_names(&rule.lhs, &mut used);
+    collect_names(&rule.rhs, &mut used);
+  }
+  let mut fresh = FreshVars { used, count: 0 };
+
+  let mut funcs = vec![(name, func.rules.clone())];
+  let mut current = 0;
+  while current < funcs.len() {
+    if let Some(index) = funcs[current].1.iter().position(is_nested_rule) {
+      let aux = format!("{}_{}", name, funcs.len() - 1);
+      let aux = Name::from_str(&aux).map_err(|_| RuntimeError::DefinitionError(DefinitionError::AuxNameTooBig { name }))?;
+      let (func_rules, aux_rules) = split_rules(&funcs[current].1, index, aux, &mut fresh);
+      funcs[current].1 = func_rules;
+      funcs.push((aux, aux_rules));
+    } else {
+      current += 1;
     }
   }
+
+  Ok(funcs.into_iter().map(|(name, rules)| (name, Func { rules })).collect())
 }
 
 /// Given a Func (a vector of rules, lhs/rhs pairs), builds the CompFunc object
@@ -3217,6 +4648,14 @@ pub fn create_fun(rt: &mut Runtime, fun: Name, args: &[RawCell]) -> RawCell {
   Fun(fun, node)
 }
 
+pub fn create_ctr(rt: &mut Runtime, ctr: Name, args: &[RawCell]) -> RawCell {
+  let node = alloc(rt, args.len() as u64);
+  for i in 0 .. args.len() {
+    link(rt, node + (i as u64), args[i]);
+  }
+  Ctr(ctr, node)
+}
+
 pub fn alloc_lnk(rt: &mut Runtime, term: RawCell) -> Loc {
   let loc = alloc(rt, 1);
   link(rt, loc, term);
@@ -3244,6 +4683,28 @@ pub fn subst(rt: &mut Runtime, lnk: RawCell, val: RawCell) {
   }
 }
 
+/// Charges the mana of a rewrite, recording it if tracing or profiling is
+/// enabled. `index` is the matched rule, for `FunCtr`.
+#[inline(always)]
+fn charge(rt: &mut Runtime, rule: Rewrite, name: Option<Name>, index: Option<u64>, mana: u64) {
+  rt.set_mana(rt.get_mana() + mana);
+  if let Some(trace) = &mut rt.trace {
+    if trace.len() < MAX_TRACE_STEPS {
+      trace.push(TraceStep { rule, name, mana });
+    } else if let Some(truncated) = trace.get_mut(MAX_TRACE_STEPS) {
+      truncated.mana += mana;
+    } else {
+      trace.push(TraceStep { rule: Rewrite::Truncated, name: None, mana });
+    }
+  }
+  if rt.profile.is_some() {
+    let dups = rt.get_dups();
+    if let Some(profile) = &mut rt.profile {
+      profile.rewrite((rule, name, index), mana, dups);
+    }
+  }
+}
+
 // TODO: document
 pub fn reduce(rt: &mut Runtime, root: Loc, mana: u64) -> Result<RawCell, RuntimeError> {
   let mut vars_data: NameMap<Vec<RawCell>> = init_name_map();
@@ -3324,7 +4785,7 @@ pub fn reduce(rt: &mut Runtime, root: Loc, mana: u64) -> Result<RawCell, Runtime
           // body
           if get_tag(arg0) == LAM {
             //println!("app-lam");
-            rt.set_mana(rt.get_mana() + AppLamMana());
+            charge(rt, Rewrite::AppLam, None, None, AppLamMana());
             rt.set_rwts(rt.get_rwts() + 1);
             subst(rt, ask_arg(rt, arg0, 0), ask_arg(rt, term, 1));
             let _done = link(rt, host, ask_arg(rt, arg0, 1));
@@ -3338,7 +4799,7 @@ pub fn reduce(rt: &mut Runtime, root: Loc, mana: u64) -> Result<RawCell, Runtime
           // {(a x0) (b x1)}
           } else if get_tag(arg0) == SUP {
             //println!("app-sup");
-            rt.set_mana(rt.get_mana() + AppSupMana());
+            charge(rt, Rewrite::AppSup, None, None, AppSupMana());
             rt.set_rwts(rt.get_rwts() + 1);
             let app0 = get_loc(term, 0);
             let app1 = get_loc(arg0, 0);
@@ -3365,7 +4826,7 @@ pub fn reduce(rt: &mut Runtime, root: Loc, mana: u64) -> Result<RawCell, Runtime
           // x <- {x0 x1}
           if get_tag(arg0) == LAM {
             //println!("dup-lam");
-            rt.set_mana(rt.get_mana() + DupLamMana());
+            charge(rt, Rewrite::DupLam, None, None, DupLamMana());
             rt.set_rwts(rt.get_rwts() + 1);
             let let0 = get_loc(term, 0);
             let par0 = get_loc(arg0, 0);
@@ -3393,7 +4854,7 @@ pub fn reduce(rt: &mut Runtime, root: Loc, mana: u64) -> Result<RawCell, Runtime
           } else if get_tag(arg0) == SUP {
             if get_ext(term) == get_ext(arg0) {
               //println!("dup-sup-e");
-              rt.set_mana(rt.get_mana() + DupSupMana());
+              charge(rt, Rewrite::DupSup, None, None, DupSupMana());
               rt.set_rwts(rt.get_rwts() + 1);
               subst(rt, ask_arg(rt, term, 0), ask_arg(rt, arg0, 0));
               subst(rt, ask_arg(rt, term, 1), ask_arg(rt, arg0, 1));
@@ -3410,7 +4871,7 @@ pub fn reduce(rt: &mut Runtime, root: Loc, mana: u64) -> Result<RawCell, Runtime
             // dup xB yB = b
             } else {
               //println!("dup-sup-d");
-              rt.set_mana(rt.get_mana() + DupDupMana());
+              charge(rt, Rewrite::DupDup, None, None, DupDupMana());
               rt.set_rwts(rt.get_rwts() + 1);
               let par0 = alloc(rt, 2);
               let let0 = get_loc(term, 0);
@@ -3436,7 +4897,7 @@ pub fn reduce(rt: &mut Runtime, root: Loc, mana: u64) -> Result<RawCell, Runtime
           // ~
           } else if get_tag(arg0) == NUM {
             //println!("dup-num");
-            rt.set_mana(rt.get_mana() + DupNumMana());
+            charge(rt, Rewrite::DupNum, None, None, DupNumMana());
             rt.set_rwts(rt.get_rwts() + 1);
             subst(rt, ask_arg(rt, term, 0), arg0);
             subst(rt, ask_arg(rt, term, 1), arg0);
@@ -3456,7 +4917,7 @@ pub fn reduce(rt: &mut Runtime, root: Loc, mana: u64) -> Result<RawCell, Runtime
             let func = get_ext(arg0);
             let name = Name::new_unsafe(func);
             let arit = rt.get_arity(&name).ok_or_else(|| RuntimeError::CtrOrFunNotDefined { name })?;
-            rt.set_mana(rt.get_mana() + DupCtrMana(arit));
+            charge(rt, Rewrite::DupCtr, Some(name), None, DupCtrMana(arit));
             rt.set_rwts(rt.get_rwts() + 1);
             if arit == 0 {
               subst(rt, ask_arg(rt, term, 0), Ctr(name, Loc(0)));
@@ -3489,7 +4950,7 @@ pub fn reduce(rt: &mut Runtime, root: Loc, mana: u64) -> Result<RawCell, Runtime
           // y <- *
           } else if get_tag(arg0) == ERA {
             //println!("dup-era");
-            rt.set_mana(rt.get_mana() + DupEraMana());
+            charge(rt, Rewrite::DupEra, None, None, DupEraMana());
             rt.set_rwts(rt.get_rwts() + 1);
             subst(rt, ask_arg(rt, term, 0), Era());
             subst(rt, ask_arg(rt, term, 1), Era());
@@ -3513,7 +4974,7 @@ pub fn reduce(rt: &mut Runtime, root: Loc, mana: u64) -> Result<RawCell, Runtime
             if op == Oper::Div && *b_u == 0 {
               return Err(RuntimeError::DivisionByZero)
             }
-            rt.set_mana(rt.get_mana() + Op2NumMana());
+            charge(rt, Rewrite::Op2Num, None, None, Op2NumMana());
             let res = match op {
               Oper::Add => *a_u.wrapping_add(b_u),
               Oper::Sub => *a_u.wrapping_sub(b_u),
@@ -3541,7 +5002,7 @@ pub fn reduce(rt: &mut Runtime, root: Loc, mana: u64) -> Result<RawCell, Runtime
           // {(+ a0 b0) (+ a1 b1)}
           } else if get_tag(arg0) == SUP {
             //println!("op2-sup-0");
-            rt.set_mana(rt.get_mana() + Op2SupMana());
+            charge(rt, Rewrite::Op2Sup, None, None, Op2SupMana());
             rt.set_rwts(rt.get_rwts() + 1);
             let op20 = get_loc(term, 0);
             let op21 = get_loc(arg0, 0);
@@ -3562,7 +5023,7 @@ pub fn reduce(rt: &mut Runtime, root: Loc, mana: u64) -> Result<RawCell, Runtime
           // {(+ a0 b0) (+ a1 b1)}
           } else if get_tag(arg1) == SUP {
             //println!("op2-sup-1");
-            rt.set_mana(rt.get_mana() + Op2SupMana());
+            charge(rt, Rewrite::Op2Sup, None, None, Op2SupMana());
             rt.set_rwts(rt.get_rwts() + 1);
             let op20 = get_loc(term, 0);
             let op21 = get_loc(arg1, 0);
@@ -3595,7 +5056,7 @@ pub fn reduce(rt: &mut Runtime, root: Loc, mana: u64) -> Result<RawCell, Runtime
                 let funx = get_ext(term);
                 let name = Name::new_unsafe(funx);
                 let arit = rt.get_arity(&name).ok_or_else(|| RuntimeError::CtrOrFunNotDefined { name })?;
-                rt.set_mana(rt.get_mana() + FunSupMana(arit));
+                charge(rt, Rewrite::FunSup, Some(name), None, FunSupMana(arit));
                 rt.set_rwts(rt.get_rwts() + 1);
                 let argn = ask_arg(rt, term, *idx);
                 let fun0 = get_loc(term, 0);
@@ -3621,7 +5082,7 @@ pub fn reduce(rt: &mut Runtime, root: Loc, mana: u64) -> Result<RawCell, Runtime
               }
             }
             // For each rule condition vector
-            for rule in &func.rules {
+            for (index, rule) in func.rules.iter().enumerate() {
               // Check if the rule matches
               let mut matched = true;
               //println!("- matching rule");
@@ -3658,7 +5119,7 @@ pub fn reduce(rt: &mut Runtime, root: Loc, mana: u64) -> Result<RawCell, Runtime
                 //println!("fun-ctr");
                 //println!("- matched");
                 // Increments the gas count
-                rt.set_mana(rt.get_mana() + FunCtrMana(&rule.body));
+                charge(rt, Rewrite::FunCtr, Some(Name::new_unsafe(get_ext(term))), Some(index as u64), FunCtrMana(&rule.body));
                 rt.set_rwts(rt.get_rwts() + 1);
                 // Gathers matched variables
                 //let mut vars = vec![None; 16]; // FIXME: pre-alloc statically
@@ -4096,10 +5557,17 @@ fn show_runtime_error(err: RuntimeError) -> String {
         EffectFailure::NoSuchState { state: addr } => format!("Tried to read state of '{}' but did not exist.", show_addr(addr)),
         EffectFailure::InvalidCallArg { caller, callee, arg } => {
           let pos = get_val(arg);
-          format!("'{}' tried to call '{}' with invalid argument '{}'.", show_addr(caller), show_addr(callee), show_ptr(arg))
+          format!("'{}' tried to call '{}' with invalid argument '{}'. Only constructors and numbers can be passed.", show_addr(caller), show_addr(callee), show_ptr(arg))
         },
+        EffectFailure::CallArgTooDeep { caller, callee } => format!("'{}' tried to call '{}' with an argument nested deeper than {} constructors.", show_addr(caller), show_addr(callee), MAX_CALL_ARG_DEPTH),
+        EffectFailure::CallArgTooBig { caller, callee } => format!("'{}' tried to call '{}' with an argument larger than {} cells.", show_addr(caller), show_addr(callee), MAX_CALL_ARG_SIZE),
         EffectFailure::InvalidIOCtr { name } => format!("'{}' is not an IO constructor.", name),
         EffectFailure::InvalidIONonCtr { ptr } => format!("'{}' is not an IO term.", show_ptr(ptr)),
+        EffectFailure::InvalidSchedTick { tick } => format!("Can't schedule a call for tick {}, which is not in the future.", tick),
+        EffectFailure::SchedTickFull { tick } => format!("Can't schedule a call for tick {}, which already has {} calls.", tick, MAX_TICK_SCHEDS),
+        EffectFailure::TooManyLogs { subject } => format!("'{}' tried to log more than {} terms in a statement.", show_addr(subject), MAX_LOGS),
+        EffectFailure::LogsTooBig { subject } => format!("'{}' tried to log more than {} cells in a statement.", show_addr(subject), MAX_LOGS_SIZE),
+        EffectFailure::CallDepthExceeded { caller, callee } => format!("'{}' tried to call '{}' beyond the maximum call depth of {}.", show_addr(caller), show_addr(callee), MAX_CALL_DEPTH),
     }
   RuntimeError::DefinitionError(def_error) =>
       match def_error {
@@ -4112,6 +5580,7 @@ fn show_runtime_error(err: RuntimeError) -> String {
         DefinitionError::VarIsNotUsed { name, rule_index } => format!("'{}' is not used in rule {}.", name, rule_index),
         DefinitionError::NestedMatch { rule_index } => format!("Nested pattern matching is not supported (at rule {}).", rule_index),
         DefinitionError::UnsupportedMatch { rule_index } => format!("Unsupported match in rule {}. Only constructor, variable and number pattern matching are supported.", rule_index),
+        DefinitionError::AuxNameTooBig { name } => format!("Name '{}' is too long to generate the auxiliary functions of its nested matches.", name),
       }
   }
 }
@@ -4452,10 +5921,12 @@ pub fn read_char(code: &str, chr: char) -> ParseResult<()> {
   if head(code) == chr {
     Ok((tail(code), ()))
   } else {
-    Err(ParseErr {
-      erro: format!("Expected '{}', found '{}'. Context:\n\x1b[2m{}\x1b[0m", chr, head(code), code.chars().take(64).collect::<String>()),
-      code: code.to_string(),
-    })
+    let erro = if code.is_empty() {
+      format!("Expected '{}', found end of file.", chr)
+    } else {
+      format!("Expected '{}', found '{}'.", chr, head(code))
+    };
+    Err(ParseErr::new(code, erro))
   }
 }
 
@@ -4489,10 +5960,31 @@ where
         }
       }
       (code, numb)
-    } else {
+    } else if head(code) == 'b' {
+      code = tail(code);
       let mut numb = 0;
+      let mut code = code;
+      let mut digits = 0;
+      while head(code) == '0' || head(code) == '1' {
+        numb = numb * 2 + head(code) as u128 - 0x30;
+        code = tail(code);
+        digits += 1;
+        if digits > 120 {
+          return Err(ParseErr::new(code, "Binary number with more than 120 digits"))
+        }
+      }
+      if digits == 0 {
+        return Err(ParseErr::new(code, "Expected binary digits after '#b'"))
+      }
+      (code, numb)
+    } else {
+      let mut numb: u128 = 0;
       while head(code) >= '0' && head(code) <= '9' {
-        numb = numb * 10 + head(code) as u128 - 0x30;
+        let digit = head(code) as u128 - 0x30;
+        numb = match numb.checked_mul(10).and_then(|numb| numb.checked_add(digit)) {
+          Some(numb) => numb,
+          None => return Err(ParseErr::new(code, "Decimal number does not fit in 120 bits")),
+        };
         code = tail(code);
       }
       (code, numb)
@@ -4508,41 +6000,31 @@ pub fn read_name(code: &str) -> ParseResult<Name> {
   if head(code) == '~' {
     return Ok((tail(code), Name::NONE));
   } else {
+    // errors below point at the start of the name
+    let start = code;
     let mut code = code;
     while is_name_char(head(code)) {
       name.push(head(code));
       code = tail(code);
     }
     if name.is_empty() {
-      return Err(ParseErr {
-        code: code.to_string(),
-        erro: format!("Expected identifier, found `{}`.", head(code))
-      });
+      return Err(ParseErr::new(code, format!("Expected identifier, found `{}`.", head(code))));
     }
     if name == "ask" || name == "dup" || name == "let" {
-      return Err(ParseErr {
-        code: code.to_string(),
-        erro: format!("Use of the keyword {} as a name for a term in `{}`.", name, code)
-      });
+      return Err(ParseErr::new(start, format!("Use of the keyword {} as a name for a term.", name)));
     }
     if ('0'..='9').contains(&name.chars().nth(0).unwrap_or(' ')) {
       // In most cases, an user writing 0-9 probably wants to make a number, not a name, so, to
       // avoid mistakes, we disable this syntax by default. But since names CAN start with 0-9 on
       // Kindelia, we must create an alternative, explicit way to parse "numeric names".
-      return Err(ParseErr {
-        code: code.to_string(),
-        erro: format!("Number must start with #, but '{}' doesn't.", name),
-      });
+      return Err(ParseErr::new(start, format!("Number must start with #, but '{}' doesn't.", name)));
     }
     let name = Name::from_str(&name);
     let name =
       match name {
         Ok(name) => name,
         Err(msg) => {
-          return Err(ParseErr {
-            code: code.to_string(),
-            erro: format!("Identifier too long: {}", msg),
-          });
+          return Err(ParseErr::new(start, format!("Identifier too long: {}", msg)));
         }
       };
     return Ok((code, name));
@@ -4563,7 +6045,10 @@ pub fn read_hex(code: &str) -> ParseResult<Vec<u8>> {
 pub fn read_until<A>(code: &str, stop: char, read: fn(&str) -> ParseResult<A>) -> ParseResult<Vec<A>> {
   let mut elems = Vec::new();
   let mut code = code;
-  while code.len() > 0 && head(skip(code)) != stop {
+  while head(skip(code)) != stop {
+    if skip(code).is_empty() {
+      return Err(ParseErr::new("", format!("Expected '{}', found end of file.", stop)));
+    }
     let (new_code, elem) = read(code)?;
     code = new_code;
     elems.push(elem);
@@ -4572,9 +6057,69 @@ pub fn read_until<A>(code: &str, stop: char, read: fn(&str) -> ParseResult<A>) -
   return Ok((code, elems));
 }
 
+// Reads a string literal, as in `"hello world"`.
+pub fn read_string(code: &str) -> ParseResult<Term> {
+  let (code, text) = read_string_text(code)?;
+  Ok((code, string_to_term(&text)))
+}
+
+// Reads the text of a string literal, without desugaring it.
+pub fn read_string_text(code: &str) -> ParseResult<String> {
+  let start = skip(code);
+  let (mut code, ()) = read_char(start, '"')?;
+  let mut text = String::new();
+  loop {
+    match head(code) {
+      '"' => break,
+      '\0' if code.is_empty() => {
+        return Err(ParseErr::new(start, "Unterminated string literal."));
+      }
+      '\0' => {
+        return Err(ParseErr::new(code, "Strings can't contain null characters."));
+      }
+      '\\' => {
+        let chr = match nth(code, 1) {
+          'n'  => '\n',
+          'r'  => '\r',
+          't'  => '\t',
+          '"'  => '"',
+          '\\' => '\\',
+          chr  => {
+            return Err(ParseErr::new(tail(code), format!("Unknown escape sequence '\\{}'.", chr)));
+          }
+        };
+        text.push(chr);
+        code = drop(code, 2);
+      }
+      chr => {
+        text.push(chr);
+        code = tail(code);
+      }
+    }
+  }
+  Ok((tail(code), text))
+}
+
+/// Builds the `StrCons` list a string literal desugars to.
+pub fn string_to_term(text: &str) -> Term {
+  let mut term = Term::ctr(Name::new_unsafe(STR_NIL), vec![]);
+  for chunk in text.as_bytes().chunks(STR_CHUNK_BYTES).rev() {
+    let mut numb = 0;
+    for idx in 0 .. STR_CHUNK_BYTES {
+      numb = numb << 8 | *chunk.get(idx).unwrap_or(&0) as u128;
+    }
+    let numb = Term::num(U120::from_u128_unchecked(numb));
+    term = Term::ctr(Name::new_unsafe(STR_CONS), vec![numb, term]);
+  }
+  term
+}
+
 pub fn read_term(code: &str) -> ParseResult<Term> {
   let code = skip(code);
   match head(code) {
+    '"' => {
+      return read_string(code);
+    },
     '@' => {
       let code         = tail(code);
       let (code, name) = read_name(code)?;
@@ -4627,7 +6172,7 @@ pub fn read_term(code: &str) -> ParseResult<Term> {
         let term = Term::ctr(name, vals);
         return Ok((code, term));
       } else {
-        return Err(ParseErr { code: code.to_string(), erro: "Tuple too long".to_string() });
+        return Err(ParseErr::new(code, "Tuple too long"));
       }
     },
     '#' => {
@@ -4753,32 +6298,91 @@ pub fn read_func(code: &str) -> ParseResult<CompFunc> {
   let comp_func = compile_func(&func, false);
   match comp_func {
     Ok(func) => Ok((code, func)),
-    Err(def_err) => Err(ParseErr {
-      code: code.to_string(),
-      erro: show_runtime_error(def_err)
-    })
+    Err(def_err) => Err(ParseErr::new(code, show_runtime_error(def_err)))
   }
 }
 
+// Statements end at their last token: when there is no signature, the code
+// after the statement is returned as is, comments included.
 pub fn read_sign(code: &str) -> ParseResult<Option<crypto::Signature>> {
-  let code = skip(code);
-  if let ('s','i','g','n') = (nth(code,0), nth(code,1), nth(code,2), nth(code,3)) {
-    let code = drop(code,4);
+  let next = skip(code);
+  if let ('s','i','g','n') = (nth(next,0), nth(next,1), nth(next,2), nth(next,3)) {
+    let code = drop(next,4);
     let (code, unit) = read_char(code, '{')?;
     let (code, sign) = read_hex(code)?;
     let (code, unit) = read_char(code, '}')?;
     if sign.len() == 65 {
       return Ok((code, Some(crypto::Signature(sign.as_slice().try_into().unwrap())))); // TODO: remove unwrap
     } else {
-      return Err(ParseErr { 
-        code: code.to_string(), 
-        erro: "Wrong signature size".to_string()
-      });
+      return Err(ParseErr::new(code, "Wrong signature size"));
     }
   }
   return Ok((code, None));
 }
 
+// Reads the optional `nonce { #n }` of a `run` statement
+pub fn read_nonce(code: &str) -> ParseResult<Option<U120>> {
+  let next = skip(code);
+  if let ('n','o','n','c','e') = (nth(next,0), nth(next,1), nth(next,2), nth(next,3), nth(next,4)) {
+    let code = drop(next,5);
+    let (code, unit) = read_char(code, '{')?;
+    let (code, unit) = read_char(code, '#')?;
+    let (code, nonce) = read_numb(code)?;
+    let (code, unit) = read_char(code, '}')?;
+    return Ok((code, Some(nonce)));
+  }
+  return Ok((code, None));
+}
+
+// Reads the optional `until { #tick }` of a statement, the last tick of the
+// blocks that can run it
+pub fn read_expiry(code: &str) -> ParseResult<Option<u64>> {
+  let next = skip(code);
+  if let ('u','n','t','i','l') = (nth(next,0), nth(next,1), nth(next,2), nth(next,3), nth(next,4)) {
+    let code = drop(next,5);
+    let (code, unit) = read_char(code, '{')?;
+    let (code, unit) = read_char(code, '#')?;
+    let (code, until) = read_numb::<U120>(code)?;
+    let (code, unit) = read_char(code, '}')?;
+    let until = u64::try_from(*until).map_err(|_| ParseErr::new(code, "Expiry tick doesn't fit in 64 bits"))?;
+    return Ok((code, Some(until)));
+  }
+  return Ok((code, None));
+}
+
+// Reads the optional `<mana>` cap of a `run` statement
+pub fn read_mana(code: &str) -> ParseResult<Option<u64>> {
+  if head(code) == '<' {
+    let (code, mana) = read_numb::<U120>(tail(code))?;
+    let (code, unit) = read_char(code, '>')?;
+    let mana = u64::try_from(*mana).map_err(|_| ParseErr::new(code, "Mana cap doesn't fit in 64 bits"))?;
+    return Ok((code, Some(mana)));
+  }
+  return Ok((code, None));
+}
+
+// Reads the `{ owner }` block of `reg` and `own` statements
+fn read_ownr(code: &str) -> ParseResult<U120> {
+  let (code, unit) = read_char(code, '{')?;
+  let code = skip(code);
+  let (code, ownr) = match head(code) {
+    '#' => {
+      let code = tail(code);
+      read_numb(code)?
+    },
+    '\'' => {
+      let code = tail(code);
+      let (code, name) = read_name(code)?;
+      let (code, unit) = read_char(code, '\'')?;
+      let numb: U120 = name.into();
+      (code, numb)
+    },
+    _ => return Err(ParseErr::new(code, "Expected a number representation"))
+  };
+  let (code, unit) = read_char(code, '}')?;
+  return Ok((code, ownr));
+}
+
 pub fn read_statement(code: &str) -> ParseResult<Statement> {
   let code = skip(code);
   match (nth(code,0), nth(code,1), nth(code,2)) {
@@ -4789,9 +6393,9 @@ pub fn read_statement(code: &str) -> ParseResult<Statement> {
       let (code, args) = read_until(code, ')', read_name)?;
       let (code, unit) = read_char(code, '{')?;
       let (code, ruls) = read_until(code, '}', read_rule)?;
-      let code = skip(code);
-      let (code, init) = if let ('w','i','t','h') = (nth(code,0), nth(code,1), nth(code,2), nth(code,3)) {
-        let code = drop(code,4);
+      let next = skip(code);
+      let (code, init) = if let ('w','i','t','h') = (nth(next,0), nth(next,1), nth(next,2), nth(next,3)) {
+        let code = drop(next,4);
         let (code, unit) = read_char(code, '{')?;
         let (code, init) = read_term(code)?;
         let (code, unit) = read_char(code, '}')?;
@@ -4799,25 +6403,30 @@ pub fn read_statement(code: &str) -> ParseResult<Statement> {
       } else {
         (code, None)
       };
+      let (code, until) = read_expiry(code)?;
       let (code, sign) = read_sign(code)?;
       let func = Func { rules: ruls };
-      return Ok((code, Statement::Fun { name, args, func, init, sign }));
+      return Ok((code, Statement::Fun { name, args, func, init, until, sign }));
     }
     ('c','t','r') => {
       let code = drop(code,3);
       let (code, unit) = read_char(code, '{')?;
       let (code, name) = read_name(code)?;
       let (code, args) = read_until(code, '}', read_name)?;
+      let (code, until) = read_expiry(code)?;
       let (code, sign) = read_sign(code)?;
-      return Ok((code, Statement::Ctr { name, args, sign }));
+      return Ok((code, Statement::Ctr { name, args, until, sign }));
     }
     ('r','u','n') => {
       let code = drop(code,3);
+      let (code, mana) = read_mana(code)?;
       let (code, unit) = read_char(code, '{')?;
       let (code, expr) = read_term(code)?;
       let (code, unit) = read_char(code, '}')?;
+      let (code, nonce) = read_nonce(code)?;
+      let (code, until) = read_expiry(code)?;
       let (code, sign) = read_sign(code)?;
-      return Ok((code, Statement::Run { expr, sign  }));
+      return Ok((code, Statement::Run { expr, mana, nonce, until, sign }));
     }
     // reg Foo.Bar { #x123456 } sign { signature }
     ('r','e','g') => {
@@ -4828,28 +6437,31 @@ pub fn read_statement(code: &str) -> ParseResult<Statement> {
         } else {
           read_name(code)?
         };
-      let (code, unit) = read_char(code, '{')?;
-      let code = skip(code);
-      let (code, ownr) = match head(code) {
-        '#' => {
-          let code = tail(code);
-          read_numb(code)?
-        },
-        '\'' => {
-          let code = tail(code);
-          let (code, name) = read_name(code)?;
-          let (code, unit) = read_char(code, '\'')?;
-          let numb: U120 = name.into();
-          (code, numb)
-        },
-        _ => return Err(ParseErr::new(code, "Expected a number representation"))
-      };
-      let (code, unit) = read_char(code, '}')?;
+      let (code, ownr) = read_ownr(code)?;
+      let (code, until) = read_expiry(code)?;
+      let (code, sign) = read_sign(code)?;
+      return Ok((code, Statement::Reg { name, ownr, until, sign }));
+    }
+    // own Foo.Bar { #x123456 } nonce { #0 } sign { signature }
+    ('o','w','n') => {
+      let code = skip(drop(code, 3));
+      let (code, name) =
+        if nth(code, 0) == '{' {
+          (code, Name::EMPTY)
+        } else {
+          read_name(code)?
+        };
+      let (code, ownr) = read_ownr(code)?;
+      let (code, nonce) = read_nonce(code)?;
+      let (code, until) = read_expiry(code)?;
       let (code, sign) = read_sign(code)?;
-      return Ok((code, Statement::Reg { name, ownr, sign }));
+      return Ok((code, Statement::Own { name, ownr, nonce, until, sign }));
     }
     _ => {
-      return Err(ParseErr { code: code.to_string(),  erro: "Expected statement.".to_string() });
+      if code.starts_with("import") && !is_name_char(nth(code, 6)) {
+        return Err(ParseErr::new(code, "Imports need a file context, and can only be loaded at the top of a file."));
+      }
+      return Err(ParseErr::new(code, "Expected statement."));
     }
   }
 }
@@ -4858,18 +6470,204 @@ pub fn read_statements(code: &str) -> ParseResult<Vec<Statement>> {
   read_until(code, '\0', read_statement)
 }
 
+// Skips a statement that failed to parse, returning the code starting at the
+// next line that begins with a statement keyword.
+fn skip_statement(code: &str) -> &str {
+  for (idx, _) in code.match_indices('\n') {
+    let line = code[idx + 1 ..].trim_start_matches(|chr| chr == ' ' || chr == '\t' || chr == '\r');
+    let keywords = ["fun", "ctr", "run", "reg", "own", "import"];
+    if keywords.iter().any(|kw| line.starts_with(kw) && !is_name_char(nth(line, kw.len() as u128))) {
+      return line;
+    }
+  }
+  ""
+}
+
+/// Parses all statements of `code`. When a statement fails to parse, the
+/// parser resumes at the next statement, so every error found is returned.
+pub fn parse_statements(code: &str) -> Result<Vec<Statement>, Vec<ParseErr>> {
+  parse_statements_at(code, 0)
+}
+
+// Parses the statements of `source` from byte `start` on, keeping track of
+// the offset each one starts at, so that errors are placed in `source`.
+fn parse_statements_at(source: &str, start: usize) -> Result<Vec<Statement>, Vec<ParseErr>> {
+  let mut statements = Vec::new();
+  let mut errors = Vec::new();
+  let mut offset = start;
+  let mut code = &source[start ..];
+  loop {
+    let next = skip(code);
+    offset += code.len() - next.len();
+    code = next;
+    if code.is_empty() {
+      break;
+    }
+    let next = match read_statement(code) {
+      Ok((rest, statement)) => {
+        statements.push(statement);
+        rest
+      }
+      Err(err) => {
+        errors.push(err.place(code, offset));
+        skip_statement(code)
+      }
+    };
+    offset += code.len() - next.len();
+    code = next;
+  }
+  if errors.is_empty() {
+    Ok(statements)
+  } else {
+    Err(errors)
+  }
+}
+
+/// Renders parse errors with source snippets, as returned by `parse_code`.
+pub fn show_parse_errors(code: &str, errors: &[ParseErr]) -> String {
+  errors.iter().map(|err| err.show(code)).collect::<Vec<_>>().join("\n\n")
+}
+
 pub fn parse_code(code: &str) -> Result<Vec<Statement>, String> {
-  let statements = read_statements(code);
-  match statements {
-    Ok((code, statements)) => {
-      if code.is_empty() {
-        Ok(statements)
+  parse_statements(code).map_err(|errors| show_parse_errors(code, &errors))
+}
+
+// Imports
+// -------
+
+// Reads an `import "file.kdl"` directive, if there is one.
+pub fn read_import(code: &str) -> ParseResult<Option<String>> {
+  let code = skip(code);
+  if code.starts_with("import") && !is_name_char(nth(code, 6)) {
+    let (code, file) = read_string_text(drop(code, 6))?;
+    Ok((code, Some(file)))
+  } else {
+    Ok((code, None))
+  }
+}
+
+/// Parses the statements of a file, from byte `start` on, after its
+/// imports. An import found among them is just misplaced, as the file context
+/// is known.
+pub fn parse_file_statements(code: &str, start: usize) -> Result<Vec<Statement>, Vec<ParseErr>> {
+  parse_statements_at(code, start).map_err(|errors| {
+    errors.into_iter().map(|err| {
+      if err.code.starts_with("import") && !is_name_char(nth(&err.code, 6)) {
+        ParseErr { erro: "Imports must come before the statements of a file.".to_string(), ..err }
       } else {
-        Err(format!("Your code was not parsed entirely: {}", code))
+        err
+      }
+    }).collect()
+  })
+}
+
+/// The statements of a code file, preceded by the ones it imports.
+#[derive(Debug)]
+pub struct Loaded {
+  pub statements: Vec<Statement>,
+  /// How many of the first statements come from imported files.
+  pub imported: usize,
+}
+
+// Resolves imports, loading each file once.
+struct Loader {
+  stack: Vec<PathBuf>,   // files being loaded, to detect cycles
+  loaded: HashSet<PathBuf>,
+  defined: HashMap<(&'static str, Name), (usize, PathBuf)>, // index of each definition
+  statements: Vec<Statement>,
+}
+
+impl Loader {
+  fn load_file(&mut self, path: &Path) -> Result<(), String> {
+    let path = path.canonicalize()
+      .map_err(|err| format!("Cannot import '{}': {}", path.display(), err))?;
+    if let Some(idx) = self.stack.iter().position(|file| *file == path) {
+      let cycle = self.stack[idx ..].iter().chain([&path]);
+      let cycle = cycle.map(|file| file.display().to_string()).collect::<Vec<_>>();
+      return Err(format!("Import cycle: {}", cycle.join(" -> ")));
+    }
+    if !self.loaded.insert(path.clone()) {
+      return Ok(());
+    }
+    let code = std::fs::read_to_string(&path)
+      .map_err(|err| format!("Cannot import '{}': {}", path.display(), err))?;
+    self.stack.push(path.clone());
+    let rest = self.load_imports(&code, &path)?;
+    self.load_statements(&code, rest, &path)?;
+    self.stack.pop();
+    Ok(())
+  }
+
+  // Loads the imports at the top of `code`, returning the code after them.
+  fn load_imports<'a>(&mut self, code: &'a str, path: &Path) -> Result<&'a str, String> {
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut rest = code;
+    loop {
+      match read_import(rest) {
+        Ok((next, Some(file))) => {
+          self.load_file(&dir.join(file))?;
+          rest = next;
+        }
+        Ok((_, None)) => return Ok(rest),
+        Err(err) => return Err(show_file_errors(code, path, &[err.place(rest, code.len() - rest.len())])),
       }
     }
-    Err(ParseErr { erro, .. }) => Err(erro),
   }
+
+  // Adds the statements of `rest`, the end of `code`, dropping definitions
+  // identical to a previous one.
+  fn load_statements(&mut self, code: &str, rest: &str, path: &Path) -> Result<(), String> {
+    let statements = parse_file_statements(code, code.len() - rest.len())
+      .map_err(|errors| show_file_errors(code, path, &errors))?;
+    for statement in statements {
+      let key = match &statement {
+        Statement::Fun { name, .. } => ("fun", *name),
+        Statement::Ctr { name, .. } => ("ctr", *name),
+        Statement::Reg { name, .. } => ("reg", *name),
+        Statement::Run { .. } | Statement::Own { .. } => {
+          self.statements.push(statement);
+          continue;
+        }
+      };
+      if let Some((other, other_path)) = self.defined.get(&key) {
+        if self.statements[*other] == statement {
+          continue;
+        }
+        return Err(format!(
+          "'{}' is defined twice with different bodies, in '{}' and '{}'.",
+          key.1, other_path.display(), path.display()
+        ));
+      }
+      self.defined.insert(key, (self.statements.len(), path.to_path_buf()));
+      self.statements.push(statement);
+    }
+    Ok(())
+  }
+}
+
+fn show_file_errors(code: &str, path: &Path, errors: &[ParseErr]) -> String {
+  format!("In '{}':\n{}", path.display(), show_parse_errors(code, errors))
+}
+
+/// Parses code that may start with `import "file.kdl"` directives, resolved
+/// relative to `path`, the file the code comes from (or the current directory
+/// if `None`). Each file is loaded once, and definitions repeated with the
+/// same body are kept only once.
+pub fn load_code(code: &str, path: Option<&Path>) -> Result<Loaded, String> {
+  let path = match path {
+    Some(path) => path.canonicalize().map_err(|err| format!("Cannot read '{}': {}", path.display(), err))?,
+    None => std::env::current_dir().map_err(|err| err.to_string())?.join("<stdin>"),
+  };
+  let mut loader = Loader {
+    stack: vec![path.clone()],
+    loaded: HashSet::from([path.clone()]),
+    defined: HashMap::new(),
+    statements: vec![],
+  };
+  let rest = loader.load_imports(code, &path)?;
+  let imported = loader.statements.len();
+  loader.load_statements(code, rest, &path)?;
+  Ok(Loaded { statements: loader.statements, imported })
 }
 
 // View
@@ -4884,6 +6682,53 @@ pub fn view_name(name: Name) -> String {
   }
 }
 
+/// Recovers the text of a `StrCons` list, if it is exactly what a string
+/// literal desugars to.
+pub fn term_to_string(term: &Term) -> Option<String> {
+  let mut bytes = Vec::new();
+  let mut term = term;
+  loop {
+    match term {
+      Term::Ctr { name, args } if **name == STR_NIL && args.is_empty() => {
+        return String::from_utf8(bytes).ok();
+      }
+      Term::Ctr { name, args } if **name == STR_CONS && args.len() == 2 => {
+        // only the last chunk can be partial
+        if bytes.len() % STR_CHUNK_BYTES != 0 {
+          return None;
+        }
+        let chunk = if let Term::Num { numb } = args[0] { numb.to_be_bytes() } else { return None };
+        let chunk = &chunk[16 - STR_CHUNK_BYTES ..];
+        let len = chunk.iter().position(|byte| *byte == 0).unwrap_or(STR_CHUNK_BYTES);
+        if len == 0 || chunk[len ..].iter().any(|byte| *byte != 0) {
+          return None;
+        }
+        bytes.extend_from_slice(&chunk[.. len]);
+        term = &args[1];
+      }
+      _ => {
+        return None;
+      }
+    }
+  }
+}
+
+pub fn view_string(text: &str) -> String {
+  let mut view = String::from("\"");
+  for chr in text.chars() {
+    match chr {
+      '\n' => view.push_str("\\n"),
+      '\r' => view.push_str("\\r"),
+      '\t' => view.push_str("\\t"),
+      '"'  => view.push_str("\\\""),
+      '\\' => view.push_str("\\\\"),
+      chr  => view.push(chr),
+    }
+  }
+  view.push('"');
+  view
+}
+
 pub fn view_term(term: &Term) -> String {
   enum StackItem<'a> {
     Term(&'a Term),
@@ -4927,9 +6772,12 @@ pub fn view_term(term: &Term) -> String {
             stack.push(StackItem::Term(func));
           }
           Term::Ctr { name, args } => {
+            let text = term_to_string(term);
             let name = view_name(*name);
-            // Pretty print names
-            if name == "Name" && args.len() == 1 {
+            // Pretty print strings and names
+            if let Some(text) = text {
+              output.push(view_string(&text));
+            } else if name == "Name" && args.len() == 1 {
               if let Term::Num { numb } = args[0] {
                 output.push(format!("{{Name '{}'}}", view_name(numb.into())));
               }
@@ -4998,25 +6846,47 @@ pub fn view_oper(oper: &Oper) -> String {
   }.to_string()
 }
 
-pub fn view_statement(statement: &Statement) -> String {
-  fn view_sign(sign: &Option<crypto::Signature>) -> String {
-    fn format_sign(sign: &crypto::Signature) -> String {
-      let hex = sign.to_hex();
-      let mut text = String::new();
-      for i in 0 .. 5 {
-        text.push_str("  ");
-        text.push_str(&hex[i * 26 .. (i+1) * 26]);
-        text.push_str("\n");
-      }
-      return text;
-    }
-    match sign {
-      None       => String::new(),
-      Some(sign) => format!(" sign {{\n{}}}", format_sign(sign)),
+pub fn view_sign(sign: &Option<crypto::Signature>) -> String {
+  fn format_sign(sign: &crypto::Signature) -> String {
+    let hex = sign.to_hex();
+    let mut text = String::new();
+    for i in 0 .. 5 {
+      text.push_str("  ");
+      text.push_str(&hex[i * 26 .. (i+1) * 26]);
+      text.push_str("\n");
     }
+    return text;
+  }
+  match sign {
+    None       => String::new(),
+    Some(sign) => format!(" sign {{\n{}}}", format_sign(sign)),
+  }
+}
+
+pub fn view_nonce(nonce: &Option<U120>) -> String {
+  match nonce {
+    None        => String::new(),
+    Some(nonce) => format!(" nonce {{ #{} }}", **nonce),
+  }
+}
+
+pub fn view_expiry(until: &Option<u64>) -> String {
+  match until {
+    None        => String::new(),
+    Some(until) => format!(" until {{ #{} }}", until),
+  }
+}
+
+pub fn view_mana(mana: &Option<u64>) -> String {
+  match mana {
+    None       => String::new(),
+    Some(mana) => format!("<{}>", mana),
   }
+}
+
+pub fn view_statement(statement: &Statement) -> String {
   match statement {
-    Statement::Fun { name, args, func, init, sign } => {
+    Statement::Fun { name, args, func, init, until, sign } => {
       let func = func.rules.iter().map(|x| format!("\n  {} = {}", view_term(&x.lhs), view_term(&x.rhs)));
       let func = func.collect::<Vec<String>>().join("");
       let args = args.iter().map(|x| x.to_string()).collect::<Vec<String>>().join(" ");
@@ -5026,26 +6896,39 @@ pub fn view_statement(statement: &Statement) -> String {
       } else {
         "\n".to_string()
       };
+      let until = view_expiry(until);
       let sign = view_sign(sign);
-      return format!("fun ({} {}) {{{}\n}}{}{}", name, args, func, init, sign);
+      return format!("fun ({} {}) {{{}\n}}{}{}{}", name, args, func, init, until, sign);
     }
-    Statement::Ctr { name, args, sign } => {
+    Statement::Ctr { name, args, until, sign } => {
       // correct:
       let name = name;
       let args = args.iter().map(|x| format!(" {}", x)).collect::<Vec<String>>().join("");
+      let until = view_expiry(until);
       let sign = view_sign(sign);
-      return format!("ctr {{{}{}}}{}", name, args, sign);
+      return format!("ctr {{{}{}}}{}{}", name, args, until, sign);
     }
-    Statement::Run { expr, sign } => {
+    Statement::Run { expr, mana, nonce, until, sign } => {
+      let mana = view_mana(mana);
       let expr = view_term(expr);
+      let nonce = view_nonce(nonce);
+      let until = view_expiry(until);
       let sign = view_sign(sign);
-      return format!("run {{\n  {}\n}}{}", expr, sign);
+      return format!("run{} {{\n  {}\n}}{}{}{}", mana, expr, nonce, until, sign);
     }
-    Statement::Reg { name, ownr, sign } => {
+    Statement::Reg { name, ownr, until, sign } => {
       let name = name;
       let ownr = format!("#x{:0>30x}", **ownr);
+      let until = view_expiry(until);
+      let sign = view_sign(sign);
+      return format!("reg {} {{ {} }}{}{}", name, ownr, until, sign);
+    }
+    Statement::Own { name, ownr, nonce, until, sign } => {
+      let ownr = format!("#x{:0>30x}", **ownr);
+      let nonce = view_nonce(nonce);
+      let until = view_expiry(until);
       let sign = view_sign(sign);
-      return format!("reg {} {{ {} }}{}", name, ownr, sign);
+      return format!("own {} {{ {} }}{}{}{}", name, ownr, nonce, until, sign);
     }
   }
 }
@@ -5082,6 +6965,38 @@ pub fn hash_statement(statement: &Statement) -> crypto::Hash {
   crypto::Hash::keccak256_from_bytes(&util::bitvec_to_bytes(&remove_sign(&statement).proto_serialized()))
 }
 
+/// Hash of the arity, owner and function of a name, as committed to by its state root leaf.
+pub fn hash_definition(arit: Option<u64>, ownr: Option<U120>, func: Option<&Func>) -> crypto::Hash {
+  let mut bits = bit_vec::BitVec::new();
+  let mut names = HashMap::new();
+  arit.proto_serialize(&mut bits, &mut names);
+  ownr.proto_serialize(&mut bits, &mut names);
+  bits.push(func.is_some());
+  if let Some(func) = func {
+    func.proto_serialize(&mut bits, &mut names);
+  }
+  crypto::Hash::keccak256_from_bytes(&util::bitvec_to_bytes(&bits))
+}
+
+/// Hash of the state of a name, read back as a term, as committed to by its state root leaf.
+pub fn hash_state(state: Option<&Term>) -> crypto::Hash {
+  let mut bits = bit_vec::BitVec::new();
+  bits.push(state.is_some());
+  if let Some(state) = state {
+    state.proto_serialize(&mut bits, &mut HashMap::new());
+  }
+  crypto::Hash::keccak256_from_bytes(&util::bitvec_to_bytes(&bits))
+}
+
+/// State root leaf of a name, given the hashes of its definition and state.
+pub fn state_leaf(name: Name, defn: &crypto::Hash, state: &crypto::Hash) -> crypto::Hash {
+  let mut data = Vec::with_capacity(80);
+  data.extend_from_slice(&name.to_be_bytes());
+  data.extend_from_slice(&defn.0);
+  data.extend_from_slice(&state.0);
+  crypto::merkle_leaf(&data)
+}
+
 // Tests
 // -----
 
@@ -5102,7 +7017,7 @@ pub fn print_io_consts() {
 }
 
 // Serializes, deserializes and evaluates statements
-pub fn test_statements(statements: &Vec<Statement>, debug: bool) {
+pub fn test_statements(statements: &Vec<Statement>, debug: bool, trace: bool, profile: bool) {
   let str_0 = view_statements(statements);
   let str_1 = view_statements(&Vec::proto_deserialized(&statements.proto_serialized()).unwrap());
 
@@ -5110,10 +7025,10 @@ pub fn test_statements(statements: &Vec<Statement>, debug: bool) {
   println!("=====");
   println!();
 
-  // TODO: code below does not need heaps_path at all. extract heap persistence out of Runtime.
-  let heaps_path = dirs::home_dir().unwrap().join(".kindelia").join("state").join("heaps");
   let genesis_smts = parse_code(constants::GENESIS_CODE).expect("Genesis code parses");
-  let mut rt = init_runtime(heaps_path, &genesis_smts);
+  let mut rt = init_volatile_runtime(&genesis_smts, &RollbackConfig::default());
+  rt.set_tracing(trace);
+  rt.set_profiling(profile);
   let init = Instant::now();
   rt.run_statements(&statements, false, debug);
   println!();
@@ -5128,16 +7043,15 @@ pub fn test_statements(statements: &Vec<Statement>, debug: bool) {
   println!("[time] {} ms", init.elapsed().as_millis());
 }
 
-pub fn test_statements_from_code(code: &str, debug: bool) {
-  let statments = read_statements(code);
-  match statments {
-    Ok((.., statements)) => test_statements(&statements, debug),
-    Err(ParseErr { code, erro }) => println!("{}", erro),
+pub fn test_statements_from_code(code: &str, debug: bool, trace: bool, profile: bool) {
+  match parse_code(code) {
+    Ok(statements) => test_statements(&statements, debug, trace, profile),
+    Err(err) => println!("{}", err),
   }
 }
 
-pub fn test_statements_from_file(file: &str, debug: bool) {
-  test_statements_from_code(&std::fs::read_to_string(file).expect("file not found"), debug);
+pub fn test_statements_from_file(file: &str, debug: bool, trace: bool, profile: bool) {
+  test_statements_from_code(&std::fs::read_to_string(file).expect("file not found"), debug, trace, profile);
 }
 
 // Term Drop implementation
@@ -2,7 +2,7 @@
 
 use std::collections::{HashMap, HashSet};
 use std::ops::Deref;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::{mpsc, Arc, Mutex};
 use std::thread::JoinHandle;
 
@@ -12,14 +12,15 @@ use priority_queue::PriorityQueue;
 use rand::seq::IteratorRandom;
 use sha3::Digest;
 
-use crate::api::{self, CtrInfo, RegInfo};
-use crate::api::{BlockInfo, FuncInfo, NodeRequest};
+use crate::api::{self, CtrInfo, FitInfo, RegInfo};
+use crate::api::{BlockInfo, FuncInfo, NodeRequest, StateProof};
 use crate::bits::{serialized_block_size, ProtoSerialize};
 use crate::common::Name;
-use crate::config::{MineConfig, NodeConfig};
+use crate::config::{MineConfig, NodeConfig, RollbackConfig};
 use crate::constants;
 use crate::crypto::{self, Hashed, Keccakable};
 use crate::hvm::{self, *};
+use crate::persistence;
 use crate::net::{ProtoAddr, ProtoComm};
 use crate::util::*;
 
@@ -156,6 +157,30 @@ impl Body {
   /// Build a body from a sequence of transactions.
   /// Fails if they can't fit in a block body.
   pub fn from_transactions_iter<I, T>(transactions: I) -> Result<Body, ()>
+  where
+    I: IntoIterator<Item = T>,
+    T: Into<Transaction>,
+  {
+    Body::from_transactions_iter_max(transactions, MAX_BODY_SIZE)
+  }
+
+  /// Build a body from a sequence of transactions.
+  /// Fails if they can't fit in `max_size` bytes.
+  fn from_transactions_iter_max<I, T>(
+    transactions: I,
+    max_size: usize,
+  ) -> Result<Body, ()>
+  where
+    I: IntoIterator<Item = T>,
+    T: Into<Transaction>,
+  {
+    let (body, tx_count) = Body::encode(transactions);
+    body.check(tx_count, max_size)
+  }
+
+  /// Encodes a sequence of transactions, however many and large they are.
+  /// Returns the body and the number of transactions in it.
+  fn encode<I, T>(transactions: I) -> (Body, usize)
   where
     I: IntoIterator<Item = T>,
     T: Into<Transaction>,
@@ -166,18 +191,9 @@ impl Body {
     for transaction in transactions.into_iter() {
       let transaction = transaction.into();
       // Ignore transaction if it's empty
-      let tx_len = transaction.data.len();
-      if tx_len == 0 {
+      if transaction.data.is_empty() {
         continue;
       }
-      // Fails if there's no space left for the transaction
-      if data.len() + 2 + tx_len > MAX_BODY_SIZE {
-        return Err(());
-      }
-      // Fails if tx count overflows 255, as we store it in a single byte.
-      if tx_count + 1 > 255 {
-        return Err(());
-      }
       tx_count += 1;
       // Pair of bytes we will store as the length
       let len_bytes = transaction.encode_length();
@@ -187,7 +203,21 @@ impl Body {
     }
     // Finally stores resulting transaction count on the first byte
     data[0] = (tx_count as u8).reverse_bits();
-    Ok(Body { data })
+    (Body { data }, tx_count)
+  }
+
+  /// Fails if the body doesn't fit in `max_size` bytes, or if its `tx_count`
+  /// overflows 255, as we store it in a single byte.
+  fn check(self, tx_count: usize, max_size: usize) -> Result<Body, ()> {
+    if self.encoded_len() > max_size || tx_count > 255 {
+      return Err(());
+    }
+    Ok(self)
+  }
+
+  /// Length of the encoded body, in bytes.
+  pub fn encoded_len(&self) -> usize {
+    self.data.len()
   }
 }
 
@@ -249,6 +279,7 @@ pub enum InclusionState {
 pub struct Node<C: ProtoComm> {
   pub data_path    : PathBuf,                           // path where files are saved
   pub network_id   : u32,                               // Network ID / magic number
+  pub rollback     : RollbackConfig,                    // snapshots kept by the runtime
   pub comm         : C,                                 // UDP socket
   pub addr         : C::Address,                        // UDP port
   pub runtime      : Runtime,                           // Kindelia's runtime
@@ -266,6 +297,9 @@ pub struct Node<C: ProtoComm> {
   pub target     : U256Map<U256>,                  // block hash -> this block's target
   pub height     : U256Map<u128>,                  // block hash -> cached height
   pub results    : U256Map<Vec<StatementResult>>,  // block hash -> results of the statements in this block
+  pub scheduled  : U256Map<Vec<StatementResult>>,  // block hash -> results of the calls scheduled for this block
+  pub roots      : U256Map<U256>,                  // block hash -> state root after this block
+  pub restored   : Option<U256>,                   // tip of the runtime state restored from disk, until it's loaded
 
   #[cfg(feature = "events")]
   pub event_emitter : mpsc::Sender<NodeEventEmittedInfo>,
@@ -622,12 +656,170 @@ pub fn zero_hash() -> U256 {
 }
 
 /// Builds the Genesis Block.
+/// Every node builds it locally instead of mining or receiving it, so its body
+/// isn't bound by the size limit of the other blocks.
 pub fn build_genesis_block(stmts: &[Statement]) -> Block {
-  let body = Body::from_transactions_iter(stmts)
+  let body = Body::from_transactions_iter_max(stmts, usize::MAX)
     .expect("Genesis statements should fit in a block body");
   Block::new(zero_hash(), 0, 0, body)
 }
 
+/// Lists the block files saved in `blocks_dir`, sorted by height.
+pub fn block_file_paths(blocks_dir: &Path) -> Vec<(u64, PathBuf)> {
+  std::fs::create_dir_all(blocks_dir).ok();
+  let mut file_paths: Vec<(u64, PathBuf)> = vec![];
+  for entry in std::fs::read_dir(blocks_dir).unwrap() {
+    // Extract block height from block file path for fast sort
+    let path = entry.unwrap().path();
+    let name = path.file_name().unwrap().to_str().unwrap();
+    let bnum = name.split('.').nth(0).unwrap();
+    let bnum = u64::from_str_radix(bnum, 16).unwrap();
+    file_paths.push((bnum, path));
+  }
+  file_paths.sort_unstable();
+  file_paths
+}
+
+/// Runs a block's scheduled calls and statements, committing its state.
+/// Returns the results of both.
+pub fn run_block(
+  runtime: &mut Runtime,
+  block: &HashedBlock,
+) -> (Vec<StatementResult>, Vec<StatementResult>) {
+  let transactions = extract_transactions(&block.body);
+  let mut statements = Vec::new();
+  for transaction in transactions {
+    if let Some(statement) = transaction.to_statement() {
+      statements.push(statement);
+    }
+  }
+  let bhash = U256::from(block.get_hash());
+  runtime.set_time(block.time >> 8);
+  runtime.set_meta(block.meta >> 8);
+  runtime.set_hax0((bhash >> 000).low_u128() >> 8);
+  runtime.set_hax1((bhash >> 120).low_u128() >> 8);
+  runtime.open();
+  let scheduled = runtime.run_scheduled(false);
+  let result = runtime.run_statements(&statements, false, false);
+  runtime.set_tip(bhash);
+  runtime.commit();
+  (scheduled, result)
+}
+
+/// Builds the proof of the state of `name` against the state root of the
+/// runtime's tip. Returns `None` if `name` isn't on the state root or if its
+/// state is too big to be read back.
+pub fn get_state_proof(runtime: &Runtime, name: Name) -> Option<StateProof> {
+  let tree = runtime.get_state_tree();
+  let index = tree.index(&name)?;
+  let state = match runtime.read_state(name.into()) {
+    Some(state) => Some(readback_term(runtime, state, Some(1 << 16))?),
+    None => None,
+  };
+  let path = tree.proof(index);
+  Some(StateProof {
+    block: runtime.get_tip().into(),
+    state_root: U256::from(&tree.root()).into(),
+    name,
+    state,
+    definition: U256::from(&runtime.get_definition_hash(&name)).into(),
+    index: index as u64,
+    leaves: tree.len() as u64,
+    path: path.iter().map(|hash| U256::from(hash).into()).collect(),
+  })
+}
+
+// State snapshots
+// ---------------
+
+/// Loads the best chain saved in `data_path`: the genesis block, followed by
+/// the saved blocks that extend it, in order.
+pub fn load_best_chain(data_path: &Path) -> Vec<HashedBlock> {
+  let genesis_stmts =
+    hvm::parse_code(constants::GENESIS_CODE).expect("Genesis code parses");
+  let mut chain = vec![build_genesis_block(&genesis_stmts).hashed()];
+  for (_, file_path) in block_file_paths(&data_path.join("blocks")) {
+    let buffer = std::fs::read(&file_path).unwrap();
+    let block = match Block::proto_deserialized(&bytes_to_bitvec(&buffer)) {
+      Some(block) => block.hashed(),
+      None => break,
+    };
+    if block.prev != U256::from(chain.last().unwrap().get_hash()) {
+      break;
+    }
+    chain.push(block);
+  }
+  chain
+}
+
+/// Exports the runtime state at the block `bhash` of the best chain saved in
+/// `data_path` to a state file. The state is computed from the persisted one,
+/// if it's at an earlier block of that chain, or else from the genesis block,
+/// in a runtime that doesn't persist, so the node's saved state is untouched.
+pub fn export_state(
+  data_path: &Path,
+  bhash: U256,
+  file: &Path,
+  rollback: &RollbackConfig,
+) -> Result<(), String> {
+  let chain = load_best_chain(data_path);
+  let height = chain
+    .iter()
+    .position(|block| U256::from(block.get_hash()) == bhash)
+    .ok_or_else(|| "Block is not on the node's best chain.".to_string())?;
+  let mut runtime = match restore_runtime(data_path.join("heaps"), rollback) {
+    Ok(mut runtime)
+      if (runtime.get_tick() as usize) <= height
+        && U256::from(chain[runtime.get_tick() as usize].get_hash())
+          == runtime.get_tip() =>
+    {
+      runtime.detach();
+      runtime
+    }
+    _ => {
+      let genesis_stmts =
+        hvm::parse_code(constants::GENESIS_CODE).expect("Genesis code parses");
+      init_volatile_runtime(&genesis_stmts, rollback)
+    }
+  };
+  for block in &chain[runtime.get_tick() as usize + 1..=height] {
+    run_block(&mut runtime, block);
+  }
+  persistence::write_state_file(file, bhash, &runtime.export_state())
+    .map_err(|err| format!("Could not write the state file: {}", err))
+}
+
+/// Installs the runtime state of a state file as the persisted state of the
+/// node at `data_path`, which then syncs from its block. Returns the hash of
+/// that block, which must be on the node's best chain.
+pub fn import_state(
+  data_path: &Path,
+  file: &Path,
+  rollback: &RollbackConfig,
+) -> Result<U256, String> {
+  let (bhash, state) = persistence::read_state_file(file)
+    .map_err(|err| format!("Could not read the state file: {}", err))?;
+  let chain = load_best_chain(data_path);
+  let height = chain
+    .iter()
+    .position(|block| U256::from(block.get_hash()) == bhash)
+    .ok_or_else(|| {
+      format!(
+        "Block {} of the state file is not on the node's best chain.",
+        api::Hash::from(bhash)
+      )
+    })?;
+  if state.tick as usize != height {
+    return Err(format!(
+      "State file is at tick {}, but its block is at height {}.",
+      state.tick, height
+    ));
+  }
+  install_runtime(data_path.join("heaps"), state, bhash, rollback)
+    .map_err(|err| format!("Could not install the state: {}", err))?;
+  Ok(bhash)
+}
+
 // Mining
 // ------
 
@@ -716,6 +908,7 @@ impl<C: ProtoComm> Node<C> {
   pub fn new(
     data_path: PathBuf,
     network_id: u32,
+    rollback: RollbackConfig,
     initial_peers: Vec<C::Address>,
     comm: C,
     miner_comm: Option<MinerCommunication>,
@@ -731,12 +924,35 @@ impl<C: ProtoComm> Node<C> {
     let genesis_block = genesis_block.hashed();
     let genesis_hash = genesis_block.get_hash().into();
 
-    let runtime = init_runtime(data_path.join("heaps"), &genesis_stmts);
+    // Restores the runtime state persisted by the last run, if any, so that
+    // only the blocks after its tip are computed when loading them
+    let heaps_path = data_path.join("heaps");
+    let restored_runtime = restore_runtime(heaps_path.clone(), &rollback);
+    let (runtime, restored) = match restored_runtime {
+      Ok(runtime) if !runtime.get_tip().is_zero() => {
+        let tip = runtime.get_tip();
+        (runtime, Some(tip))
+      }
+      Ok(_) => (init_runtime(heaps_path, &genesis_stmts, &rollback), None),
+      Err(err) => {
+        if err.kind() != std::io::ErrorKind::NotFound {
+          eprintln!("WARN: Could not restore the runtime state: {}", err);
+        }
+        (init_runtime(heaps_path, &genesis_stmts, &rollback), None)
+      }
+    };
+
+    // The genesis state root is only known if its state was just computed
+    let mut roots = u256map_new();
+    if restored.is_none() {
+      roots.insert(genesis_hash, U256::from(&runtime.get_state_root()));
+    }
 
     #[rustfmt::skip]
     let mut node = Node {
       data_path,
       network_id,
+      rollback,
       addr: comm.get_addr(),
       comm,
       runtime,
@@ -754,6 +970,9 @@ impl<C: ProtoComm> Node<C> {
       height   : u256map_from([(genesis_hash, 0               )]),
       target   : u256map_from([(genesis_hash, initial_target())]),
       results  : u256map_from([(genesis_hash, vec![]          )]),
+      scheduled: u256map_from([(genesis_hash, vec![]          )]),
+      roots,
+      restored,
 
       #[cfg(feature = "events")]
       event_emitter: event_emitter.clone(),
@@ -795,6 +1014,24 @@ impl<C: ProtoComm> Node<C> {
     }
   }
 
+  // Removes the transactions that expire before the next block, since they
+  // would only fail if mined
+  pub fn evict_expired_transactions(&mut self) {
+    let tick = self.runtime.get_tick();
+    let expired: Vec<Transaction> = self
+      .pool
+      .iter()
+      .filter(|(tx, _)| {
+        let until = tx.to_statement().and_then(|stmt| hvm::get_until(&stmt));
+        until.map_or(false, |until| until <= tick)
+      })
+      .map(|(tx, _)| tx.clone())
+      .collect();
+    for tx in expired {
+      self.pool.remove(&tx);
+    }
+  }
+
   // Registers a block on the node's database. This performs several actions:
   // - If this block is too far into the future, ignore it.
   // - If this block's parent isn't available:
@@ -899,7 +1136,18 @@ impl<C: ProtoComm> Node<C> {
               self.pool.remove(&tx);
             }
             self.tip = bhash;
-            if true {
+            if let Some(restored) = self.restored {
+              // The runtime was restored from disk, so the blocks up to its tip
+              // were already computed
+              if bhash == restored {
+                self.restored = None;
+              // If the chain reached its height without it, it isn't on this chain
+              } else if self.height[&bhash] >= self.runtime.get_tick() as u128 {
+                eprintln!("WARN: Restored runtime state isn't on the best chain, recomputing it...");
+                self.restored = None;
+                self.recompute_runtime();
+              }
+            } else {
               // Block reorganization (* marks blocks for which we have runtime snapshots):
               // tick: |  0 | *1 |  2 |  3 |  4 | *5 |  6 | *7 | *8 |
               // hash: |  A |  B |  C |  D |  E |  F |  G |  H |    |  <- old timeline
@@ -980,6 +1228,7 @@ impl<C: ProtoComm> Node<C> {
                 self.compute_block(&block_comp.clone()); // TODO: avoid clone
               }
             }
+            self.evict_expired_transactions();
           }
         } else {
           emit_event!(
@@ -1028,22 +1277,29 @@ impl<C: ProtoComm> Node<C> {
   }
 
   pub fn compute_block(&mut self, block: &HashedBlock) {
-    let transactions = extract_transactions(&block.body);
-    let mut statements = Vec::new();
-    for transaction in transactions {
-      if let Some(statement) = transaction.to_statement() {
-        statements.push(statement);
-      }
-    }
     let bhash = U256::from(block.get_hash());
-    self.runtime.set_time(block.time >> 8);
-    self.runtime.set_meta(block.meta >> 8);
-    self.runtime.set_hax0((bhash >> 000).low_u128() >> 8);
-    self.runtime.set_hax1((bhash >> 120).low_u128() >> 8);
-    self.runtime.open();
-    let result = self.runtime.run_statements(&statements, false, false);
+    let (scheduled, result) = run_block(&mut self.runtime, block);
+    if let Some(event) = NodeEventType::logs(block, self.height.get(&bhash).copied(), &result) {
+      emit_event!(self.event_emitter, event, tags = add_block, logs);
+    }
     self.results.insert(bhash, result);
-    self.runtime.commit();
+    self.scheduled.insert(bhash, scheduled);
+    self.roots.insert(bhash, U256::from(&self.runtime.get_state_root()));
+  }
+
+  // Recomputes the runtime state from the genesis block up to the tip
+  fn recompute_runtime(&mut self) {
+    let genesis_stmts =
+      hvm::parse_code(constants::GENESIS_CODE).expect("Genesis code parses");
+    self.runtime = init_runtime(
+      self.data_path.join("heaps"),
+      &genesis_stmts,
+      &self.rollback,
+    );
+    for bhash in self.get_longest_chain(None).iter().skip(1) {
+      let block = self.block[bhash].clone();
+      self.compute_block(&block);
+    }
   }
 
   // Get the current target
@@ -1105,11 +1361,15 @@ impl<C: ProtoComm> Node<C> {
     let height = self.height.get(hash).expect("Missing block height.");
     let height: u64 = (*height).try_into().expect("Block height is too big.");
     let results = self.results.get(hash).map(|r| r.clone());
+    let scheduled = self.scheduled.get(hash).map(|r| r.clone());
+    let state_root = self.roots.get(hash).map(|r| (*r).into());
     let info = BlockInfo {
       block: (&**block).into(),
       hash: (*hash).into(),
       height,
       results,
+      scheduled,
+      state_root,
     };
     Some(info)
   }
@@ -1138,7 +1398,41 @@ impl<C: ProtoComm> Node<C> {
     let ns: Vec<Name> =
       self.runtime.get_all_ns().into_iter().filter(pred).collect();
     let stmt = [ctrs, funs, ns].concat();
-    Some(RegInfo { ownr, stmt })
+    let xfer = self.runtime.get_transfer(&name).map(|pos| (pos >> 60) as u64);
+    Some(RegInfo { ownr, stmt, xfer })
+  }
+
+  /// Checks if the statements would fit in a block on top of the current tip,
+  /// both in body size and in mana / size growth.
+  pub fn get_fit_info(&mut self, code: &[Statement]) -> FitInfo {
+    let (body, tx_count) = Body::encode(code.iter().map(Transaction::from));
+    let used_bytes = body.encoded_len();
+    let body_fits = body.check(tx_count, MAX_BODY_SIZE).is_ok();
+    let results = self.runtime.test_statements(code);
+    let mut used_mana = 0;
+    let mut used_bits = 0;
+    for info in results.iter().flatten() {
+      if let StatementInfo::Run { used_mana: mana, size_diff, .. } = info {
+        used_mana += mana;
+        used_bits += size_diff * 128;
+      }
+    }
+    let runs_ok =
+      results.len() == code.len() && results.iter().all(|res| res.is_ok());
+    let fits = body_fits
+      && runs_ok
+      && used_mana <= BLOCK_MANA_LIMIT
+      && used_bits <= BLOCK_BITS_LIMIT as i64;
+    FitInfo {
+      fits,
+      used_bytes,
+      max_bytes: MAX_BODY_SIZE,
+      used_mana,
+      max_mana: BLOCK_MANA_LIMIT,
+      used_bits,
+      max_bits: BLOCK_BITS_LIMIT,
+      results,
+    }
   }
 
   pub fn handle_request(&mut self, request: NodeRequest<C>) {
@@ -1196,6 +1490,11 @@ impl<C: ProtoComm> Node<C> {
         let info = self.get_block_hash_by_index(index);
         handle_ans_err("GetBlockHash", tx.send(info));
       }
+      NodeRequest::GetRunResult { block_idx, stmt_idx, tx } => {
+        let result =
+          self.runtime.get_run_result(block_idx as u128, stmt_idx as u128);
+        handle_ans_err("GetRunResult", tx.send(result));
+      }
       NodeRequest::GetFunctions { tx } => {
         let mut funcs: HashSet<u128> = HashSet::new();
         self.runtime.reduce_with(&mut funcs, |acc, heap| {
@@ -1213,6 +1512,10 @@ impl<C: ProtoComm> Node<C> {
         let state = self.runtime.read_disk_as_term(name.into(), Some(1 << 16));
         handle_ans_err("GetState", tx.send(state));
       }
+      NodeRequest::GetStateProof { name, tx } => {
+        let proof = get_state_proof(&self.runtime, name);
+        handle_ans_err("GetStateProof", tx.send(proof));
+      }
       NodeRequest::GetPeers { all, tx } => {
         let peers =
           if all { self.peers.get_all() } else { self.peers.get_all_active() };
@@ -1226,14 +1529,14 @@ impl<C: ProtoComm> Node<C> {
         let info = self.get_reg_info(name);
         handle_ans_err("GetReg", tx.send(info));
       }
-      NodeRequest::RunCode { code, tx } => {
+      NodeRequest::RunCode { code, trace, tx } => {
+        self.runtime.set_tracing(trace);
         let result = self.runtime.test_statements_from_code(&code);
+        self.runtime.set_tracing(false);
         handle_ans_err("RunCode", tx.send(result));
       }
       NodeRequest::PublishCode { code, tx } => {
-        let statements =
-          hvm::read_statements(&code).map_err(|err| err.erro).map(|(_, s)| s);
-        let res = match statements {
+        let res = match hvm::parse_code(&code) {
           Err(err) => Err(err),
           Ok(stmts) => {
             let results: Vec<_> = stmts
@@ -1264,6 +1567,10 @@ impl<C: ProtoComm> Node<C> {
           .collect();
         handle_ans_err("Publish", tx.send(result));
       }
+      NodeRequest::Fit { code, tx } => {
+        let info = self.get_fit_info(&code);
+        handle_ans_err("Fit", tx.send(info));
+      }
     }
   }
 
@@ -1467,18 +1774,7 @@ impl<C: ProtoComm> Node<C> {
   }
 
   fn load_blocks(&mut self) {
-    let blocks_dir = self.get_blocks_path();
-    std::fs::create_dir_all(&blocks_dir).ok();
-    let mut file_paths: Vec<(u64, PathBuf)> = vec![];
-    for entry in std::fs::read_dir(&blocks_dir).unwrap() {
-      // Extract block height from block file path for fast sort
-      let path = entry.unwrap().path();
-      let name = path.file_name().unwrap().to_str().unwrap();
-      let bnum = name.split('.').nth(0).unwrap();
-      let bnum = u64::from_str_radix(bnum, 16).unwrap();
-      file_paths.push((bnum, path));
-    }
-    file_paths.sort_unstable();
+    let file_paths = block_file_paths(&self.get_blocks_path());
     let num_blocks = file_paths.len();
     eprintln!("Loading {} blocks from disk...", num_blocks);
     for (_, file_path) in file_paths {
@@ -1741,6 +2037,7 @@ pub fn start<C: ProtoComm + 'static>(
   let (node_query_sender, node) = Node::new(
     config.data_path,
     config.network_id,
+    config.rollback,
     initial_peers,
     comm,
     miner_comm,
@@ -1,9 +1,11 @@
 use std::io::{Read, Write, Result as IoResult, Error, ErrorKind};
+use std::path::Path;
 use std::hash::{Hash, BuildHasher};
 use std::collections::HashMap;
 use std::sync::Arc;
 use std::ops::Deref;
-use crate::hvm::{CompFunc, Func, compile_func};
+use crate::hvm::{CompFunc, Func, Heap, compile_func};
+use crate::util::{u256_to_bytes, U256};
 use crate::bits::ProtoSerialize;
 
 
@@ -254,3 +256,93 @@ impl DiskSer for crate::hvm::Loc {
     }
   }
 }
+
+impl DiskSer for crate::hvm::Sched {
+  fn disk_serialize<W: Write>(&self, sink: &mut W) -> IoResult<usize>{
+    let mut total_written = 0;
+    total_written += self.tick.disk_serialize(sink)?;
+    total_written += self.caller.disk_serialize(sink)?;
+    total_written += self.callee.disk_serialize(sink)?;
+    total_written += self.name.disk_serialize(sink)?;
+    total_written += (self.args.len() as u64).disk_serialize(sink)?;
+    for arg in &self.args {
+      total_written += arg.disk_serialize(sink)?;
+    }
+    Ok(total_written)
+  }
+  fn disk_deserialize<R: Read>(source: &mut R) -> IoResult<Option<Self>> {
+    fn read<T: DiskSer, R: Read>(source: &mut R) -> IoResult<T> {
+      T::disk_deserialize(source)?.ok_or_else(|| Error::from(ErrorKind::UnexpectedEof))
+    }
+    let tick = match u64::disk_deserialize(source)? {
+      None => return Ok(None),
+      Some(tick) => tick,
+    };
+    let caller = read(source)?;
+    let callee = read(source)?;
+    let name = read(source)?;
+    let len: u64 = read(source)?;
+    let mut args = Vec::with_capacity(len as usize);
+    for _ in 0 .. len {
+      args.push(read(source)?);
+    }
+    Ok(Some(crate::hvm::Sched { tick, caller, callee, name, args }))
+  }
+}
+
+/// Writes `bytes` to `path` atomically: they're written to a temporary file,
+/// which is synced and then renamed over `path`. A crash at any point leaves
+/// either the old file or the new one, never a partial write.
+pub fn write_atomic(path: &Path, bytes: &[u8]) -> IoResult<()> {
+  let temp = path.with_extension("tmp");
+  let mut file = std::fs::File::create(&temp)?;
+  file.write_all(bytes)?;
+  file.sync_all()?;
+  std::fs::rename(&temp, path)?;
+  if let Some(dir) = path.parent() {
+    std::fs::File::open(dir)?.sync_all()?;
+  }
+  Ok(())
+}
+
+// State files
+// -----------
+
+const STATE_MAGIC : &[u8; 8] = b"KDLSTATE";
+
+/// Version of the state file format, bumped whenever it changes.
+pub const STATE_VERSION : u64 = 2;
+
+/// Writes a portable state file: a magic string, the format version, the hash
+/// of the block whose state it is and the state itself, followed by the
+/// Keccak256 checksum of all of that.
+pub fn write_state_file(path: &Path, block: U256, state: &Heap) -> IoResult<()> {
+  let mut bytes = STATE_MAGIC.to_vec();
+  STATE_VERSION.disk_serialize(&mut bytes)?;
+  bytes.extend(u256_to_bytes(block));
+  bytes.extend(state.to_bytes()?);
+  let checksum = crate::crypto::Hash::keccak256_from_bytes(&bytes);
+  bytes.extend(checksum.0);
+  write_atomic(path, &bytes)
+}
+
+/// Reads a state file written by `write_state_file`, returning the hash of
+/// its block and its state.
+pub fn read_state_file(path: &Path) -> IoResult<(U256, Heap)> {
+  let invalid = |msg: String| Error::new(ErrorKind::InvalidData, msg);
+  let bytes = std::fs::read(path)?;
+  if bytes.len() < 8 + 8 + 32 + 32 || &bytes[.. 8] != STATE_MAGIC {
+    return Err(invalid("Not a state file.".to_string()));
+  }
+  let (body, checksum) = bytes.split_at(bytes.len() - 32);
+  if crate::crypto::Hash::keccak256_from_bytes(body).0 != checksum {
+    return Err(invalid("Checksum mismatch, the state file is corrupted.".to_string()));
+  }
+  let version = u64::from_le_bytes(body[8 .. 16].try_into().unwrap());
+  if version != STATE_VERSION {
+    return Err(invalid(format!("Unsupported state file version {}.", version)));
+  }
+  let block = U256::from_big_endian(&body[16 .. 48]);
+  let state = Heap::from_bytes(fastrand::u128(..), &body[48 ..])?;
+  Ok((block, state))
+}
@@ -0,0 +1,96 @@
+use proptest::{collection::vec, proptest};
+use rstest::rstest;
+
+use crate::format::format_code;
+use crate::hvm::{parse_code, view_statements};
+use crate::test::strategies::statement;
+
+#[rstest]
+#[case("genesis.kdl")]
+#[case("example/block_1.kdl")]
+#[case("example/block_2.kdl")]
+#[case("example/block_3.kdl")]
+#[case("example/block_4.kdl")]
+#[case("example/block_5.kdl")]
+#[case("example/voucher.kdl")]
+fn format_round_trip(#[case] file: &str) {
+  let code = std::fs::read_to_string(file).unwrap();
+  let formatted = format_code(&code).unwrap();
+  assert_eq!(parse_code(&code).unwrap(), parse_code(&formatted).unwrap());
+  assert_eq!(format_code(&formatted).unwrap(), formatted);
+}
+
+#[test]
+fn format_comments() {
+  let code = "
+// Header
+
+ctr {Pair a b}   // a pair
+fun (Swap p) { // swaps a pair
+  // the only case
+  (Swap {Pair a b}) = dup a0 a1 = a; {Pair b (+ a0 (+ a1 #x10))}
+
+
+  (Swap x) = (+ x // kept as written
+      'ab')
+}
+run { ask x = (Swap {Pair #1 #2}); (Done x) } // last
+";
+  let expected = "// Header
+
+ctr {Pair a b} // a pair
+fun (Swap p) { // swaps a pair
+  // the only case
+  (Swap {Pair a b}) =
+    dup a0 a1 = a;
+    {Pair b (+ a0 (+ a1 #x10))}
+
+  (Swap x) = (+ x // kept as written
+      'ab')
+}
+run {
+  ask x = (Swap {Pair #1 #2});
+  (Done x)
+} // last
+";
+  assert_eq!(format_code(code).unwrap(), expected);
+}
+
+#[test]
+fn format_imports() {
+  let code = "// Header
+import   \"lib/pair.kdl\" // the pair
+import \"lib/fst.kdl\"
+
+run { (Done (Fst {Pair #1 #2})) }
+";
+  let expected = "// Header
+import \"lib/pair.kdl\" // the pair
+import \"lib/fst.kdl\"
+
+run {
+  (Done (Fst {Pair #1 #2}))
+}
+";
+  let formatted = format_code(code).unwrap();
+  assert_eq!(formatted, expected);
+  assert_eq!(format_code(&formatted).unwrap(), formatted);
+  // Imports only go at the top
+  let errors = format_code("run { #0 }\nimport \"lib/pair.kdl\"").unwrap_err();
+  assert!(errors[0].erro.contains("Imports must come before"), "{}", errors[0].erro);
+}
+
+#[test]
+fn format_errors() {
+  let errors = format_code("ctr {Pair a b\nrun { #0 }\nfun (Foo) { (Foo) = }").unwrap_err();
+  assert_eq!(errors.len(), 2);
+}
+
+proptest! {
+  #[test]
+  fn format_parses_back(statements in vec(statement(), 0..10)) {
+    let code = view_statements(&statements);
+    let formatted = format_code(&code).unwrap();
+    assert_eq!(parse_code(&formatted).unwrap(), statements);
+  }
+}
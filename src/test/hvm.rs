@@ -3,21 +3,27 @@ use std::path::PathBuf;
 
 use proptest::prelude::ProptestConfig;
 use proptest::proptest;
+use proptest::prelude::any;
+use proptest::sample::Index;
 use proptest::{collection::vec, strategy::Strategy};
 use rstest::rstest;
 use rstest_reuse::{apply, template};
 
 use crate::common::{Name, U120};
+use crate::config::RollbackConfig;
+use crate::constants;
 use crate::hvm::{
   self, init_u128_map, read_statements, readback_term, show_term, view_statements,
-  view_term, Rollback, Runtime, StatementInfo, Term, Heap
+  view_term, Rewrite, Runtime, StatementInfo, Term, TraceStep, Heap
 };
 use crate::node;
+use crate::persistence;
 use crate::test::strategies::{func, heap, name, op2, statement, term};
 use crate::test::util::{
-  self, advance, init_runtime, rollback, rollback_path, rollback_simple,
-  run_term_and, run_term_from_code_and, temp_dir, temp_file, test_heap_checksum,
-  view_rollback_ticks, RuntimeStateTest, TempPath,
+  self, advance, alice, bob, bump_counter, deploy_counter, genesis_namer, init_runtime, init_runtime_with,
+  init_volatile_runtime, init_volatile_runtime_with,
+  rollback, rollback_path, rollback_simple, run_term_and, run_term_from_code_and, sign_statement, temp_dir,
+  temp_file, test_heap_checksum, view_rollback_ticks, RuntimeStateTest, TempPath,
 };
 
 #[template]
@@ -39,7 +45,6 @@ pub fn simple_rollback(
   pre_code: &str,
   code: &str,
   validators: &[util::Validator],
-  temp_dir: TempPath,
 ) {
   assert!(rollback_simple(
     pre_code,
@@ -48,7 +53,6 @@ pub fn simple_rollback(
     1000,
     1,
     validators,
-    &temp_dir.path
   ));
 }
 
@@ -59,7 +63,6 @@ pub fn advanced_rollback_in_random_state(
   pre_code: &str,
   code: &str,
   validators: &[util::Validator],
-  temp_dir: TempPath,
 ) {
   let path = [1000, 12, 1000, 24, 1000, 36];
   assert!(rollback_path(
@@ -68,7 +71,6 @@ pub fn advanced_rollback_in_random_state(
     fn_names,
     &path,
     validators,
-    &temp_dir.path
   ));
 }
 
@@ -78,9 +80,8 @@ pub fn advanced_rollback_in_saved_state(
   pre_code: &str,
   code: &str,
   validators: &[util::Validator],
-  temp_dir: TempPath,
 ) {
-  let mut rt = init_runtime(&temp_dir.path);
+  let mut rt = init_volatile_runtime();
   rt.run_statements_from_code(pre_code, true, true);
   advance(&mut rt, 1000, Some(code), validators);
   rt.rollback(900);
@@ -107,7 +108,6 @@ pub fn advanced_rollback_run_fail(
   pre_code: &str,
   code: &str,
   validators: &[util::Validator],
-  temp_dir: TempPath,
 ) {
   let path = [2, 1, 2, 1, 2, 1];
   assert!(rollback_path(
@@ -116,7 +116,6 @@ pub fn advanced_rollback_run_fail(
     &fn_names,
     &path,
     validators,
-    &temp_dir.path
   ));
 }
 
@@ -126,10 +125,9 @@ pub fn stack_overflow(
   pre_code: &str,
   code: &str,
   validators: &[util::Validator],
-  temp_dir: TempPath,
 ) {
   // caused by compute_at function
-  let mut rt = init_runtime(&temp_dir.path);
+  let mut rt = init_volatile_runtime();
   rt.run_statements_from_code(pre_code, true, true);
   advance(&mut rt, 1000, Some(code), validators);
 }
@@ -137,15 +135,14 @@ pub fn stack_overflow(
 #[rstest]
 #[ignore = "fix not done"]
 // TODO: fix drop stack overflow
-pub fn stack_overflow2(temp_dir: TempPath) {
+pub fn stack_overflow2() {
   // caused by drop of term
-  let mut rt = init_runtime(&temp_dir.path);
+  let mut rt = init_volatile_runtime();
   rt.run_statements_from_code(PRE_COUNTER, false, true);
   rt.run_statements_from_code(COUNTER_STACKOVERFLOW, false, true);
 }
 
 #[apply(hvm_cases)]
-#[ignore = "heap persistence disabled"]
 pub fn persistence1(
   fn_names: &[&str],
   pre_code: &str,
@@ -160,12 +157,9 @@ pub fn persistence1(
   advance(&mut rt, tick, Some(code), validators);
   let s1 = RuntimeStateTest::new(&fn_names, &mut rt);
 
-  let last = {
-    if let Rollback::Cons { head, .. } = *rt.get_back() {
-      rt.get_heap(head).tick
-    } else {
-      0
-    }
+  let last = match rt.get_back().newest() {
+    Some(head) => rt.get_heap(head).tick,
+    None => 0,
   };
 
   rt.rollback(last); // rollback for the latest rollback saved
@@ -186,10 +180,172 @@ pub fn persistence1(
 }
 
 #[rstest]
-fn one_hundred_snapshots(temp_dir: TempPath) {
+fn test_persist_state(temp_dir: TempPath) {
+  let rollback = RollbackConfig { heaps: 4, spacing: 16 };
+  let mut rt = init_runtime_with(&temp_dir.path, &rollback);
+  deploy_counter(&mut rt, 0);
+  for tick in 1 ..= 40 {
+    rt.open();
+    bump_counter(&mut rt, 1);
+    rt.set_tip(crate::util::u256(tick));
+    rt.commit();
+  }
+  let counter = Name::from_str("Counter").unwrap();
+  let mut restored = hvm::restore_runtime(temp_dir.path.clone(), &rollback).unwrap();
+  // The current heap isn't persisted, so the last few ticks are lost
+  let tick = restored.get_tick();
+  assert!(tick > 40 - 16 && tick <= 40, "{}", tick);
+  assert_eq!(restored.get_tip(), crate::util::u256(tick as u128));
+  assert_eq!(restored.get_index(&counter), rt.get_index(&counter));
+  let results = restored.run_statements_from_code("run { ask x = (Peek 'Counter'); (Done x) }", true, false);
+  match &results[0] {
+    Ok(StatementInfo::Run { done_term, .. }) => assert_eq!(view_term(done_term), format!("#{}", tick)),
+    result => panic!("unexpected result: {:?}", result),
+  }
+  // Only the heaps on the manifest are left on disk
+  let heaps = 1 + rt.get_back().len();
+  let files = std::fs::read_dir(&temp_dir.path).unwrap()
+    .filter(|entry| entry.as_ref().unwrap().path().to_str().unwrap().ends_with(".heap.bin"))
+    .count();
+  assert_eq!(files, heaps);
+}
+
+#[rstest]
+fn test_persist_state_failure(temp_dir: TempPath) {
+  let rollback = RollbackConfig { heaps: 4, spacing: 4 };
+  let mut rt = init_runtime_with(&temp_dir.path, &rollback);
+  deploy_counter(&mut rt, 0);
+  // A snapshot that can't be saved doesn't stop the runtime
+  std::fs::remove_dir_all(&temp_dir.path).unwrap();
+  for tick in 1 ..= 4 {
+    rt.open();
+    bump_counter(&mut rt, 1);
+    rt.set_tip(crate::util::u256(tick));
+    rt.commit();
+  }
+  assert!(hvm::restore_runtime(temp_dir.path.clone(), &rollback).is_err());
+  // The next snapshot saves the whole ring again
+  std::fs::create_dir_all(&temp_dir.path).unwrap();
+  for tick in 5 ..= 8 {
+    rt.open();
+    bump_counter(&mut rt, 1);
+    rt.set_tip(crate::util::u256(tick));
+    rt.commit();
+  }
+  let restored = hvm::restore_runtime(temp_dir.path.clone(), &rollback).unwrap();
+  assert_eq!(restored.get_tick(), 8);
+  assert_eq!(restored.get_tip(), crate::util::u256(8));
+}
+
+#[test]
+fn test_volatile_runtime() {
+  let genesis_stmts = hvm::parse_code(constants::GENESIS_CODE).expect("Genesis code parses");
+  let mut rt = hvm::init_volatile_runtime(&genesis_stmts, &RollbackConfig { heaps: 4, spacing: 4 });
+  deploy_counter(&mut rt, 0);
+  for tick in 1 ..= 20 {
+    rt.open();
+    bump_counter(&mut rt, 1);
+    rt.set_tip(crate::util::u256(tick));
+    rt.commit();
+  }
+  assert_eq!(rt.get_dir_path(), None);
+  assert!(rt.restore_state().is_err());
+  // Its snapshots still roll back
+  rt.rollback(10);
+  assert!(rt.get_tick() <= 10);
+}
+
+#[rstest]
+fn test_export_import_state(temp_dir: TempPath) {
+  let mut rt = init_volatile_runtime();
+  deploy_counter(&mut rt, 0);
+  for tick in 1 ..= 40 {
+    rt.open();
+    bump_counter(&mut rt, 1);
+    rt.set_tip(crate::util::u256(tick));
+    rt.commit();
+  }
+  std::fs::create_dir_all(&temp_dir.path).unwrap();
+  let file = temp_dir.path.join("state.bin");
+  persistence::write_state_file(&file, rt.get_tip(), &rt.export_state()).unwrap();
+  let (block, state) = persistence::read_state_file(&file).unwrap();
+  assert_eq!(block, crate::util::u256(40));
+  let counter = Name::from_str("Counter").unwrap();
+  let mut imported = hvm::install_runtime(temp_dir.path.join("imported"), state, block, &RollbackConfig::default()).unwrap();
+  assert_eq!(imported.get_tick(), 40);
+  assert_eq!(imported.get_tip(), block);
+  assert_eq!(imported.get_index(&counter), rt.get_index(&counter));
+  let results = imported.run_statements_from_code("run { ask x = (Peek 'Counter'); (Done x) }", true, false);
+  match &results[0] {
+    Ok(StatementInfo::Run { done_term, .. }) => assert_eq!(view_term(done_term), "#40"),
+    result => panic!("unexpected result: {:?}", result),
+  }
+  // The installed state is restored on startup
+  let restored = hvm::restore_runtime(temp_dir.path.join("imported"), &RollbackConfig::default()).unwrap();
+  assert_eq!(restored.get_tick(), 40);
+  // Corrupted files are rejected
+  let mut bytes = std::fs::read(&file).unwrap();
+  let last = bytes.len() / 2;
+  bytes[last] ^= 1;
+  std::fs::write(&file, bytes).unwrap();
+  assert!(persistence::read_state_file(&file).is_err());
+}
+
+#[rstest]
+fn test_state_root(temp_dir: TempPath) {
+  let code = "
+    fun (Reader action) {
+      (Reader ~) = ask x = (Peek 'Counter'); (Done x)
+    }
+  ";
+  let rollback = RollbackConfig { heaps: 4, spacing: 16 };
+  let mut rt = init_volatile_runtime_with(&rollback);
+  let mut other = init_volatile_runtime_with(&rollback);
+  for rt in [&mut rt, &mut other] {
+    deploy_counter(rt, 0);
+    rt.run_statements_from_code(code, true, false);
+  }
+  let mut roots = vec![];
+  for _ in 0 .. 40 {
+    for rt in [&mut rt, &mut other] {
+      rt.open();
+      bump_counter(rt, 1);
+      rt.commit();
+    }
+    // Runtimes that computed the same blocks agree on the state root
+    assert_eq!(rt.get_state_root(), other.get_state_root());
+    roots.push((rt.get_tick(), rt.get_state_root()));
+  }
+  // Each state change changes the root
+  for pair in roots.windows(2) {
+    assert_ne!(pair[0].1, pair[1].1);
+  }
+  // Peeking clones a state without changing it
+  rt.open();
+  rt.run_statements_from_code("run { ask (Call 'Reader' {Bump #0}); (Done #0) }", true, false);
+  rt.commit();
+  assert_eq!(rt.get_state_root(), roots.last().unwrap().1);
+  // Dry runs don't change it either
+  rt.test_statements_from_code("fun (Unused x) { (Unused x) = x }");
+  rt.open();
+  rt.commit();
+  assert_eq!(rt.get_state_root(), roots.last().unwrap().1);
+  // The root is the same as if every leaf was computed at once
+  let imported = hvm::install_runtime(temp_dir.path.join("c"), rt.export_state(), rt.get_tip(), &RollbackConfig::default()).unwrap();
+  assert_eq!(imported.get_state_root(), rt.get_state_root());
+  // Rolling back restores the root of the tick it goes back to
+  rt.rollback(35);
+  let tick = rt.get_tick();
+  assert!(tick < 40);
+  let (_, root) = roots.iter().find(|(t, _)| *t == tick).unwrap();
+  assert_eq!(&rt.get_state_root(), root);
+}
+
+#[rstest]
+fn one_hundred_snapshots() {
   // run this with rollback in each 4th snapshot
   // note: this test has no state
-  let mut rt = init_runtime(&temp_dir.path);
+  let mut rt = init_volatile_runtime();
   for _ in 0..100000 {
     rt.open();
     println!(
@@ -201,10 +357,97 @@ fn one_hundred_snapshots(temp_dir: TempPath) {
   }
 }
 
+proptest! {
+  #![proptest_config(ProptestConfig::with_cases(32))]
+  #[test]
+  fn rollback_restores_committed_states(
+    heaps in 2_u64 .. 6,
+    spacing in 1_u64 .. 5,
+    ticks in 1_u64 .. 48,
+    target in any::<Index>(),
+  ) {
+    // The owner, statement index, last transfer and statement hash of each namespace
+    type Names = Vec<(Option<U120>, Option<u128>, Option<u128>, Option<u128>)>;
+    type State = (u64, crate::crypto::Hash, u64, Names);
+    fn state(rt: &mut Runtime) -> State {
+      let names = (0 .. 48).map(|tick| {
+        let name = if tick == 0 { Name::from_str("Foo") } else { Name::from_str(&format!("R{}", tick)) };
+        let name = name.unwrap();
+        let indx = rt.get_index(&name);
+        let hash = indx.and_then(|pos| rt.get_sth0(pos));
+        (rt.get_owner(&name), indx, rt.get_transfer(&name), hash)
+      }).collect();
+      (rt.get_tick(), rt.get_state_root(), test_heap_checksum(&["Counter"], rt), names)
+    }
+    fn advance(rt: &mut Runtime, states: &mut Vec<State>, ticks: u64) {
+      let namer = genesis_namer();
+      let owners = [alice(), bob()];
+      let foo = Name::from_str("Foo").unwrap();
+      while rt.get_tick() < ticks {
+        rt.open();
+        let tick = rt.get_tick();
+        if tick == 1 {
+          deploy_counter(rt, 0);
+        }
+        bump_counter(rt, tick);
+        // Registers a namespace per tick, and passes `Foo` back and forth between two owners
+        let name = Name::from_str(&format!("R{}", tick)).unwrap();
+        let mut statements = vec![
+          sign_statement(&namer, hvm::Statement::Reg { name, ownr: owners[0].name.into(), until: None, sign: None }),
+        ];
+        if tick == 1 {
+          statements.push(sign_statement(&namer, hvm::Statement::Reg { name: foo, ownr: owners[0].name.into(), until: None, sign: None }));
+        } else {
+          // Each owner transfers it on every other tick, using up a nonce each time
+          let (prev, next) = (&owners[tick as usize % 2], &owners[(tick as usize + 1) % 2]);
+          let nonce = Some(U120::from_u128_unchecked((tick as u128 - 2) / 2));
+          let own = hvm::Statement::Own { name: foo, ownr: next.name.into(), nonce, until: None, sign: None };
+          statements.push(sign_statement(prev, own));
+        }
+        for result in rt.run_statements(&statements, true, false) {
+          assert!(result.is_ok(), "{:?}", result);
+        }
+        rt.commit();
+        let tick = rt.get_tick() as usize;
+        states.truncate(tick);
+        states.push(state(rt));
+      }
+    }
+
+    let temp_dir = temp_dir();
+    let rollback = RollbackConfig { heaps, spacing };
+    let mut rt = init_runtime_with(&temp_dir.path, &rollback);
+    let mut states = vec![state(&mut rt)];
+    advance(&mut rt, &mut states, ticks);
+    let committed = states.clone();
+
+    // Rolls back to the last snapshot at or before a tick in the window
+    let oldest = rt.get_back().iter().last().map(|head| rt.get_heap(head).tick).unwrap();
+    let oldest = if oldest == hvm::U64_NONE { 0 } else { oldest };
+    assert!(rt.get_back().len() as u64 <= heaps);
+    let target = oldest + target.index((ticks - oldest) as usize) as u64;
+    rt.rollback(target);
+    let landed = target - target % spacing;
+    assert_eq!(&state(&mut rt), &committed[landed as usize]);
+
+    // Recomputing the rolled back ticks gives the same states
+    advance(&mut rt, &mut states, ticks);
+    assert_eq!(&states, &committed);
+
+    // The persisted ring restores its newest snapshot, even into a smaller ring
+    for heaps in [heaps, 2] {
+      let mut restored = hvm::restore_runtime(temp_dir.path.clone(), &RollbackConfig { heaps, spacing }).unwrap();
+      let tick = restored.get_tick();
+      assert_eq!(tick % spacing, 0);
+      assert_eq!(&state(&mut restored), &committed[tick as usize]);
+    }
+  }
+}
+
 // Statement Indexes
 #[rstest]
-fn test_simple_idx(temp_dir: TempPath){
-  let mut rt = init_runtime(&temp_dir.path.clone());
+fn test_simple_idx(){
+  let mut rt = init_volatile_runtime();
   rt.open();
   rt.commit();
   rt.open();
@@ -238,8 +481,8 @@ fn test_simple_idx(temp_dir: TempPath){
   }
 }
 #[rstest]
-fn test_genesis_idx(temp_dir: TempPath){
-  let mut rt = init_runtime(&temp_dir.path.clone());
+fn test_genesis_idx(){
+  let mut rt = init_volatile_runtime();
   let code = "
    run {
      ask T2_idx = (GetIdx 'T2');
@@ -257,8 +500,8 @@ fn test_genesis_idx(temp_dir: TempPath){
 
 }
 #[rstest]
-fn test_thousand_idx(temp_dir: TempPath) {
-  let mut rt = init_runtime(&temp_dir.path.clone());
+fn test_thousand_idx() {
+  let mut rt = init_volatile_runtime();
   for i in 0..1000 {
     rt.open();
     rt.commit();
@@ -283,8 +526,8 @@ fn test_thousand_idx(temp_dir: TempPath) {
 }
   
 #[rstest]
-fn test_stmt_hash(temp_dir: TempPath){
-  let mut rt = init_runtime(&temp_dir.path.clone());
+fn test_stmt_hash(){
+  let mut rt = init_volatile_runtime();
   rt.open();
   let code = "
    fun (Test x) {
@@ -311,8 +554,8 @@ fn test_stmt_hash(temp_dir: TempPath){
   } 
 }
 #[rstest]
-fn test_two_stmt_hash(temp_dir: TempPath){
-  let mut rt = init_runtime(&temp_dir.path.clone());
+fn test_two_stmt_hash(){
+  let mut rt = init_volatile_runtime();
   let code = "
    fun (Test1 x) {
      (Test1 ~) = #0
@@ -350,8 +593,8 @@ fn test_two_stmt_hash(temp_dir: TempPath){
 }
 
 #[rstest]
-fn test_stmt_hash_after_commit(temp_dir: TempPath){
-  let mut rt = init_runtime(&temp_dir.path.clone());
+fn test_stmt_hash_after_commit(){
+  let mut rt = init_volatile_runtime();
   let code = "
    fun (Test x) {
      (Test ~) = #0
@@ -385,8 +628,8 @@ fn test_stmt_hash_after_commit(temp_dir: TempPath){
 }
 
 #[rstest]
-fn test_name_sanitizing(temp_dir: TempPath) {
-  let mut rt = init_runtime(&temp_dir.path.clone());
+fn test_name_sanitizing() {
+  let mut rt = init_volatile_runtime();
   rt.open();
   let code = "
    fun (Test x) {
@@ -410,6 +653,846 @@ fn test_name_sanitizing(temp_dir: TempPath) {
   }
 }
 
+#[rstest]
+fn test_log() {
+  let mut rt = init_volatile_runtime();
+  rt.open();
+  let code = "
+   fun (Emit x) {
+     (Emit x) = ask (Log {T2 #7 x}); (Done #0)
+   }
+
+   run {
+     ask (Log #1);
+     ask (Call 'Emit' {T1 #2});
+     ask (Log (+ #1 #2));
+     (Done #42)
+   }
+   ";
+  let results = rt.run_statements_from_code(code, false, true);
+  rt.commit();
+  let result_term = results.last().unwrap().clone().unwrap();
+  if let StatementInfo::Run { done_term, logs, .. } = result_term {
+    assert_eq!("#42", view_term(&done_term));
+    let logs: Vec<_> = logs.iter().map(view_term).collect();
+    assert_eq!(logs, ["#1", "{T2 #7 {T1 #2}}", "#3"]);
+  } else {
+    panic!("Wrong result");
+  }
+}
+
+#[rstest]
+fn test_log_limits() {
+  let mut rt = init_volatile_runtime();
+  rt.open();
+  let code = "
+   ctr {Leaf}
+   ctr {Node left right}
+
+   fun (Spam n) {
+     (Spam #0) = (Done #0)
+     (Spam n) = dup a b = n; ask (Log a); (Spam (- b #1))
+   }
+
+   fun (Tree n) {
+     (Tree #0) = {Leaf}
+     (Tree n) = dup a b = (- n #1); {Node (Tree a) (Tree b)}
+   }
+
+   run { (Spam #64) }
+   run { (Spam #65) }
+   run { ask (Log #1); ask (Log #2); (Done #0) }
+   run { ask (Log #1); ask (Log {T2 #1 #2}); (Done #0) }
+   run { ask (Log (Tree #11)); ask (Log #1); (Done #0) }
+   run { ask (Log (Tree #11)); ask (Log #1); ask (Log #2); (Done #0) }
+   ";
+  let results = rt.run_statements_from_code(code, true, false);
+  let run = |index: usize| match &results[index] {
+    Ok(StatementInfo::Run { used_mana, logs, .. }) => (*used_mana, logs.clone()),
+    result => panic!("unexpected result: {:?}", result),
+  };
+  assert_eq!(run(4).1.len(), hvm::MAX_LOGS);
+  let err = results[5].as_ref().unwrap_err();
+  assert!(err.err.contains("more than 64 terms"), "{}", err.err);
+  // Each log is charged by its size, in cells
+  assert_eq!(run(7).0 - run(6).0, 2 * 3 - 2);
+  // A log that doesn't fit in the room left fails the statement
+  assert_eq!(view_term(&run(8).1[0]).matches("Node").count(), 2047);
+  let err = results[9].as_ref().unwrap_err();
+  assert!(err.err.contains("more than 4096 cells"), "{}", err.err);
+}
+
+#[rstest]
+fn test_run_results() {
+  let mut rt = init_volatile_runtime();
+  rt.open();
+  let tick = rt.get_tick() as u128;
+  let code = "
+   run { (Done #42) }
+   run { (Done {T0}) }
+   ";
+  rt.run_statements_from_code(code, false, true);
+  rt.commit();
+  assert_eq!(rt.get_run_result(tick, 0), Some(U120::from_u128_unchecked(42)));
+  assert_eq!(rt.get_run_result(tick, 1), None);
+
+  rt.open();
+  let code = format!("
+   run {{
+     ask r = (GetRun #{} #0);
+     (Done (+ r #1))
+   }}
+   ", tick);
+  let results = rt.run_statements_from_code(&code, false, true);
+  rt.commit();
+  let result_term = results.last().unwrap().clone().unwrap();
+  if let StatementInfo::Run { done_term, .. } = result_term {
+    assert_eq!("#43", view_term(&done_term));
+  } else {
+    panic!("Wrong result");
+  }
+  assert_eq!(rt.get_run_result(tick + 1, 0), Some(U120::from_u128_unchecked(43)));
+
+  rt.rollback(tick as u64 - 1);
+  assert_eq!(rt.get_run_result(tick, 0), None);
+  assert_eq!(rt.get_run_result(tick + 1, 0), None);
+}
+
+#[rstest]
+fn test_trace() {
+  let mut rt = init_volatile_runtime();
+  rt.open();
+  let code = "
+   fun (Add a b) {
+     (Add a b) = (+ a b)
+   }
+
+   run { (Done (Add #1 #2)) }
+   ";
+  rt.set_tracing(true);
+  let results = rt.test_statements_from_code(code);
+  let result_term = results.last().unwrap().clone().unwrap();
+  if let StatementInfo::Run { done_term, used_mana, trace, .. } = result_term {
+    assert_eq!("#3", view_term(&done_term));
+    let trace = trace.expect("trace is enabled");
+    let add = Name::from_str("Add").unwrap();
+    assert!(trace.iter().any(|step| step.rule == Rewrite::FunCtr && step.name == Some(add)));
+    assert!(trace.contains(&TraceStep { rule: Rewrite::Op2Num, name: None, mana: 2 }));
+    assert_eq!(trace.iter().map(|step| step.mana).sum::<u64>(), used_mana);
+  } else {
+    panic!("Wrong result");
+  }
+
+  // Long traces are truncated, the last step standing for the ones left out
+  let code = "
+    fun (Loop n) {
+      (Loop #0) = #0
+      (Loop n) = (Loop (- n #1))
+    }
+    run { (Done (Loop #100000)) }
+  ";
+  let results = rt.test_statements_from_code(code);
+  if let Ok(StatementInfo::Run { used_mana, trace, .. }) = results.last().unwrap() {
+    let trace = trace.as_ref().expect("trace is enabled");
+    assert_eq!(trace.len(), hvm::MAX_TRACE_STEPS + 1);
+    assert_eq!(trace.last().unwrap().rule, Rewrite::Truncated);
+    assert_eq!(trace.iter().map(|step| step.mana).sum::<u64>(), *used_mana);
+  } else {
+    panic!("Wrong result");
+  }
+
+  rt.set_tracing(false);
+  let results = rt.test_statements_from_code(code);
+  let result_term = results.last().unwrap().clone().unwrap();
+  if let StatementInfo::Run { trace, .. } = result_term {
+    assert!(trace.is_none());
+  } else {
+    panic!("Wrong result");
+  }
+}
+
+#[rstest]
+fn test_nested_match() {
+  let mut rt = init_volatile_runtime();
+  rt.open();
+  let code = "
+   ctr {Cons head tail}
+   ctr {Nil}
+
+   fun (Second list) {
+     (Second {Cons ~ {Cons x ~}}) = x
+     (Second ~) = #0
+   }
+
+   fun (Sum pair) {
+     (Sum {T2 #0 {T2 #0 ~}}) = #100
+     (Sum {T2 a {T2 b ~}}) = (+ a b)
+     (Sum {T2 a ~}) = a
+   }
+
+   run { (Done (Second {Cons #1 {Cons #2 {Nil}}})) }
+   run { (Done (Second {Cons #1 {Nil}})) }
+   run { (Done (Second {Nil})) }
+   run { (Done (Sum {T2 #0 {T2 #0 #9}})) }
+   run { (Done (Sum {T2 #3 {T2 #4 #9}})) }
+   run { (Done (Sum {T2 #5 {Nil}})) }
+   ";
+  let results = rt.run_statements_from_code(code, false, true);
+  let done: Vec<_> = results.into_iter().filter_map(|res| match res.unwrap() {
+    StatementInfo::Run { done_term, .. } => Some(view_term(&done_term)),
+    _ => None,
+  }).collect();
+  assert_eq!(done, ["#2", "#0", "#0", "#100", "#7", "#5"]);
+  assert_eq!(rt.get_arity(&Name::from_str("Second_0").unwrap()), Some(2));
+  assert_eq!(rt.get_arity(&Name::from_str("Second_1").unwrap()), None);
+  assert_eq!(rt.get_arity(&Name::from_str("Sum_1").unwrap()), Some(2));
+}
+
+#[rstest]
+fn test_profile() {
+  let mut rt = init_volatile_runtime();
+  rt.open();
+  let code = "
+   ctr {Succ pred}
+   ctr {Zero}
+
+   fun (Double n) {
+     (Double {Succ pred}) = dup a b = (Double pred); {T2 a b}
+     (Double {Zero}) = {Zero}
+   }
+
+   run {
+     (Done (Double {Succ {Succ {Succ {Zero}}}}))
+   }
+   ";
+  rt.set_profiling(true);
+  let results = rt.test_statements_from_code(code);
+  let result_term = results.last().unwrap().clone().unwrap();
+  if let StatementInfo::Run { used_mana, profile, .. } = result_term {
+    let profile = profile.expect("profile is enabled");
+    let double = Name::from_str("Double").unwrap();
+    let row = |index| profile.iter().find(|row| row.name == Some(double) && row.index == Some(index)).unwrap();
+    assert_eq!(row(0).rwts, 3);
+    assert_eq!(row(0).dups, 3);
+    assert_eq!(row(1).rwts, 1);
+    assert_eq!(row(1).dups, 0);
+    assert_eq!(profile.iter().map(|row| row.mana).sum::<u64>(), used_mana);
+    assert!(profile.windows(2).all(|rows| rows[0].mana >= rows[1].mana));
+  } else {
+    panic!("Wrong result");
+  }
+}
+
+#[test]
+fn test_parse_errors() {
+  let code = "ctr {Pair a b}
+
+fun (Fst p) {
+  (Fst {Pair a b}) = a
+  (Fst x) = #0)
+}
+
+run { (Fst {Pair #1 #2}) }
+
+fun (Snd p) {
+  (Snd {Pair a b}) = dup x y = b; x
+}
+
+run { (Snd {Pair #1 #2 }
+";
+  let errors = hvm::parse_statements(code).unwrap_err();
+  assert_eq!(errors.len(), 2);
+  let pos = errors[0].locate(code);
+  assert_eq!((pos.line, pos.column), (5, 15));
+  assert_eq!(&code[pos.offset..pos.offset + 1], ")");
+  let pos = errors[1].locate(code);
+  assert_eq!((pos.line, pos.column), (15, 1));
+  assert_eq!(errors[1].erro, "Expected ')', found end of file.");
+
+  let shown = errors[0].show(code);
+  assert_eq!(
+    shown,
+    "error: Expected identifier, found `)`.\n  --> 5:15\n  |\n5 |   (Fst x) = #0)\n  |               ^"
+  );
+
+  let err = hvm::parse_code(code).unwrap_err();
+  assert!(err.contains("--> 5:15") && err.contains("--> 15:1"));
+  assert_eq!(hvm::parse_code("ctr {Pair a b}\nrun { #0 }").unwrap().len(), 2);
+
+  // Recovery resumes at `own` statements too
+  let code = "run { (Fst }\nown Foo { #1 \nrun { #0 }\n";
+  let errors = hvm::parse_statements(code).unwrap_err();
+  assert_eq!(errors.len(), 2);
+  assert_eq!(errors[1].locate(code).line, 3);
+
+  // And at imports, which need a file
+  let code = "run { (Fst }\nimport \"lib.kdl\"\nrun { #0 }\n";
+  let errors = hvm::parse_statements(code).unwrap_err();
+  assert_eq!(errors.len(), 2);
+  assert_eq!(errors[1].locate(code).line, 2);
+
+  // Offsets are in bytes, columns in chars, and the caret keeps the tabs
+  let code = "// ção\nrun {\n\t\t(Fst )) }\n";
+  let errors = hvm::parse_statements(code).unwrap_err();
+  let pos = errors[0].locate(code);
+  assert_eq!((pos.line, pos.column), (3, 9));
+  assert_eq!(pos.offset, code.find(") }").unwrap());
+  assert!(errors[0].show(code).ends_with("3 | \t\t(Fst )) }\n  | \t\t      ^"), "{}", errors[0].show(code));
+
+  // Errors in a file are placed after its imports
+  let code = "import \"lib.kdl\"\nrun { (Fst )) }\n";
+  let start = code.find("run").unwrap();
+  let errors = hvm::parse_file_statements(code, start).unwrap_err();
+  assert_eq!(errors[0].locate(code).to_string(), "2:13");
+}
+
+#[rstest]
+#[case("#b101", Some(5))]
+#[case("#x1F", Some(31))]
+#[case(&format!("#b{}", "1".repeat(120)), Some(*U120::MAX))]
+#[case(&format!("#b{}", "1".repeat(121)), None)]
+#[case("#b", None)]
+#[case("#1329227995784915872903807060280344576", None)] // 2^120
+#[case("#999999999999999999999999999999999999999999", None)]
+fn test_number_literals(#[case] code: &str, #[case] expected: Option<u128>) {
+  let numb = hvm::read_term(code).map(|(_, term)| term);
+  match expected {
+    Some(expected) => assert_eq!(numb.unwrap(), Term::num(U120::new(expected).unwrap())),
+    None => assert!(numb.is_err()),
+  }
+}
+
+#[rstest]
+#[case("\"\"", 0)]
+#[case("\"hello\"", 1)]
+#[case("\"exactly 15 byte\"", 1)]
+#[case("\"a string that takes three chunks, \\\"quoted\\\"\"", 3)]
+#[case("\"ação\\n\\t\\\\\"", 1)]
+fn test_string_literals(#[case] code: &str, #[case] chunks: usize) {
+  let (_, term) = hvm::read_term(code).unwrap();
+  let mut list = &term;
+  for _ in 0 .. chunks {
+    list = match list {
+      Term::Ctr { name, args } if name.to_string() == "StrCons" => &args[1],
+      _ => panic!("Not a string: {:?}", term),
+    };
+  }
+  assert!(matches!(list, Term::Ctr { name, .. } if name.to_string() == "StrNil"));
+  assert_eq!(view_term(&term), code);
+}
+
+#[rstest]
+#[case("\"unterminated")]
+#[case("\"unknown \\q escape\"")]
+#[case("\"null \0 char\"")]
+fn test_string_literal_errors(#[case] code: &str) {
+  assert!(hvm::read_term(code).is_err());
+}
+
+#[rstest]
+fn test_imports(temp_dir: TempPath) {
+  let dir = &temp_dir.path;
+  std::fs::create_dir_all(dir.join("lib")).unwrap();
+  let write = |file: &str, code: &str| std::fs::write(dir.join(file), code).unwrap();
+  write("pair.kdl", "ctr {Pair a b}");
+  write("lib/fst.kdl", "import \"../pair.kdl\"\nfun (Fst p) { (Fst {Pair a ~}) = a }");
+  write("lib/snd.kdl", "import \"../pair.kdl\"\nctr {Pair a b}\nfun (Snd p) { (Snd {Pair ~ b}) = b }");
+  let code = "
+    import \"lib/fst.kdl\"
+    import \"lib/snd.kdl\"
+    run { (Done (Fst {Pair #1 #2})) }
+  ";
+  let main = dir.join("main.kdl");
+  write("main.kdl", code);
+
+  let loaded = hvm::load_code(code, Some(&main)).unwrap();
+  let view = view_statements(&loaded.statements);
+  assert_eq!(loaded.imported, 3);
+  assert_eq!(view.matches("ctr {Pair a b}").count(), 1);
+  assert!(view.find("ctr {Pair").unwrap() < view.find("fun (Fst").unwrap());
+
+  write("lib/snd.kdl", "ctr {Pair x y}");
+  let err = hvm::load_code(code, Some(&main)).unwrap_err();
+  assert!(err.contains("'Pair' is defined twice with different bodies"), "{}", err);
+
+  write("lib/snd.kdl", "import \"fst.kdl\"");
+  write("lib/fst.kdl", "import \"snd.kdl\"");
+  let err = hvm::load_code(code, Some(&main)).unwrap_err();
+  assert!(err.starts_with("Import cycle: "), "{}", err);
+
+  write("lib/fst.kdl", "run { #0 }\nimport \"snd.kdl\"");
+  let err = hvm::load_code(code, Some(&main)).unwrap_err();
+  assert!(err.contains("Imports must come before the statements of a file."), "{}", err);
+
+  // Without a file, there is nothing to resolve an import against
+  let err = hvm::parse_code(code).unwrap_err();
+  assert!(err.contains("Imports need a file context"), "{}", err);
+}
+
+#[rstest]
+fn test_string_run() {
+  let mut rt = init_volatile_runtime();
+  let code = "
+    fun (Len str) {
+      (Len {StrNil}) = #0
+      (Len {StrCons ~ rest}) = (+ #1 (Len rest))
+    }
+    run { (Done (Len \"sixteen chars...\")) }
+    run { (Done [#0 \"hi\"]) }
+  ";
+  let results = rt.run_statements_from_code(code, true, false);
+  let done_terms: Vec<_> = results.iter().filter_map(|result| match result {
+    Ok(StatementInfo::Run { done_term, .. }) => Some(view_term(done_term)),
+    _ => None,
+  }).collect();
+  assert_eq!(done_terms, ["#2", "{T2 #0 \"hi\"}"]);
+}
+
+#[rstest]
+fn test_long_io_chain() {
+  // Runs on a small stack, which a recursive IO interpreter would overflow
+  let done_terms = std::thread::Builder::new()
+    .stack_size(1 << 20)
+    .spawn(move || {
+      let mut rt = init_volatile_runtime();
+      let code = "
+        fun (Loop n) {
+          (Loop #0) = (Done #0)
+          (Loop n) = ask (Save n); ask x = (Take); (Loop (- x #1))
+        }
+        run { (Loop #10000) }
+      ";
+      let results = rt.run_statements_from_code(code, true, false);
+      results.iter().filter_map(|result| match result {
+        Ok(StatementInfo::Run { done_term, .. }) => Some(view_term(done_term)),
+        _ => None,
+      }).collect::<Vec<_>>()
+    })
+    .unwrap()
+    .join()
+    .unwrap();
+  assert_eq!(done_terms, ["#0"]);
+}
+
+#[rstest]
+fn test_call_depth() {
+  let mut rt = init_volatile_runtime();
+  let code = format!("
+    ctr {{Down n}}
+    fun (Deep arg) {{
+      (Deep {{Down #0}}) = (Done #0)
+      (Deep {{Down n}}) = ask r = (Call 'Deep' {{Down (- n #1)}}); (Done (+ r #1))
+    }}
+    run {{ ask r = (Call 'Deep' {{Down #{}}}); (Done r) }}
+    run {{ ask r = (Call 'Deep' {{Down #{}}}); (Done r) }}
+  ", hvm::MAX_CALL_DEPTH - 1, hvm::MAX_CALL_DEPTH);
+  let results = rt.run_statements_from_code(&code, true, false);
+  match &results[2] {
+    Ok(StatementInfo::Run { done_term, .. }) => {
+      assert_eq!(view_term(done_term), format!("#{}", hvm::MAX_CALL_DEPTH - 1));
+    }
+    result => panic!("unexpected result: {:?}", result),
+  }
+  let err = results[3].as_ref().unwrap_err();
+  assert!(err.err.contains("maximum call depth"), "{}", err.err);
+}
+
+#[rstest]
+fn test_call_args() {
+  let mut rt = init_volatile_runtime();
+  let deep = format!("{}{{NoPays}}{}", "{Pays #0 ".repeat(hvm::MAX_CALL_ARG_DEPTH), "}".repeat(hvm::MAX_CALL_ARG_DEPTH));
+  let wide = |field: &str| format!("{{Wide{}}}", format!(" {}", field).repeat(16));
+  let big = wide(&wide(&wide("#0")));
+  let code = format!("
+    ctr {{Pay n}}
+    ctr {{Pays head tail}}
+    ctr {{NoPays}}
+    ctr {{Wide a b c d e f g h i j k l m n o p}}
+    fun (Amount p) {{
+      (Amount {{Pay n}}) = n
+    }}
+    fun (Total list) {{
+      (Total {{NoPays}}) = #0
+      (Total {{Pays p tail}}) = (+ (Amount p) (Total tail))
+    }}
+    fun (Ledger list) {{
+      (Ledger list) = (Done (Total list))
+    }}
+    run {{ ask r = (Call 'Ledger' {{Pays {{Pay #2}} {{Pays {{Pay #3}} {{NoPays}}}}}}); (Done r) }}
+    run {{ ask r = (Call 'Ledger' {{Pays @x x {{NoPays}}}}); (Done r) }}
+    run {{ ask r = (Call 'Ledger' {}); (Done r) }}
+    run {{ ask r = (Call 'Ledger' {}); (Done r) }}
+  ", deep, big);
+  let results = rt.run_statements_from_code(&code, true, false);
+  match &results[7] {
+    Ok(StatementInfo::Run { done_term, .. }) => assert_eq!(view_term(done_term), "#5"),
+    result => panic!("unexpected result: {:?}", result),
+  }
+  let err = results[8].as_ref().unwrap_err();
+  assert!(err.err.contains("invalid argument"), "{}", err.err);
+  let err = results[9].as_ref().unwrap_err();
+  assert!(err.err.contains("nested deeper than"), "{}", err.err);
+  let err = results[10].as_ref().unwrap_err();
+  assert!(err.err.contains("larger than"), "{}", err.err);
+}
+
+#[rstest]
+fn test_hash() {
+  let mut rt = init_volatile_runtime();
+  let code = "
+    run { ask h = (Hash #1 'ab'); (Done h) }
+    run { ask h = (Hash #1 {T0}); (Done h) }
+  ";
+  let results = rt.run_statements_from_code(code, true, false);
+  let mut bytes = [0u8; 30];
+  bytes[14] = 1;
+  bytes[27..30].copy_from_slice(&(*Name::from_str("ab").unwrap()).to_be_bytes()[13..]);
+  let (hash0, hash1) = hvm::hash_to_u120s(&crate::crypto::Hash::keccak256_from_bytes(&bytes));
+  match &results[0] {
+    Ok(StatementInfo::Run { done_term, .. }) => {
+      assert_eq!(view_term(done_term), format!("{{T2 #{} #{}}}", hash0, hash1));
+    }
+    result => panic!("unexpected result: {:?}", result),
+  }
+  assert!(results[1].is_err());
+}
+
+#[rstest]
+fn test_signer() {
+  let mut rt = init_volatile_runtime();
+  let code = std::fs::read_to_string("example/voucher.kdl").unwrap();
+  let results = rt.run_statements_from_code(&code, true, false);
+  let done_terms: Vec<_> = results.iter().filter_map(|result| match result {
+    Ok(StatementInfo::Run { done_term, .. }) => Some(view_term(done_term)),
+    _ => None,
+  }).collect();
+  assert_eq!(done_terms, ["#1", "#0"]);
+
+  // Signs a fresh hash, and passes pieces that don't fit
+  let account = crate::crypto::Account::from_private_key(&[7; 32]);
+  let hash = crate::crypto::Hash::keccak256_from_bytes(b"voucher");
+  let sign = account.sign(&hash);
+  let pieces = |bytes: &[u8]| {
+    bytes.chunks(15).map(|chunk| {
+      format!("#x{}", hex::encode(chunk))
+    }).collect::<Vec<_>>().join(" ")
+  };
+  let code = format!("
+    run {{ ask s = (GetSigner {} {}); (Done s) }}
+    run {{ ask s = (GetSigner {} #x10000 {}); (Done s) }}
+  ", pieces(&hash.0), pieces(&sign.0), pieces(&hash.0[0 .. 30]), pieces(&sign.0));
+  let results = rt.run_statements_from_code(&code, true, false);
+  let done_terms: Vec<_> = results.iter().filter_map(|result| match result {
+    Ok(StatementInfo::Run { done_term, .. }) => Some(view_term(done_term)),
+    _ => None,
+  }).collect();
+  assert_eq!(done_terms, [format!("#{}", *account.name), "#0".to_string()]);
+}
+
+#[rstest]
+fn test_peek() {
+  let mut rt = init_volatile_runtime();
+  let code = "
+    ctr {Put x}
+    ctr {Get}
+    fun (Store action) {
+      (Store {Put x}) = ask (Save {T2 x {T1 #2}}); (Done #0)
+      (Store {Get}) = ask x = (Load); (Done x)
+    } with {
+      #0
+    }
+    run { ask (Call 'Store' {Put #7}); (Done #0) }
+    run { ask x = (Peek 'Store'); (Done x) }
+    run { ask x = (Call 'Store' {Get}); (Done x) }
+    run { ask x = (Peek 'Nope'); (Done x) }
+  ";
+  let results = rt.run_statements_from_code(code, true, false);
+  let done_terms: Vec<_> = results[4 .. 6].iter().map(|result| match result {
+    Ok(StatementInfo::Run { done_term, .. }) => view_term(done_term),
+    result => panic!("unexpected result: {:?}", result),
+  }).collect();
+  assert_eq!(done_terms, ["{T2 #7 {T1 #2}}", "{T2 #7 {T1 #2}}"]);
+  let err = results[6].as_ref().unwrap_err();
+  assert!(err.err.contains("did not exist"), "{}", err.err);
+}
+
+#[rstest]
+fn test_schedule() {
+  let mut rt = init_volatile_runtime();
+  rt.open();
+  deploy_counter(&mut rt, 0);
+  let code = "
+    run { ask t = (Tick); ask (Schedule (+ t #2) 'Counter' {Bump #5}); (Done #0) }
+    run { ask t = (Tick); ask (Schedule t 'Counter' {Bump #5}); (Done #0) }
+  ";
+  let results = rt.run_statements_from_code(code, true, false);
+  match &results[0] {
+    // The scheduled call takes space until it runs
+    Ok(StatementInfo::Run { size_diff, .. }) => assert_eq!(*size_diff, 5),
+    result => panic!("unexpected result: {:?}", result),
+  }
+  let err = results[1].as_ref().unwrap_err();
+  assert!(err.err.contains("not in the future"), "{}", err.err);
+  rt.commit();
+  let size = rt.get_size();
+  let mut scheduled = vec![];
+  for _ in 0 .. 3 {
+    rt.open();
+    scheduled.push(rt.run_scheduled(true));
+    rt.commit();
+  }
+  assert!(scheduled[0].is_empty());
+  assert!(scheduled[2].is_empty());
+  match &scheduled[1][..] {
+    [Ok(StatementInfo::Sched { name, done_term, .. })] => {
+      assert_eq!(name.to_string(), "Counter");
+      assert_eq!(view_term(done_term), "#0");
+    }
+    result => panic!("unexpected result: {:?}", result),
+  }
+  let results = rt.run_statements_from_code("run { ask x = (Peek 'Counter'); (Done x) }", true, false);
+  match &results[0] {
+    Ok(StatementInfo::Run { done_term, .. }) => assert_eq!(view_term(done_term), "#5"),
+    result => panic!("unexpected result: {:?}", result),
+  }
+  // Calls are deleted once they run
+  assert_eq!(rt.count_scheduled(3), 0);
+  assert_eq!(rt.get_size(), size - 5);
+  // A tick holds a limited number of calls
+  rt.open();
+  rt.set_tick(1000);
+  let code = format!("
+    fun (Spam n) {{
+      (Spam #0) = (Done #0)
+      (Spam n) = ask t = (Tick); ask (Schedule (+ t #1) 'Counter' {{Bump #1}}); (Spam (- n #1))
+    }}
+    run {{ (Spam #{}) }}
+    run {{ (Spam #1) }}
+  ", hvm::MAX_TICK_SCHEDS);
+  let results = rt.run_statements_from_code(&code, true, false);
+  assert!(results[1].is_ok(), "{:?}", results[1]);
+  let err = results[2].as_ref().unwrap_err();
+  assert!(err.err.contains("already has"), "{}", err.err);
+  assert_eq!(rt.count_scheduled(1001), hvm::MAX_TICK_SCHEDS);
+}
+
+#[rstest]
+fn test_schedule_failures() {
+  let mut rt = init_volatile_runtime();
+  rt.open();
+  rt.set_tick(1000);
+  // Each call burns some mana, then fails
+  let code = "
+    ctr {Burn n}
+    fun (Loop n) {
+      (Loop #0) = #0
+      (Loop n) = (Loop (- n #1))
+    }
+    fun (Fail n) {
+      (Fail #0) = (Missing)
+    }
+    fun (Burner action) {
+      (Burner {Burn n}) = (Done (Fail (Loop n)))
+    }
+    fun (Spam n) {
+      (Spam #0) = (Done #0)
+      (Spam n) = ask t = (Tick); ask (Schedule (+ t #1) 'Burner' {Burn #20000}); (Spam (- n #1))
+    }
+    run { (Spam #64) }
+  ";
+  let results = rt.run_statements_from_code(code, true, false);
+  assert!(results.iter().all(|res| res.is_ok()), "{:?}", results);
+  rt.commit();
+  rt.open();
+  let mana = rt.get_mana();
+  let results = rt.run_scheduled(true);
+  assert_eq!(results.len(), 64);
+  assert!(results.iter().all(|res| res.is_err()));
+  // Failed calls still use up the mana of the block's scheduled calls, give
+  // or take the rewrite that ran out of it
+  let used_mana = rt.get_mana() - mana;
+  assert!(used_mana >= hvm::BLOCK_SCHED_MANA && used_mana < hvm::BLOCK_SCHED_MANA + 100, "{}", used_mana);
+  let err = results.last().unwrap().as_ref().unwrap_err();
+  assert!(err.err.contains("Not enough mana"), "{}", err.err);
+}
+
+#[rstest]
+fn test_transfer() {
+  let mut rt = init_volatile_runtime();
+  let (namer, alice, bob) = (genesis_namer(), alice(), bob());
+  let foo = Name::from_str("Foo").unwrap();
+  let statements = [
+    sign_statement(&namer, hvm::Statement::Reg { name: foo, ownr: alice.name.into(), until: None, sign: None }),
+    sign_statement(&bob, hvm::Statement::Own { name: foo, ownr: bob.name.into(), nonce: Some(U120::ZERO), until: None, sign: None }),
+    sign_statement(&alice, hvm::Statement::Own { name: foo, ownr: bob.name.into(), nonce: Some(U120::ZERO), until: None, sign: None }),
+    sign_statement(&alice, hvm::Statement::Own { name: Name::from_str("Nope").unwrap(), ownr: bob.name.into(), nonce: Some(U120::from_u128_unchecked(1)), until: None, sign: None }),
+    sign_statement(&bob, hvm::Statement::Reg { name: Name::from_str("Foo.Bar").unwrap(), ownr: bob.name.into(), until: None, sign: None }),
+    sign_statement(&namer, hvm::Statement::Own { name: Name::EMPTY, ownr: bob.name.into(), nonce: Some(U120::ZERO), until: None, sign: None }),
+  ];
+  let results = rt.run_statements(&statements, true, false);
+  assert!(results[0].is_ok(), "{:?}", results[0]);
+  let err = results[1].as_ref().unwrap_err();
+  assert!(err.err.contains("not allowed to transfer"), "{}", err.err);
+  match &results[2] {
+    Ok(StatementInfo::Own { name, prev, ownr }) => {
+      assert_eq!(*name, foo);
+      assert_eq!(*prev, U120::from(alice.name));
+      assert_eq!(*ownr, U120::from(bob.name));
+    }
+    result => panic!("unexpected result: {:?}", result),
+  }
+  let err = results[3].as_ref().unwrap_err();
+  assert!(err.err.contains("is not registered"), "{}", err.err);
+  assert!(results[4].is_ok(), "{:?}", results[4]);
+  let err = results[5].as_ref().unwrap_err();
+  assert!(err.err.contains("Can't transfer the empty namespace"), "{}", err.err);
+  assert_eq!(rt.get_owner(&Name::EMPTY), Some(U120::from(namer.name)));
+  assert_eq!(rt.get_owner(&foo), Some(U120::from(bob.name)));
+  assert!(rt.get_transfer(&foo).is_some());
+}
+
+#[rstest]
+fn test_transfer_rollback() {
+  // Snapshots every tick, so that the rollback lands right before the transfer
+  let rollback = RollbackConfig { heaps: 4, spacing: 1 };
+  let mut rt = init_volatile_runtime_with(&rollback);
+  let (namer, alice, bob) = (genesis_namer(), alice(), bob());
+  let foo = Name::from_str("Foo").unwrap();
+  rt.open();
+  let reg = sign_statement(&namer, hvm::Statement::Reg { name: foo, ownr: alice.name.into(), until: None, sign: None });
+  let results = rt.run_statements(&[reg], true, false);
+  assert!(results[0].is_ok(), "{:?}", results[0]);
+  rt.commit();
+  let tick = rt.get_tick();
+  let index = rt.get_index(&foo);
+  rt.open();
+  let own = sign_statement(&alice, hvm::Statement::Own { name: foo, ownr: bob.name.into(), nonce: Some(U120::ZERO), until: None, sign: None });
+  let results = rt.run_statements(&[own], true, false);
+  assert!(results[0].is_ok(), "{:?}", results[0]);
+  rt.commit();
+  assert_eq!(rt.get_owner(&foo), Some(U120::from(bob.name)));
+  // Rolling back undoes the transfer
+  rt.rollback(tick);
+  assert_eq!(rt.get_tick(), tick);
+  assert_eq!(rt.get_owner(&foo), Some(U120::from(alice.name)));
+  assert_eq!(rt.get_transfer(&foo), None);
+  assert_eq!(rt.get_index(&foo), index);
+}
+
+#[rstest]
+fn test_transfer_replay() {
+  let mut rt = init_volatile_runtime();
+  let (namer, alice, bob) = (genesis_namer(), alice(), bob());
+  let foo = Name::from_str("Foo").unwrap();
+  let own = |account: &crate::crypto::Account, ownr: &crate::crypto::Account, nonce: Option<u128>| {
+    let nonce = nonce.map(U120::from_u128_unchecked);
+    sign_statement(account, hvm::Statement::Own { name: foo, ownr: ownr.name.into(), nonce, until: None, sign: None })
+  };
+  let statements = [
+    sign_statement(&namer, hvm::Statement::Reg { name: foo, ownr: alice.name.into(), until: None, sign: None }),
+    own(&alice, &bob, None),
+    own(&alice, &bob, Some(0)),
+    own(&bob, &alice, Some(0)),
+  ];
+  let results = rt.run_statements(&statements, true, false);
+  let err = results[1].as_ref().unwrap_err();
+  assert!(err.err.contains("has no nonce, expected #0"), "{}", err.err);
+  assert!(results[2].is_ok(), "{:?}", results[2]);
+  assert!(results[3].is_ok(), "{:?}", results[3]);
+  // Once `Foo` is back with Alice, her old transfer to Bob can't be resubmitted
+  let results = rt.run_statements(&[own(&alice, &bob, Some(0))], true, false);
+  let err = results[0].as_ref().unwrap_err();
+  assert!(err.err.contains("has nonce #0, expected #1"), "{}", err.err);
+  assert_eq!(rt.get_owner(&foo), Some(U120::from(alice.name)));
+  assert_eq!(rt.get_nonce(&U120::from(alice.name)), U120::from_u128_unchecked(1));
+}
+
+#[rstest]
+fn test_run_nonce() {
+  let mut rt = init_volatile_runtime();
+  let alice = crate::crypto::Account::from_private_key(&[7; 32]);
+  let signed = |code: &str| {
+    let statement = read_statements(code).unwrap().1.pop().unwrap();
+    hvm::set_sign(&statement, alice.sign(&hvm::hash_statement(&statement)))
+  };
+  let statements = [
+    signed("run { ask x = (Subj); (Done x) } nonce { #0 }"),
+    signed("run { ask x = (Subj); (Done x) } nonce { #0 }"),
+    signed("run { ask x = (Subj); (Done x) }"),
+    signed("run { (Done (Missing)) } nonce { #1 }"),
+    signed("run { ask x = (Subj); (Done x) } nonce { #2 }"),
+    read_statements("run { (Done #0) }").unwrap().1.pop().unwrap(),
+  ];
+  let results = rt.run_statements(&statements, true, false);
+  assert!(results[0].is_ok(), "{:?}", results[0]);
+  let err = results[1].as_ref().unwrap_err();
+  assert!(err.err.contains("has nonce #0, expected #1"), "{}", err.err);
+  let err = results[2].as_ref().unwrap_err();
+  assert!(err.err.contains("has no nonce, expected #1"), "{}", err.err);
+  // A failed run still uses its nonce up
+  assert!(results[3].is_err());
+  assert!(results[4].is_ok(), "{:?}", results[4]);
+  assert!(results[5].is_ok(), "{:?}", results[5]);
+  assert_eq!(rt.get_nonce(&U120::from(alice.name)), U120::from_u128_unchecked(3));
+}
+
+#[rstest]
+fn test_dry_run_nonce() {
+  let mut rt = init_volatile_runtime();
+  let signed = |code: &str| sign_statement(&alice(), read_statements(code).unwrap().1.pop().unwrap());
+  let subj = U120::from(alice().name);
+  let root = rt.get_state_root();
+  // Neither a successful nor a failed dry run uses the nonce up
+  let statements = [
+    read_statements("ctr {DryRun}").unwrap().1.pop().unwrap(),
+    signed("run { (Done #0) } nonce { #0 }"),
+  ];
+  let results = rt.test_statements(&statements);
+  assert!(results.iter().all(|res| res.is_ok()), "{:?}", results);
+  let results = rt.test_statements(&[signed("run { (Done (Missing)) } nonce { #0 }")]);
+  assert!(results[0].is_err());
+  assert_eq!(rt.get_nonce(&subj), U120::ZERO);
+  assert_eq!(rt.get_state_root(), root);
+  assert!(!rt.exists(&Name::try_from("DryRun").unwrap()));
+}
+
+#[rstest]
+fn test_expiry() {
+  let mut rt = init_volatile_runtime();
+  rt.open();
+  let code = "
+    run { (Done #1) } until { #1 }
+    run { (Done #1) } until { #0 }
+    ctr {Expired} until { #0 }
+  ";
+  let results = rt.run_statements_from_code(code, true, false);
+  assert!(results[0].is_ok(), "{:?}", results[0]);
+  let err = results[1].as_ref().unwrap_err();
+  assert!(err.err.contains("expired after tick 0"), "{}", err.err);
+  assert!(results[2].is_err());
+  let statement = read_statements("run { (Done #1) } until { #1 }").unwrap().1;
+  assert!(view_statements(&statement).contains("until { #1 }"));
+}
+
+#[rstest]
+fn test_mana_cap() {
+  let mut rt = init_volatile_runtime();
+  let code = "
+    fun (Burn n) {
+      (Burn #0) = #0
+      (Burn n) = (Burn (- n #1))
+    }
+    run<100> { (Done (Burn #1000)) }
+    run<1000000> { (Done (Burn #1000)) }
+  ";
+  let results = rt.run_statements_from_code(code, true, false);
+  let err = results[1].as_ref().unwrap_err();
+  assert!(err.err.contains("Not enough mana"), "{}", err.err);
+  assert!(results[2].is_ok(), "{:?}", results[2]);
+  let statement = read_statements("run<100> { (Done #1) }").unwrap().1;
+  assert!(view_statements(&statement).starts_with("run<100> {"));
+}
+
 #[rstest]
 #[case(keyword_fail_1)]
 #[case(keyword_fail_2)]
@@ -424,7 +1507,7 @@ fn parse_ask_fail1(
 }
 
 #[rstest]
-fn compute_at_funs(temp_dir: TempPath) {
+fn compute_at_funs() {
   let code = "
     fun (Add a b) {
       (Add #256 #256) = #512
@@ -435,7 +1518,7 @@ fn compute_at_funs(temp_dir: TempPath) {
       (Done dup ~ b = @x @y (Add x y); b)
     }
   ";
-  let mut rt = init_runtime(&temp_dir.path);
+  let mut rt = init_volatile_runtime();
   let results = rt.run_statements_from_code(code, false, true);
   let result_term = results.last().unwrap().clone().unwrap();
   if let StatementInfo::Run { done_term, .. } = result_term {
@@ -446,7 +1529,7 @@ fn compute_at_funs(temp_dir: TempPath) {
 }
 
 #[rstest]
-fn dupped_state_test(temp_dir: TempPath) {
+fn dupped_state_test() {
   fn print_and_assert_states(
     rt: &mut Runtime,
     expected_original_readback: &str,
@@ -481,7 +1564,7 @@ fn dupped_state_test(temp_dir: TempPath) {
     println!();
   }
 
-  let mut rt = init_runtime(&temp_dir.path);
+  let mut rt = init_volatile_runtime();
   rt.run_statements_from_code(&PRE_DUPPED_STATE, false, true);
   rt.run_statements_from_code(&DUPPED_STATE, false, true);
   print_and_assert_states(&mut rt, "@x0 @x1 #7", "@x0 @x1 #7");
@@ -490,7 +1573,7 @@ fn dupped_state_test(temp_dir: TempPath) {
 }
 
 #[rstest]
-fn shadowing(temp_dir: TempPath) {
+fn shadowing() {
   let code = "
     fun (Test state) {
       (Test state) = 
@@ -506,7 +1589,7 @@ fn shadowing(temp_dir: TempPath) {
       (Done (Test #2))
     }
   ";
-  let mut rt = init_runtime(&temp_dir.path);
+  let mut rt = init_volatile_runtime();
   let results = rt.run_statements_from_code(code, false, true);
   let result_term = results.last().unwrap().clone().unwrap();
   if let StatementInfo::Run { done_term, .. } = result_term {
@@ -545,11 +1628,10 @@ fn shadowing(temp_dir: TempPath) {
 #[case("let state = #2; let state = (+ state #1); state", "#3")]
 fn readback(
   #[case] code: &str,
-  #[case] expected_readback: &str,
-  temp_dir: TempPath,
+  #[case] expected_readback: &str
 ) {
   // initialize runtime
-  let mut rt = init_runtime(&temp_dir.path);
+  let mut rt = init_volatile_runtime();
   // declare used constructors
   let pre_code = "ctr {Cons x xs} ctr {Nil} ctr {Pair x y}";
   rt.run_statements_from_code(&pre_code, false, true);
@@ -585,12 +1667,20 @@ proptest! {
     assert_eq!(statements, s1);
   }
 
+  #[test]
+  fn string_literals(text in "[^\\x00]*") {
+    let term = hvm::string_to_term(&text);
+    assert_eq!(hvm::term_to_string(&term), Some(text.clone()));
+    let (_, parsed) = hvm::read_term(&hvm::view_string(&text)).unwrap();
+    assert_eq!(parsed, term);
+  }
+
   #[test]
   #[ignore = "slow"]
   fn serialize_deserialize_heap(heap in heap()) {
     let h1 = heap;
     let path = temp_dir();
-    h1.serialize(&path.path, false).unwrap();
+    h1.serialize(&path.path).unwrap();
     if let Ok(h2) = Heap::deserialize(h1.uuid, &path.path) {
         assert_eq!(h1, h2);
     }
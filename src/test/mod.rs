@@ -12,6 +12,7 @@ mod util;
 
 // test modules
 mod bits;
+mod format;
 mod hasher;
 mod hvm;
 mod network;
@@ -70,6 +70,7 @@ fn network() {
         ui: Some(config::UiConfig { json: true, tags: vec![] }),
         api: None,
         ws: Some(ws_config), // Some(ws_config),
+        rollback: config::RollbackConfig::default(),
       };
       node::start(node_cfg, socket, initial_peers);
     });
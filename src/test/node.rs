@@ -1,8 +1,22 @@
 use proptest::collection::vec;
 use proptest::proptest;
 
+use std::str::FromStr;
+
+use proptest::prelude::any;
+use primitive_types::U256;
+use rstest::rstest;
+
+use crate::api;
 use crate::bits::ProtoSerialize;
+use crate::common::Name;
+use crate::config::RollbackConfig;
+use crate::constants;
+use crate::crypto::{self, Keccakable};
+use crate::hvm;
+use crate::net;
 use crate::node;
+use crate::test::util::{deploy_counter, init_volatile_runtime, temp_dir, TempPath};
 use crate::test::strategies::statement;
 use crate::util;
 
@@ -16,4 +30,179 @@ proptest! {
     let s2 = format!("{:?}", statements);
     assert_eq!(s1, s2);
   }
+
+  #[test]
+  fn merkle_proofs_verify(leaves in vec(any::<[u8; 32]>(), 1..40), index in any::<usize>()) {
+    let leaves: Vec<crypto::Hash> = leaves.into_iter().map(crypto::Hash).collect();
+    let count = leaves.len();
+    let index = index % count;
+    let root = crypto::merkle_root(&leaves);
+    let proof = crypto::merkle_proof(&leaves, index);
+    assert!(crypto::merkle_verify(&root, &leaves[index], index, count, &proof));
+    // Proofs of another leaf or position fail
+    let other = crypto::Hash::keccak256_from_bytes(&leaves[index].0);
+    assert!(!crypto::merkle_verify(&root, &other, index, count, &proof));
+    if count > 1 {
+      let moved = (index + 1) % count;
+      assert!(leaves[moved] == leaves[index] || !crypto::merkle_verify(&root, &leaves[index], moved, count, &proof));
+    }
+  }
+
+  #[test]
+  fn merkle_tree_updates_match_rebuilds(
+    leaves in vec(any::<[u8; 32]>(), 0..40),
+    changes in vec((any::<usize>(), any::<[u8; 32]>(), any::<bool>()), 1..8),
+  ) {
+    let mut leaves: Vec<crypto::Hash> = leaves.into_iter().map(crypto::Hash).collect();
+    let mut tree = crypto::MerkleTree::new(leaves.clone());
+    for (index, leaf, insert) in changes {
+      let leaf = crypto::Hash(leaf);
+      // Either changes a leaf, or inserts one and moves the leaves after it
+      if insert || leaves.is_empty() {
+        let index = index % (leaves.len() + 1);
+        leaves.insert(index, leaf);
+        tree.replace_from(index, leaves[index ..].to_vec());
+      } else {
+        let index = index % leaves.len();
+        leaves[index] = leaf.clone();
+        tree.set(index, leaf);
+      }
+      assert!(tree.root() == crypto::merkle_root(&leaves));
+      for index in 0 .. leaves.len() {
+        assert!(tree.proof(index) == crypto::merkle_proof(&leaves, index));
+      }
+    }
+  }
+}
+
+#[test]
+fn genesis_block_holds_every_genesis_statement() {
+  let genesis_stmts = hvm::parse_code(constants::GENESIS_CODE).unwrap();
+  let block = node::build_genesis_block(&genesis_stmts);
+  let transactions = node::extract_transactions(&block.body);
+  assert_eq!(transactions.len(), genesis_stmts.len());
+}
+
+#[rstest]
+fn state_proofs() {
+  let mut rt = init_volatile_runtime();
+  rt.open();
+  deploy_counter(&mut rt, 7);
+  rt.commit();
+  let root = api::Hash::from(U256::from(&rt.get_state_root()));
+  let counter = Name::from_str("Counter").unwrap();
+  let mut proof = node::get_state_proof(&rt, counter).unwrap();
+  assert_eq!(U256::from(proof.state_root), U256::from(root));
+  assert_eq!(proof.state, Some(hvm::Term::num(7u128.try_into().unwrap())));
+  assert!(api::verify_state_proof(&proof, &root));
+  // Names without state can be proven too
+  let bump = node::get_state_proof(&rt, Name::from_str("Bump").unwrap()).unwrap();
+  assert_eq!(bump.state, None);
+  assert!(api::verify_state_proof(&bump, &root));
+  assert!(node::get_state_proof(&rt, Name::from_str("Missing").unwrap()).is_none());
+  // A forged state, or another root, fails
+  assert!(!api::verify_state_proof(&bump, &proof.block));
+  proof.state = Some(hvm::Term::num(8u128.try_into().unwrap()));
+  assert!(!api::verify_state_proof(&proof, &root));
+}
+
+#[rstest]
+fn export_leaves_saved_state(temp_dir: TempPath) {
+  let data_path = &temp_dir.path;
+  let rollback = RollbackConfig { heaps: 4, spacing: 2 };
+  let genesis_stmts = hvm::parse_code(constants::GENESIS_CODE).unwrap();
+  let blocks_path = data_path.join("blocks");
+  std::fs::create_dir_all(&blocks_path).unwrap();
+  let mut chain = vec![node::build_genesis_block(&genesis_stmts).hashed()];
+  for height in 1 ..= 8 {
+    let prev = U256::from(chain.last().unwrap().get_hash());
+    let body = node::Body::from_transactions_iter(Vec::<node::Transaction>::new()).unwrap();
+    let block = node::Block::new(prev, height, 0, body).hashed();
+    let file_path = blocks_path.join(format!("{:0>16x}.kindelia_block.bin", height));
+    std::fs::write(file_path, util::bitvec_to_bytes(&block.proto_serialized())).unwrap();
+    chain.push(block);
+  }
+  // The node saved its state at the tip
+  let mut rt = hvm::init_runtime(data_path.join("heaps"), &genesis_stmts, &rollback);
+  for block in &chain[1 ..] {
+    node::run_block(&mut rt, block);
+  }
+  let saved = || {
+    let mut files: Vec<_> = std::fs::read_dir(data_path.join("heaps")).unwrap()
+      .map(|entry| entry.unwrap().path())
+      .map(|path| (path.clone(), std::fs::read(path).unwrap()))
+      .collect();
+    files.sort();
+    files
+  };
+  let before = saved();
+  assert!(before.iter().any(|(path, _)| path.ends_with("_manifest_")));
+  // Exporting an older block, which is recomputed from the genesis block, leaves it as it was
+  let bhash = U256::from(chain[3].get_hash());
+  let file = data_path.join("state.bin");
+  node::export_state(data_path, bhash, &file, &rollback).unwrap();
+  assert!(saved() == before);
+  let (block, state) = crate::persistence::read_state_file(&file).unwrap();
+  assert_eq!(block, bhash);
+  assert_eq!(state.tick, 3);
+}
+
+/// A comm that reaches no one, for nodes that are only queried.
+struct Offline;
+
+impl net::ProtoComm for Offline {
+  type Address = u32;
+  fn proto_send(&mut self, _: Vec<u32>, _: &node::Message<u32>) {}
+  fn proto_recv(&mut self) -> Vec<(u32, node::Message<u32>)> {
+    vec![]
+  }
+  fn get_addr(&self) -> u32 {
+    0
+  }
+}
+
+fn offline_node(temp_dir: &TempPath) -> node::Node<Offline> {
+  #[cfg(feature = "events")]
+  let (event_tx, _) = std::sync::mpsc::channel();
+  let (_, node) = node::Node::new(
+    temp_dir.path.clone(),
+    0,
+    RollbackConfig::default(),
+    vec![],
+    Offline,
+    None,
+    #[cfg(feature = "events")]
+    event_tx,
+  );
+  node
+}
+
+#[rstest]
+fn fit_info(temp_dir: TempPath) {
+  let mut node = offline_node(&temp_dir);
+  // The body holds the transaction count, then each transaction after its length
+  let code = hvm::parse_code("ctr {Pair a b}").unwrap();
+  let fit = node.get_fit_info(&code);
+  assert!(fit.fits);
+  assert_eq!(fit.used_bytes, 1 + 2 + node::Transaction::from(&code[0]).len());
+  // Too many bytes for a body
+  let code = (0 .. 200).map(|i| format!("ctr {{Pair{} a b}}\n", i)).collect::<String>();
+  let code = hvm::parse_code(&code).unwrap();
+  let fit = node.get_fit_info(&code);
+  assert!(fit.results.iter().all(|res| res.is_ok()), "{:?}", fit.results);
+  assert!(fit.used_bytes > fit.max_bytes, "{}", fit.used_bytes);
+  assert!(!fit.fits);
+  // Too much mana for a block
+  let code = "
+    fun (Loop n) {
+      (Loop #0) = #0
+      (Loop n) = (Loop (- n #1))
+    }
+    run { (Done (Loop #1000000)) }
+  ";
+  let fit = node.get_fit_info(&hvm::parse_code(code).unwrap());
+  assert!(fit.used_bytes <= fit.max_bytes);
+  assert!(!fit.fits);
+  let err = fit.results[1].as_ref().unwrap_err();
+  assert!(err.err.contains("Not enough mana"), "{}", err.err);
 }
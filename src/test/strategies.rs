@@ -5,8 +5,8 @@ use crate::{
   common::{Name, U120},
   hvm::{
     init_u128_map, init_name_map, init_u120_map, init_loc_map, Arits, CompFunc, CompRule, Func, Funcs, Hashs,
-    Heap, Nodes, Oper, Ownrs, Rollback, Rule, Runtime, Loc, RawCell,
-    Statement, Store, Term, Var, Indxs,
+    Heap, Nodes, Oper, Ownrs, Rule, Runs, Runtime, Loc, RawCell, Sched, Scheds, Nonces,
+    Statement, Store, Term, Var, Indxs, Leafs,
   },
   util::{U128Map, NameMap, U120Map, LocMap},
   net::Address,
@@ -124,16 +124,18 @@ pub fn sign() -> impl Strategy<Value = crypto::Signature> {
 // generate statements
 pub fn statement() -> impl Strategy<Value = Statement> {
   prop_oneof![
-    (small_name(), vec(name(), 0..10), func(), term(), option::of(sign()))
-      .prop_map(|(name, args, func, init, sign)| {
-        Statement::Fun { name, args, func, init: Some(init), sign }
+    (small_name(), vec(name(), 0..10), func(), term(), option::of(any::<u64>()), option::of(sign()))
+      .prop_map(|(name, args, func, init, until, sign)| {
+        Statement::Fun { name, args, func, init: Some(init), until, sign }
       }),
-    (small_name(), vec(name(), 0..10), option::of(sign()))
-      .prop_map(|(name, args, sign)| { Statement::Ctr { name, args, sign } }),
-    (term(), option::of(sign()))
-      .prop_map(|(t, s)| { Statement::Run { expr: t, sign: s } }),
-    (name(), u120(), option::of(sign()))
-      .prop_map(|(name, ownr, sign)| { Statement::Reg { name, ownr, sign } }),
+    (small_name(), vec(name(), 0..10), option::of(any::<u64>()), option::of(sign()))
+      .prop_map(|(name, args, until, sign)| { Statement::Ctr { name, args, until, sign } }),
+    (term(), option::of(any::<u64>()), option::of(u120()), option::of(any::<u64>()), option::of(sign()))
+      .prop_map(|(t, m, n, u, s)| { Statement::Run { expr: t, mana: m, nonce: n, until: u, sign: s } }),
+    (name(), u120(), option::of(any::<u64>()), option::of(sign()))
+      .prop_map(|(name, ownr, until, sign)| { Statement::Reg { name, ownr, until, sign } }),
+    (name(), u120(), option::of(u120()), option::of(any::<u64>()), option::of(sign()))
+      .prop_map(|(name, ownr, nonce, until, sign)| { Statement::Own { name, ownr, nonce, until, sign } }),
   ]
 }
 pub fn hash() -> impl Strategy<Value = crypto::Hash> {
@@ -211,6 +213,33 @@ pub fn hashs() -> impl Strategy<Value = Hashs> {
   map(hash()).prop_map(|m| Hashs { stmt_hashes: m })
 }
 
+pub fn runs() -> impl Strategy<Value = Runs> {
+  map(u120()).prop_map(|m| Runs { runs: m })
+}
+
+pub fn nonces() -> impl Strategy<Value = Nonces> {
+  u120_map(u120()).prop_map(|m| Nonces { nonces: m })
+}
+
+pub fn sched() -> impl Strategy<Value = Sched> {
+  (any::<u64>(), u120(), name(), name(), vec(u120(), 0..16))
+    .prop_map(|(t, c, f, n, a)| Sched { tick: t, caller: c, callee: f, name: n, args: a })
+}
+
+pub fn scheds() -> impl Strategy<Value = Scheds> {
+  vec(sched(), 0..10).prop_map(|v| {
+    let mut m = init_u128_map();
+    for s in v {
+      m.entry(s.tick as u128).or_insert_with(Vec::new).push(s);
+    }
+    Scheds { scheds: m }
+  })
+}
+
+pub fn leafs() -> impl Strategy<Value = Leafs> {
+  name_map(hash()).prop_map(|m| Leafs { leafs: m })
+}
+
 pub fn var() -> impl Strategy<Value = Var> {
   (name(), any::<u64>(), option::of(any::<u64>()), any::<bool>())
     .prop_map(|(n, p, f, e)| Var { name: n, param: p, field: f, erase: e })
@@ -259,8 +288,10 @@ pub fn heap() -> impl Strategy<Value = Heap> {
     arits(),
     ownrs(),
     funcs(),
-    indxs(),
+    (indxs(), indxs()),
     hashs(),
+    (runs(), nonces()),
+    (scheds(), leafs()),
   )
     .prop_map(
       |(
@@ -272,8 +303,10 @@ pub fn heap() -> impl Strategy<Value = Heap> {
         arit,
         ownr,
         file,
-        indx,
-        hash
+        (indx, xfer),
+        hash,
+        (runs, nonc),
+        (sche, leaf)
       )| Heap {
         mcap,
         disk,
@@ -281,6 +314,11 @@ pub fn heap() -> impl Strategy<Value = Heap> {
         ownr,
         hash,
         indx,
+        xfer,
+        runs,
+        nonc,
+        sche,
+        leaf,
         file: Funcs { funcs: init_name_map() }, // TODO, fix?
         uuid,
         memo,
@@ -1,23 +1,89 @@
 use std::collections::{hash_map::DefaultHasher, HashMap};
 use std::hash::{Hash, Hasher};
 use std::path::PathBuf;
-use std::sync::Arc;
 
 use rstest::fixture;
 use tokio::runtime;
 
+use crate::config::RollbackConfig;
 use crate::constants;
 use crate::common::{Name, U120};
+use crate::crypto::Account;
 use crate::hvm::{
-  self, read_term, show_term, Rollback, Runtime, Statement, StatementInfo,
+  self, read_term, show_term, Runtime, Statement, StatementInfo,
   Term, U128_NONE, U64_NONE,
 };
 use crate::node;
 
 pub fn init_runtime(path: &PathBuf) -> hvm::Runtime {
+  init_runtime_with(path, &RollbackConfig::default())
+}
+
+pub fn init_runtime_with(
+  path: &PathBuf,
+  rollback: &RollbackConfig,
+) -> hvm::Runtime {
   let genesis_stmts =
     hvm::parse_code(constants::GENESIS_CODE).expect("Genesis code parses.");
-  hvm::init_runtime(path.clone(), &genesis_stmts)
+  hvm::init_runtime(path.clone(), &genesis_stmts, rollback)
+}
+
+pub fn init_volatile_runtime() -> hvm::Runtime {
+  init_volatile_runtime_with(&RollbackConfig::default())
+}
+
+pub fn init_volatile_runtime_with(rollback: &RollbackConfig) -> hvm::Runtime {
+  let genesis_stmts =
+    hvm::parse_code(constants::GENESIS_CODE).expect("Genesis code parses.");
+  hvm::init_volatile_runtime(&genesis_stmts, rollback)
+}
+
+/// Deploys `Counter`, an app whose state starts at `init` and is increased by
+/// `{Bump x}` calls.
+pub fn deploy_counter(
+  rt: &mut hvm::Runtime,
+  init: u64,
+) -> Vec<hvm::StatementResult> {
+  let code = format!(
+    "
+    ctr {{Bump x}}
+    fun (Counter action) {{
+      (Counter {{Bump x}}) = ask n = (Take); ask (Save (+ n x)); (Done #0)
+    }} with {{
+      #{}
+    }}
+  ",
+    init
+  );
+  rt.run_statements_from_code(&code, true, false)
+}
+
+/// Increases the state of `Counter` by `x`.
+pub fn bump_counter(rt: &mut hvm::Runtime, x: u64) -> Vec<hvm::StatementResult> {
+  let code = format!("run {{ ask (Call 'Counter' {{Bump #{}}}); (Done #0) }}", x);
+  rt.run_statements_from_code(&code, true, false)
+}
+
+/// The account that registers top-level namespaces on the genesis block.
+pub fn genesis_namer() -> Account {
+  let mut key = [0; 32];
+  key[31] = 1;
+  Account::from_private_key(&key)
+}
+
+/// Two test accounts, Alice and Bob.
+pub fn alice() -> Account {
+  Account::from_private_key(&[7; 32])
+}
+
+pub fn bob() -> Account {
+  Account::from_private_key(&[8; 32])
+}
+
+/// Signs `statement` with `account`.
+pub fn sign_statement(account: &Account, statement: Statement) -> Statement {
+  let sign = account.sign(&hvm::hash_statement(&statement));
+  hvm::set_sign(&statement, sign)
 }
 
 // ===========================================================
@@ -42,32 +108,12 @@ pub fn are_all_elemenets_equal<E: PartialEq>(vec: &[E]) -> bool {
 }
 
 pub fn view_rollback_ticks(rt: &Runtime) -> String {
-  fn view_rollback_ticks_go(
-    rt: &Runtime,
-    back: &Arc<Rollback>,
-  ) -> Vec<Option<u64>> {
-    match &**back {
-      Rollback::Nil => return Vec::new(),
-      Rollback::Cons { keep, head, tail, life } => {
-        let mut vec = view_rollback_ticks_go(&rt, tail);
-        let tick = rt.get_heap(*head).tick;
-        vec.push(Some(tick));
-        return vec;
-      }
-    }
-  }
-
-  let back = rt.get_back();
-  let ticks = view_rollback_ticks_go(rt, &back);
-  let elems = ticks
+  let elems = rt
+    .get_back()
     .iter()
-    .rev()
-    .map(|x| {
-      if let Some(x) = x {
-        format!("{}", if *x != U64_NONE { *x } else { 0 })
-      } else {
-        "___________".to_string()
-      }
+    .map(|head| {
+      let tick = rt.get_heap(head).tick;
+      format!("{}", if tick != U64_NONE { tick } else { 0 })
     })
     .collect::<Vec<String>>()
     .join(", ");
@@ -173,9 +219,8 @@ pub fn rollback_simple(
   total_tick: u128,
   rollback_tick: u64,
   validators: &[Validator],
-  dir_path: &PathBuf,
 ) -> bool {
-  let mut rt = init_runtime(dir_path);
+  let mut rt = init_volatile_runtime();
 
   // Calculate all total_tick states and saves old checksum
   let mut old_state = RuntimeStateTest::new(fn_names, &mut rt);
@@ -214,7 +259,6 @@ pub fn rollback_path(
   fn_names: &[&str],
   path: &[u64],
   validators: &[Validator],
-  dir_path: &PathBuf,
 ) -> bool {
   let mut states_store: HashMap<u64, Vec<RuntimeStateTest>> = HashMap::new();
   let mut insert_state = |rt: &mut Runtime| {
@@ -227,7 +271,7 @@ pub fn rollback_path(
     }
   };
 
-  let mut rt = init_runtime(dir_path);
+  let mut rt = init_volatile_runtime();
   rt.run_statements_from_code(pre_code, true, true);
 
   for tick in path {
@@ -249,14 +293,13 @@ pub fn run_term_and<A>(term: &Term, action: A)
 where
   A: Fn(&Term),
 {
-  let temp_dir = temp_dir();
-  let mut rt = init_runtime(&temp_dir.path);
+  let mut rt = init_volatile_runtime();
 
   let term = Term::Fun {
     name: "Done".try_into().unwrap(),
     args: [term.clone()].to_vec(),
   };
-  let stmt = Statement::Run { expr: term, sign: None };
+  let stmt = Statement::Run { expr: term, mana: None, nonce: None, until: None, sign: None };
   let result = rt.run_statement(&stmt, false, true, None).unwrap();
 
   if let StatementInfo::Run { done_term, .. } = result {
@@ -142,9 +142,9 @@ mod cli {
   }
 
   #[rstest]
-  #[case("example/private_key_1_namer", "4d576ce7dc24f565a7cee2390071191da2b0de13e1fd99c7008225694cf4c21788fe7395ac05091428440fb6e64a0af6d3cdbad53421a46d3d34d49f2864301dd28e774a2a9228434e492ed9c3")]
-  #[case("example/private_key_2_alice", "4d576ce7dc24f565a7cee23980b6cc8dbc97db445ce9db50e5f7cddba5f5088227bcbb1d517aebe61f324ef5bd38c44d544ffc8ae962de6379f20fa638417184d45a6b25365411657889c7f41e")]
-  #[case("example/private_key_3_bob", "4d576ce7dc24f565a7cee239808f479434991302dd2cf82b2b4e4acfe67bbd93d81f496e1da6610498261a2bc92270c58c260d857ff98f761e6afff7f9294feccf28f1f5455cf0e80722f82c6b")]
+  #[case("example/private_key_1_namer", "4d576ce7dc24f565a7cee238900ace646072fddda36aee8614121d5506a4882cef07c16204556ea755347cd77e1aeed04bc447a173c80db138b71d8a2ebb41687b19ec5dcf0cfdae327c023d83d0")]
+  #[case("example/private_key_2_alice", "4d576ce7dc24f565a7cee238900996576386f96751d0465475895577b4d3751d0959f2ec7d2e158b20edd1369ee5e67218d261b0238d29f73942ca14ae38b0fac108a95d8dfe0d8e8f18d3025ff0")]
+  #[case("example/private_key_3_bob", "4d576ce7dc24f565a7cee238900b170f2001e8a6d55ace6abf5ca6f760eb298e52beb0deeb918823df6a99e41f7ca0a4759200bc015f03ffaf41d686db37d87ffbc772f9405b78b4188a32184f60")]
   fn signing(#[case] private_key: &str, #[case] expected_result: &'static str) {
     let assertion = kindelia!()
       .args([
@@ -159,6 +159,42 @@ mod cli {
     assertion.success().stdout(format!("{}\n", expected_result));
   }
 
+  #[rstest]
+  fn test_imports() {
+    let dir = temp_dir().join(format!("crate.{:x}", fastrand::u128(..)));
+    std::fs::create_dir_all(dir.join("lib")).unwrap();
+    std::fs::write(dir.join("lib/pair.kdl"), "ctr {Pair a b}").unwrap();
+    std::fs::write(
+      dir.join("main.kdl"),
+      "import \"lib/pair.kdl\"\nrun { (Done {Pair #1 #2}) }",
+    )
+    .unwrap();
+
+    let output = kindelia!()
+      .args(["test", dir.join("main.kdl").to_str().unwrap()])
+      .output()
+      .unwrap();
+    let output = get_stdout(&output);
+    assert_eq!(get_runs_result(&output), ["{Pair #1 #2}"]);
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[rstest]
+  fn fmt_check() {
+    let temp_file =
+      temp_dir().join(format!("crate.{:x}.kdl", fastrand::u128(..)));
+    std::fs::write(&temp_file, "ctr {Pair a b}  // pair\nrun { (Done #1) }")
+      .unwrap();
+    let path = temp_file.to_str().unwrap();
+
+    kindelia!().args(["fmt", "--check", path]).assert().failure();
+    kindelia!().args(["fmt", path]).assert().success();
+    kindelia!().args(["fmt", "--check", path]).assert().success();
+
+    let formatted = std::fs::read_to_string(&temp_file).unwrap();
+    assert_eq!(formatted, "ctr {Pair a b} // pair\nrun {\n  (Done #1)\n}\n");
+  }
+
   #[rstest]
   #[case("/constructor/*", Some("T3"), ctr_response_1(), "ctr arity", "3")]
   #[case(
@@ -187,6 +223,7 @@ mod cli {
   // not working because the `with { ~ }` syntax
   // #[case("/functions/*", "Test", fun_response_1().0, "fun code", FUN_CODE)]
   #[case("/functions/*/state", Some("Test"), fun_response_1().1, "fun state", "#42")]
+  #[case("/blocks/*/runs/1", Some("3"), run_response_1(), "run 1", "#42")]
   fn test_get_mock<T: serde::Serialize>(
     #[case] path: &str,
     #[case] name: Option<&str>,
@@ -225,6 +262,35 @@ mod cli {
     assertion.success().stdout(format!("{}\n", expected_result));
   }
 
+  #[rstest]
+  fn test_fit_mock() {
+    let server = httpmock::MockServer::start();
+    server.mock(|when, then| {
+      when.method(httpmock::Method::POST).path("/fit");
+      then.status(200).json_body_obj(&fit_response_1());
+    });
+    let mock_url = format!("http://127.0.0.1:{}/", server.port());
+
+    let args = ["--api", &mock_url, "fit", "example/block_2.kdl"];
+    let assertion = kindelia!().args(args).assert();
+    assertion.success().stdout(
+      "[body] 120 / 1280 bytes\n[mana] 52 / 4000000\n[size] 256 / 2048 bits\nFits in a block.\n",
+    );
+  }
+
+  fn fit_response_1() -> api::FitInfo {
+    api::FitInfo {
+      fits: true,
+      used_bytes: 120,
+      max_bytes: 1280,
+      used_mana: 52,
+      max_mana: 4000000,
+      used_bits: 256,
+      max_bits: 2048,
+      results: vec![],
+    }
+  }
+
   fn ctr_response_1() -> api::CtrInfo {
     api::CtrInfo { arit: 3 }
   }
@@ -234,7 +300,7 @@ mod cli {
       .iter()
       .map(|s| (*s).try_into().unwrap())
       .collect();
-    api::RegInfo { ownr: common::Name::from_u128_unchecked(1024), stmt: names }
+    api::RegInfo { ownr: common::Name::from_u128_unchecked(1024), stmt: names, xfer: None }
   }
 
   // fn peers_response_1() -> Vec<node::Peer> {
@@ -260,6 +326,10 @@ mod cli {
     }
   }
 
+  fn run_response_1() -> common::U120 {
+    common::U120::from_u128_unchecked(42)
+  }
+
   fn stats_response_1() -> api::Stats {
     api::Stats {
       ctr_count: 3,